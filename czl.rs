@@ -4,10 +4,15 @@
 
 use std::cmp::max;
 use std::cmp::min;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::SyncSender;
 
 use conf::*;
 use core::*;
 use draw::*;
+use session::*;
 use term::*;
 use text::*;
 use util::*;
@@ -48,12 +53,7 @@ macro_rules! check {
  *  - offer to save if panic
  *  - better navigation !
  *  - copy and yank buffer
- *  - cursor horizontal memory
- *  - buffer explorer
- *  - directory explorer
  *  - grep move
- *  - cursor previous points and cursor markers
- *  - ctags support
  *  - support tab character and autodetect tab expansion ?
  *  - add a special input for forcing a tab insert
  *
@@ -66,53 +66,343 @@ macro_rules! check {
 
 
 fn main() {
+    let args = match Args::parse(std::env::args().skip(1)) {
+        Ok(args)    => args,
+        Err(err)    => {
+            eprintln!("czl: {}\n{}", err.cause(), USAGE);
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        println!("{}", USAGE);
+        return
+    }
+
+    let config_errors = conf::load();
+
     let _term = Term::set_raw().unwrap();
 
     open_logfile(&CONF.logfile).unwrap();
 
-    Editor::run().unwrap();
+    Editor::run(args, config_errors).unwrap();
 }
 
 
 mod conf {
 
 
+use std::env;
+use std::ops::Deref;
+use std::sync::OnceLock;
+
 use core::*;
+use ioutil;
+use term::Input;
 
 
-// Global constant that controls a bunch of options.
-pub const CONF : Config = Config {
-    draw_screen:            true,
-    draw_colors:            true,
-    retain_frame:           false,
-    no_raw_mode:            false, //true,
+// Global configuration that controls a bunch of options. The defaults are overridden by the config
+// file once load() has run, and cannot change afterwards.
+pub static CONF : Globalconfig = Globalconfig;
 
-    debug_console:          true,
-    debug_bounds:           true,
-    debug_latency:          true,
+static LOADED : OnceLock<Config> = OnceLock::new();
 
-    relative_lineno:        true,
-    cursor_show_line:       true,
-    cursor_show_column:     true,
+pub struct Globalconfig;
 
-    color_default:          Colorcell { fg: Color::Black,   bg: Color::White },
-    color_header_active:    Colorcell { fg: Color::Black,   bg: Color::Yellow },
-    color_header_inactive:  Colorcell { fg: Color::Gray(2), bg: Color::Cyan },
-    color_footer:           Colorcell { fg: Color::White,   bg: Color::Gray(14) },
-    color_lineno:           Colorcell { fg: Color::Green,   bg: Color::White },
-    color_console:          Colorcell { fg: Color::White,   bg: Color::Gray(12) },
-    color_cursor_lines:     Colorcell { fg: Color::Black,   bg: Color::Gray(15) },
+impl Deref for Globalconfig {
+    type Target = Config;
 
-    color_mode_command:     Colorcell { fg: Color::BoldWhite, bg: Color::Black },
-    color_mode_insert:      Colorcell { fg: Color::BoldWhite, bg: Color::Red },
-    color_mode_replace:     Colorcell { fg: Color::BoldWhite, bg: Color::Magenta },
-    color_mode_exit:        Colorcell { fg: Color::Magenta, bg: Color::Magenta },
+    fn deref(&self) -> &Config {
+        LOADED.get_or_init(Config::default)
+    }
+}
 
-    tab_expansion:          4,
+// Directory of the config file and of themes: $XDG_CONFIG_HOME/czl, or ~/.config/czl.
+pub fn dir() -> String {
+    match env::var("XDG_CONFIG_HOME") {
+        Ok(ref dir) if !dir.is_empty()  => format!("{}/czl", dir),
+        _                               => ioutil::expand_home("~/.config/czl"),
+    }
+}
+
+pub fn path() -> String {
+    format!("{}/config", dir())
+}
+
+// Read the config file if there is one and make it the global configuration. Bad lines are
+// skipped and returned as error messages.
+pub fn load() -> Vec<String> {
+    let path = path();
+    let mut config = Config::default();
+    let errors = match ioutil::file_load(&path) {
+        Ok(text)    => config.apply(&path, &String::from_utf8_lossy(&text)),
+        Err(_)      => Vec::new(),  // no config file
+    };
+    if LOADED.set(config).is_err() {
+        return vec![format!("{}: configuration already in use, ignored", path)]
+    }
+    errors
+}
+
+
+impl Config {
+    pub fn default() -> Config {
+        Config {
+            draw_screen:            true,
+            draw_colors:            true,
+            retain_frame:           false,
+            no_raw_mode:            false, //true,
+
+            debug_console:          true,
+            debug_bounds:           true,
+            debug_latency:          true,
+
+            relative_lineno:        true,
+            cursor_show_line:       true,
+            cursor_show_column:     true,
+
+            color_default:          Colorcell { fg: Color::Black,   bg: Color::White },
+            color_header_active:    Colorcell { fg: Color::Black,   bg: Color::Yellow },
+            color_header_inactive:  Colorcell { fg: Color::Gray(2), bg: Color::Cyan },
+            color_footer:           Colorcell { fg: Color::White,   bg: Color::Gray(14) },
+            color_lineno:           Colorcell { fg: Color::Green,   bg: Color::White },
+            color_console:          Colorcell { fg: Color::White,   bg: Color::Gray(12) },
+            color_cursor_lines:     Colorcell { fg: Color::Black,   bg: Color::Gray(15) },
+            color_popup:            Colorcell { fg: Color::White,   bg: Color::Gray(6) },
+            color_popup_selected:   Colorcell { fg: Color::Black,   bg: Color::Yellow },
+            color_selection:        Colorcell { fg: Color::White,   bg: Color::Blue },
+
+            color_mode_command:     Colorcell { fg: Color::BoldWhite, bg: Color::Black },
+            color_mode_insert:      Colorcell { fg: Color::BoldWhite, bg: Color::Red },
+            color_mode_replace:     Colorcell { fg: Color::BoldWhite, bg: Color::Magenta },
+            color_mode_exit:        Colorcell { fg: Color::Magenta, bg: Color::Magenta },
+
+            tab_expansion:          4,
+
+            color_depth:            None,
+
+            keymap_command:         Vec::new(),
+            keymap_insert:          Vec::new(),
+
+            logfile:                "/tmp/czl.log".to_string(),
+            sessionfile:            "~/.czl_session".to_string(),
+        }
+    }
+
+    // Set options from 'key value' lines. Everything after a '#' is a comment, except in colors.
+    // 'theme <name>' sets colors from the theme file <name> in the themes directory, where
+    // 'make install-themes' copies the themes of the repository.
+    // 'map_command <keys> <command>' and 'map_insert <keys> <command>' bind a sequence of space
+    // separated keys to a named command, or unbind it with the 'none' command. Only the Command
+    // and Insert mode keys can be bound: the keys of pickers, the explorer, the command line,
+    // prompts and pane commands are fixed.
+    pub fn apply(&mut self, path: &str, text: &str) -> Vec<String> {
+        self.apply_lines(path, text, false)
+    }
+
+    // Theme files have the same format, but only set colors.
+    fn apply_lines(&mut self, path: &str, text: &str, is_theme: bool) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue
+            }
+            let mut kv = line.splitn(2, [' ', '\t']);
+            let key = kv.next().unwrap_or("");
+            let value = kv.next().unwrap_or("").trim();
+            let result = if value.is_empty() {
+                Err(format!("no value for key '{}'", key))
+            } else if is_theme && !key.starts_with("color_") {
+                Err(format!("themes can only set colors, not '{}'", key))
+            } else if key == "theme" {
+                self.theme_load(value).map(|theme_errors| errors.extend(theme_errors))
+            } else if key.starts_with("map_") {
+                self.bind(key, value, format!("{}:{}", path, i + 1))
+            } else {
+                self.set(key, value)
+            };
+            if let Err(msg) = result {
+                errors.push(format!("{}:{}: {}", path, i + 1, msg));
+            }
+        }
+        errors
+    }
+
+    fn theme_load(&mut self, name: &str) -> Result<Vec<String>, String> {
+        if name.contains('/') {
+            return Err(format!("invalid theme name '{}'", name))
+        }
+        let path = format!("{}/themes/{}", dir(), name);
+        match ioutil::file_load(&path) {
+            Ok(text)    => Ok(self.apply_lines(&path, &String::from_utf8_lossy(&text), true)),
+            Err(_)      => Err(format!("cannot read theme '{}' at {}", name, path)),
+        }
+    }
+
+    // Command names are only checked once the keymaps are built.
+    fn bind(&mut self, key: &str, value: &str, origin: String) -> Result<(), String> {
+        let keymap = match key {
+            "map_command"   => &mut self.keymap_command,
+            "map_insert"    => &mut self.keymap_insert,
+            _               => return Err(format!("unknown key '{}'", key)),
+        };
+        let mut words : Vec<&str> = value.split_whitespace().collect();
+        let command = words.pop().unwrap_or("");
+        if words.is_empty() {
+            return Err(format!("expected keys and a command name, got '{}'", value))
+        }
+        let mut keys = Vec::new();
+        for w in words {
+            match Input::parse_key(w) {
+                Some(k)     => keys.push(k),
+                None        => return Err(format!("unknown key '{}'", w)),
+            }
+        }
+        keymap.push(Keybinding { keys, command: command.to_string(), origin });
+        Ok(())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "draw_screen"           => self.draw_screen = parse_bool(value)?,
+            "draw_colors"           => self.draw_colors = parse_bool(value)?,
+            "retain_frame"          => self.retain_frame = parse_bool(value)?,
+            "no_raw_mode"           => self.no_raw_mode = parse_bool(value)?,
+            "debug_console"         => self.debug_console = parse_bool(value)?,
+            "debug_bounds"          => self.debug_bounds = parse_bool(value)?,
+            "debug_latency"         => self.debug_latency = parse_bool(value)?,
+            "relative_lineno"       => self.relative_lineno = parse_bool(value)?,
+            "cursor_show_line"      => self.cursor_show_line = parse_bool(value)?,
+            "cursor_show_column"    => self.cursor_show_column = parse_bool(value)?,
+            "color_default"         => self.color_default = parse_colorcell(value)?,
+            "color_header_active"   => self.color_header_active = parse_colorcell(value)?,
+            "color_header_inactive" => self.color_header_inactive = parse_colorcell(value)?,
+            "color_footer"          => self.color_footer = parse_colorcell(value)?,
+            "color_lineno"          => self.color_lineno = parse_colorcell(value)?,
+            "color_console"         => self.color_console = parse_colorcell(value)?,
+            "color_cursor_lines"    => self.color_cursor_lines = parse_colorcell(value)?,
+            "color_popup"           => self.color_popup = parse_colorcell(value)?,
+            "color_popup_selected"  => self.color_popup_selected = parse_colorcell(value)?,
+            "color_selection"       => self.color_selection = parse_colorcell(value)?,
+            "color_mode_command"    => self.color_mode_command = parse_colorcell(value)?,
+            "color_mode_insert"     => self.color_mode_insert = parse_colorcell(value)?,
+            "color_mode_replace"    => self.color_mode_replace = parse_colorcell(value)?,
+            "color_mode_exit"       => self.color_mode_exit = parse_colorcell(value)?,
+            "tab_expansion"         => self.tab_expansion = parse_int(value, 1, 16)?,
+            "color_depth"           => self.color_depth = parse_colordepth(value)?,
+            "logfile"               => self.logfile = value.to_string(),
+            "sessionfile"           => self.sessionfile = value.to_string(),
+            _                       => return Err(format!("unknown key '{}'", key)),
+        }
+        Ok(())
+    }
+}
+
+// Cut a line at the first '#' which does not start a '#rrggbb' color.
+fn strip_comment(line: &str) -> &str {
+    let bytes = line.as_bytes();
+    for (i, &c) in bytes.iter().enumerate() {
+        let is_color = i > 0 && (bytes[i - 1] == b' ' || bytes[i - 1] == b'\t')
+            && bytes.len() >= i + 7
+            && bytes[i + 1..i + 7].iter().all(|c| c.is_ascii_hexdigit())
+            && (bytes.len() == i + 7 || bytes[i + 7] == b' ' || bytes[i + 7] == b'\t');
+        if c == b'#' && !is_color {
+            return &line[..i]
+        }
+    }
+    line
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "on" | "yes" | "1"     => Ok(true),
+        "false" | "off" | "no" | "0"    => Ok(false),
+        _                               => Err(format!("invalid boolean '{}'", value)),
+    }
+}
+
+fn parse_colordepth(value: &str) -> Result<Option<Colordepth>, String> {
+    match value {
+        "auto"      => Ok(None),
+        "mono"      => Ok(Some(Colordepth::Monochrome)),
+        "16"        => Ok(Some(Colordepth::Ansi16)),
+        "256"       => Ok(Some(Colordepth::Ansi256)),
+        "truecolor" => Ok(Some(Colordepth::Truecolor)),
+        _           => Err(format!("invalid color depth '{}', expected auto, mono, 16, 256 or truecolor", value)),
+    }
+}
+
+fn parse_int(value: &str, min: i32, max: i32) -> Result<i32, String> {
+    match value.parse::<i32>() {
+        Ok(n) if min <= n && n <= max   => Ok(n),
+        _                               => Err(format!("invalid number '{}', expected {} to {}", value, min, max)),
+    }
+}
+
+// A foreground and a background color, separated by spaces.
+pub fn parse_colorcell(value: &str) -> Result<Colorcell, String> {
+    let colors : Vec<&str> = value.split_whitespace().collect();
+    if colors.len() != 2 {
+        return Err(format!("expected foreground and background colors, got '{}'", value))
+    }
+    Ok(Colorcell {
+        fg: parse_color(colors[0])?,
+        bg: parse_color(colors[1])?,
+    })
+}
+
+// One of the 16 ansi color names like 'red' or 'bold_red', 'gray:N' with N in 0..24,
+// 'rgb216:R,G,B' with R, G and B in 0..6, or a 24 bit color '#rrggbb'.
+pub fn parse_color(value: &str) -> Result<Color, String> {
+    let color = match value.to_lowercase().as_str() {
+        "black"         => Color::Black,
+        "red"           => Color::Red,
+        "green"         => Color::Green,
+        "yellow"        => Color::Yellow,
+        "blue"          => Color::Blue,
+        "magenta"       => Color::Magenta,
+        "cyan"          => Color::Cyan,
+        "white"         => Color::White,
+        "bold_black"    => Color::BoldBlack,
+        "bold_red"      => Color::BoldRed,
+        "bold_green"    => Color::BoldGreen,
+        "bold_yellow"   => Color::BoldYellow,
+        "bold_blue"     => Color::BoldBlue,
+        "bold_magenta"  => Color::BoldMagenta,
+        "bold_cyan"     => Color::BoldCyan,
+        "bold_white"    => Color::BoldWhite,
+        v if v.starts_with("gray:") => {
+            Color::Gray(parse_int(&v["gray:".len()..], 0, 23)?)
+        }
+        v if v.starts_with("#") && v.len() == 7 && v[1..].bytes().all(|c| c.is_ascii_hexdigit()) => {
+            let channel = |i: usize| u8::from_str_radix(&v[i..i + 2], 16)
+                .map_err(|_| format!("invalid color '{}'", value));
+            Color::Rgb(channel(1)?, channel(3)?, channel(5)?)
+        }
+        v if v.starts_with("rgb216:") => {
+            let c : Vec<&str> = v["rgb216:".len()..].split(',').collect();
+            if c.len() != 3 {
+                return Err(format!("invalid color '{}'", value))
+            }
+            Color::RGB216 {
+                r: parse_int(c[0], 0, 5)?,
+                g: parse_int(c[1], 0, 5)?,
+                b: parse_int(c[2], 0, 5)?,
+            }
+        }
+        _ => return Err(format!("invalid color '{}'", value)),
+    };
+    Ok(color)
+}
 
-    logfile:                &"/tmp/czl.log",
-};
 
+// A key sequence bound to a named command by the config file.
+#[derive(Debug, Clone)]
+pub struct Keybinding {
+    pub keys:       Vec<Input>,
+    pub command:    String,
+    pub origin:     String,     // file and line of the binding, for error messages
+}
 
 pub struct Config {
     pub draw_screen:            bool,
@@ -135,6 +425,9 @@ pub struct Config {
     pub color_lineno:           Colorcell,
     pub color_console:          Colorcell,
     pub color_cursor_lines:     Colorcell,
+    pub color_popup:            Colorcell,
+    pub color_popup_selected:   Colorcell,
+    pub color_selection:        Colorcell,
 
     pub color_mode_command:     Colorcell,
     pub color_mode_insert:      Colorcell,
@@ -143,10 +436,18 @@ pub struct Config {
 
     pub tab_expansion:          i32,
 
-    pub logfile:                &'static str,
+    pub color_depth:            Option<Colordepth>, // detected from the terminal when not set
+
+    pub keymap_command:         Vec<Keybinding>,    // applied over the default key bindings
+    pub keymap_insert:          Vec<Keybinding>,
+
+    pub logfile:                String,
+    pub sessionfile:            String,
 }
 
 
+
+
 } // mod conf
 
 
@@ -162,6 +463,8 @@ use std::ops::AddAssign;
 use std::ops::Neg;
 use std::ops::Sub;
 
+use util::*;
+
 
 #[derive(PartialEq, Debug, Clone, Copy)]
 pub enum Color {
@@ -187,6 +490,17 @@ pub enum Color {
     RGB216 { r: i32, g: i32, b: i32 },
     /* 24 level of Grays */
     Gray(i32),
+    /* 24 bit colors, approximated by the closest palette color without truecolor support */
+    Rgb(u8, u8, u8),
+}
+
+// How many colors the terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Colordepth {
+    Monochrome,
+    Ansi16,
+    Ansi256,
+    Truecolor,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -216,7 +530,71 @@ pub fn colorcode(c : Color) -> i32 {
         BoldWhite                => 15,
         RGB216 { r, g, b }       => 16 + (b + 6 * (g + 6 * r)),
         Gray(g)                  => 232 + g,
+        Rgb(r, g, b)             => COLORCODE_RGB | (r as i32) << 16 | (g as i32) << 8 | b as i32,
+    }
+}
+
+// Flag of color codes holding a 24 bit color instead of a 256 colors palette index.
+pub const COLORCODE_RGB : i32 = 1 << 24;
+
+const ANSI16_RGB : [(u8, u8, u8); 16] = [
+    (0, 0, 0),          (205, 0, 0),        (0, 205, 0),        (205, 205, 0),
+    (0, 0, 238),        (205, 0, 205),      (0, 205, 205),      (229, 229, 229),
+    (127, 127, 127),    (255, 0, 0),        (0, 255, 0),        (255, 255, 0),
+    (92, 92, 255),      (255, 0, 255),      (0, 255, 255),      (255, 255, 255),
+];
+
+const CUBE_LEVELS : [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+// The rgb value of a color code, using the xterm values for the 256 colors palette.
+pub fn colorcode_rgb(code: i32) -> (u8, u8, u8) {
+    if code & COLORCODE_RGB != 0 {
+        return ((code >> 16) as u8, (code >> 8) as u8, code as u8)
+    }
+    match code {
+        0 ..= 15    => ANSI16_RGB[usize(code)],
+        16 ..= 231  => {
+            let c = code - 16;
+            (CUBE_LEVELS[usize(c / 36)], CUBE_LEVELS[usize(c / 6 % 6)], CUBE_LEVELS[usize(c % 6)])
+        }
+        _           => {
+            let v = (8 + 10 * (code - 232)) as u8;
+            (v, v, v)
+        }
+    }
+}
+
+fn color_distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> i32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32) * (x as i32 - y as i32);
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+// The closest palette index for a color code, in the 6x6x6 cube or the gray ramp.
+pub fn colorcode_256(code: i32) -> i32 {
+    if code & COLORCODE_RGB == 0 {
+        return code
+    }
+    let rgb = colorcode_rgb(code);
+    let level = |v: u8| {
+        (0..6).min_by_key(|&i| (CUBE_LEVELS[i] as i32 - v as i32).abs()).unwrap_or(0) as i32
+    };
+    let cube = 16 + 36 * level(rgb.0) + 6 * level(rgb.1) + level(rgb.2);
+    let average = (rgb.0 as i32 + rgb.1 as i32 + rgb.2 as i32) / 3;
+    let gray = 232 + ((average - 3) / 10).clamp(0, 23);
+    if color_distance(rgb, colorcode_rgb(gray)) < color_distance(rgb, colorcode_rgb(cube)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+// The closest of the 16 ansi colors for a color code.
+pub fn colorcode_16(code: i32) -> i32 {
+    if (0..16).contains(&code) {
+        return code
     }
+    let rgb = colorcode_rgb(code);
+    (0..16).min_by_key(|&i| color_distance(rgb, ANSI16_RGB[i])).unwrap_or(0) as i32
 }
 
 
@@ -409,7 +787,6 @@ mod util {
 
 use std;
 use std::cmp::min;
-use std::error::Error;
 use std::io;
 use std::io::Write;
 use std::fs;
@@ -433,6 +810,16 @@ pub struct Er {
     pub descr: String,
 }
 
+impl Er {
+    // The description without the source location, for showing to the user.
+    pub fn cause(&self) -> &str {
+        match self.descr.find(" cause: ") {
+            Some(i) => &self.descr[i + " cause: ".len()..],
+            None    => &self.descr,
+        }
+    }
+}
+
 impl fmt::Display for Er {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.write_str(&self.descr)
@@ -447,14 +834,14 @@ impl std::error::Error for Er {
 
 impl From<io::Error> for Er {
     fn from(err: io::Error) -> Er {
-        Er { descr: format!("{}:?? cause: {}", file!(), err.description()) }
+        Er { descr: format!("{}:?? cause: {}", file!(), err) }
     }
 }
 
 
 impl From<mpsc::RecvError> for Er {
     fn from(err: mpsc::RecvError) -> Er {
-        Er { descr: format!("{}:?? cause: {}", file!(), err.description()) }
+        Er { descr: format!("{}:?? cause: {}", file!(), err) }
     }
 }
 
@@ -516,6 +903,9 @@ pub fn itoa10_left(dst: &mut [u8], x: i32) -> usize {
         y /= 10;
         n += 1;
     }
+    if x == 0 {
+        n = 1;
+    }
     y = x;
     let n_digits = n;
     for i in (0..n).rev() {
@@ -654,6 +1044,7 @@ impl Debugconsole {
 
 mod ioutil {
 
+use std::env;
 use std::fs;
 use util::*;
 use std::io::Read;
@@ -673,22 +1064,215 @@ pub fn file_load(filename: &str) -> Re<Vec<u8>> {
     Ok(buf)
 }
 
+// Replace a leading '~' by the user home directory.
+pub fn expand_home(path: &str) -> String {
+    if let Some(rest) = path.strip_prefix('~') {
+        if let Ok(home) = env::var("HOME") {
+            return format!("{}{}", home, rest)
+        }
+    }
+    path.to_string()
+}
+
+// Absolute path of a file, or the path as is if the file does not exist.
+pub fn canonical_path(path: &str) -> String {
+    match fs::canonicalize(path) {
+        Ok(p)   => p.to_string_lossy().into_owned(),
+        Err(_)  => path.to_string(),
+    }
+}
+
 
 } // mod ioutil
 
 
 
 
+/* PERSISTENT EDITOR STATE */
+mod session {
+
+
+use std::fs;
+use std::io::Write;
+
+use core::*;
+use util::*;
+use ioutil;
+
+
+// A global mark, pointing to a position in any file.
+#[derive(Debug, Clone)]
+pub struct Mark {
+    pub name:       char,
+    pub filepath:   String,
+    pub cursor:     Pos,
+}
+
+// A pane of a tab page: the file it shows and the cursor position in that file.
+#[derive(Debug, Clone)]
+pub struct Paneinfo {
+    pub filepath:   String,
+    pub cursor:     Pos,
+}
+
+// A tab page: its name, how its panes are split, and which pane has the focus.
+#[derive(Debug, Clone)]
+pub struct Tabinfo {
+    pub name:       String,
+    pub layout:     String,     // split tree in prefix notation: "V" and "H" splits, and pane indexes
+    pub panes:      Vec<Paneinfo>,
+    pub focus:      usize,
+}
+
+// Editor state saved across restarts, stored as one entry per line:
+//  mark <name> <lineno> <colno> <filepath>
+//  tab <focus> <name>                  starts a tab page, the entries below belong to it
+//  layout <splits>
+//  pane <lineno> <colno> <filepath>
+//  current <index>                     the tab page shown
+pub struct Session {
+    pub marks:      Vec<Mark>,
+    pub tabs:       Vec<Tabinfo>,
+    pub current:    usize,
+}
+
+impl Session {
+    pub fn load(path: &str) -> Re<Session> {
+        let mut session = Session {
+            marks:      Vec::new(),
+            tabs:       Vec::new(),
+            current:    0,
+        };
+
+        let text = match ioutil::file_load(path) {
+            Ok(text)    => text,
+            Err(_)      => return Ok(session), // no session saved yet
+        };
+
+        // Invalid entries are skipped with a warning, with the entries of an invalid tab.
+        let mut tab_skipped = false;
+        for (i, line) in String::from_utf8_lossy(&text).lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("#") {
+                continue
+            }
+            let fields : Vec<&str> = line.splitn(5, ' ').collect();
+            let error = match fields[0] {
+                "mark" if fields.len() == 5 => {
+                    let name = fields[1].chars().next().unwrap_or(' ');
+                    let y = fields[2].parse::<i32>();
+                    let x = fields[3].parse::<i32>();
+                    match (y, x) {
+                        (Ok(y), Ok(x)) => {
+                            session.marks.push(Mark {
+                                name,
+                                filepath:   fields[4].to_string(),
+                                cursor:     pos(x, y),
+                            });
+                            None
+                        }
+                        _ => Some("invalid mark"),
+                    }
+                }
+                "tab" => {
+                    let fields : Vec<&str> = line.splitn(3, ' ').collect();
+                    match fields.get(1).map(|f| f.parse::<usize>()) {
+                        Some(Ok(focus)) => {
+                            session.tabs.push(Tabinfo {
+                                name:   fields.get(2).unwrap_or(&"").to_string(),
+                                layout: String::new(),
+                                panes:  Vec::new(),
+                                focus,
+                            });
+                            tab_skipped = false;
+                            None
+                        }
+                        _ => {
+                            tab_skipped = true;
+                            Some("invalid tab")
+                        }
+                    }
+                }
+                "layout" | "pane" if tab_skipped => None,
+                "layout" => match session.tabs.last_mut() {
+                    Some(tab)   => {
+                        tab.layout = line["layout".len()..].trim().to_string();
+                        None
+                    }
+                    None        => Some("layout outside of a tab"),
+                },
+                "pane" if fields.len() >= 4 => {
+                    let fields : Vec<&str> = line.splitn(4, ' ').collect();
+                    let y = fields[1].parse::<i32>();
+                    let x = fields[2].parse::<i32>();
+                    match (session.tabs.last_mut(), y, x) {
+                        (Some(tab), Ok(y), Ok(x)) => {
+                            tab.panes.push(Paneinfo {
+                                filepath:   fields[3].to_string(),
+                                cursor:     pos(x, y),
+                            });
+                            None
+                        }
+                        _ => Some("invalid pane"),
+                    }
+                }
+                "current" if fields.len() == 2 => {
+                    match fields[1].parse::<usize>() {
+                        Ok(current) => {
+                            session.current = current;
+                            None
+                        }
+                        Err(_)      => Some("invalid current tab"),
+                    }
+                }
+                _ => Some("unknown entry"),
+            };
+            if let Some(error) = error {
+                logconsole(&format!("{}:{} {}, skipped: '{}'", path, i + 1, error, line));
+            }
+        }
+
+        Ok(session)
+    }
+
+    pub fn save(&self, path: &str) -> Re<()> {
+        let mut f = fs::File::create(path)?;
+        f.write_all(b"# czl session\n")?;
+        for m in &self.marks {
+            writeln!(f, "mark {} {} {} {}", m.name, m.cursor.y, m.cursor.x, m.filepath)?;
+        }
+        for t in &self.tabs {
+            writeln!(f, "tab {} {}", t.focus, t.name)?;
+            writeln!(f, "layout {}", t.layout)?;
+            for p in &t.panes {
+                writeln!(f, "pane {} {} {}", p.cursor.y, p.cursor.x, p.filepath)?;
+            }
+        }
+        writeln!(f, "current {}", self.current)?;
+        Ok(())
+    }
+}
+
+
+} // mod session
+
+
+
+
 /* TERMINAL BINDINGS */
 mod term {
 
 
+use std::cmp::min;
+use std::collections::VecDeque;
+use std::env;
 use std::fmt;
-use std::error::Error;
+use std::fs;
 use std::io;
 use std::io::Read;
 use std::io::Write;
 use std::panic;
+use std::sync::OnceLock;
 use std::sync::mpsc::Receiver;
 use std::sync::mpsc::SyncSender;
 
@@ -719,6 +1303,130 @@ extern "C" {
 static mut is_raw : bool = false;
 
 
+// What the terminal supports beyond moving the cursor and writing text.
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    pub colordepth:     Colordepth,
+    pub altscreen:      bool,   // "\x1b[?47h": draw in a separate screen restored at exit
+    pub mouse:          bool,   // "\x1b[?1000h", "\x1b[?1002h" and "\x1b[?1006h": report mouse clicks and
+                                // drags, in SGR format
+    pub focus:          bool,   // "\x1b[?1004h": report focus in and out
+    pub paste:          bool,   // "\x1b[?2004h": wrap pasted text in "\x1b[200~" and "\x1b[201~"
+}
+
+static CAPABILITIES : OnceLock<Capabilities> = OnceLock::new();
+
+// The capabilities of the terminal, detected on first use.
+pub fn capabilities() -> &'static Capabilities {
+    CAPABILITIES.get_or_init(|| {
+        let term = env::var("TERM").unwrap_or_default();
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        Capabilities::detect(&term, &colorterm, terminfo_colors(&term))
+    })
+}
+
+impl Capabilities {
+    // Detect capabilities from the TERM and COLORTERM environment variables and from the number of
+    // colors of the terminfo entry of TERM, if there is one.
+    pub fn detect(term: &str, colorterm: &str, terminfo_colors: Option<i32>) -> Capabilities {
+        let is_dumb = term.is_empty() || term == "dumb";
+        // The Linux virtual console and other hardware-like terminals only know the vt100 basics.
+        let is_console = is_dumb || term == "linux" || term.starts_with("vt") || term == "cons25";
+
+        let mut colordepth = match terminfo_colors {
+            _ if is_dumb                => Colordepth::Monochrome,
+            Some(n) if n >= 256         => Colordepth::Ansi256,
+            Some(n) if n >= 8           => Colordepth::Ansi16,
+            Some(_)                     => Colordepth::Monochrome,
+            None if term.contains("256color") => Colordepth::Ansi256,
+            None                        => Colordepth::Ansi16,
+        };
+        if !is_dumb && (colorterm == "truecolor" || colorterm == "24bit") {
+            colordepth = Colordepth::Truecolor;
+        }
+        if let Some(depth) = CONF.color_depth {
+            colordepth = depth;
+        }
+
+        Capabilities {
+            colordepth,
+            altscreen:  !is_console,
+            mouse:      !is_console,
+            focus:      !is_console,
+            paste:      !is_console,
+        }
+    }
+}
+
+// The 'colors' number of the terminfo entry of 'term', if that entry can be found.
+fn terminfo_colors(term: &str) -> Option<i32> {
+    let first = term.chars().next()?;
+    if term.contains('/') {
+        return None
+    }
+
+    let mut dirs = Vec::new();
+    if let Ok(dir) = env::var("TERMINFO") {
+        dirs.push(dir);
+    }
+    if let Ok(home) = env::var("HOME") {
+        dirs.push(format!("{}/.terminfo", home));
+    }
+    if let Ok(list) = env::var("TERMINFO_DIRS") {
+        dirs.extend(list.split(':').filter(|d| !d.is_empty()).map(String::from));
+    }
+    for dir in &["/etc/terminfo", "/lib/terminfo", "/usr/share/terminfo", "/usr/lib/terminfo"] {
+        dirs.push(dir.to_string());
+    }
+
+    // Entries are grouped by first letter, or by the hex code of that letter on macOS.
+    for dir in &dirs {
+        for group in &[first.to_string(), format!("{:x}", first as u32)] {
+            if let Ok(data) = fs::read(format!("{}/{}/{}", dir, group, term)) {
+                return terminfo_number(&data, TERMINFO_COLORS)
+            }
+        }
+    }
+    None
+}
+
+// Index of 'colors' in the numbers section of compiled terminfo entries.
+const TERMINFO_COLORS : usize = 13;
+
+// Read a number capability from a compiled terminfo entry, see term(5).
+fn terminfo_number(data: &[u8], index: usize) -> Option<i32> {
+    let short = |i: usize| -> Option<i32> {
+        Some(i16::from_le_bytes([*data.get(i)?, *data.get(i + 1)?]) as i32)
+    };
+    let width = match short(0)? {
+        0o432   => 2,   // legacy format with 16 bit numbers
+        0o1036  => 4,   // extended format with 32 bit numbers
+        _       => return None,
+    };
+    let names = usize(short(2)?);
+    let bools = usize(short(4)?);
+    let numbers = usize(short(6)?);
+    if index >= numbers {
+        return None
+    }
+
+    let mut offset = 12 + names + bools;
+    if offset % 2 == 1 {
+        offset += 1;    // numbers start on an even byte
+    }
+    let at = offset + index * width;
+    let n = if width == 2 {
+        short(at)?
+    } else {
+        i32::from_le_bytes([*data.get(at)?, *data.get(at + 1)?, *data.get(at + 2)?, *data.get(at + 3)?])
+    };
+    if n < 0 {
+        return None     // absent capability
+    }
+    Some(n)
+}
+
+
 // Empty object used to safely control terminal raw mode and properly exit raw mode at scope exit.
 pub struct Term {
 }
@@ -739,13 +1447,24 @@ impl Term {
 
     pub fn set_raw() -> Re<Term> {
         if !CONF.no_raw_mode {
+            let caps = capabilities();
             let stdout = io::stdout();
             let mut h = stdout.lock();
             h.write(b"\x1b[s")?;            // save cursor
-            h.write(b"\x1b[?47h")?;         // go offscreen
-            h.write(b"\x1b[?1000h")?;       // get mouse event
-            h.write(b"\x1b[?1002h")?;       // track mouse event
-            h.write(b"\x1b[?1004h")?;       // get focus event
+            if caps.altscreen {
+                h.write(b"\x1b[?47h")?;     // go offscreen
+            }
+            if caps.mouse {
+                h.write(b"\x1b[?1000h")?;   // get mouse event
+                h.write(b"\x1b[?1002h")?;   // track mouse event
+                h.write(b"\x1b[?1006h")?;   // mouse events in SGR format, for any column
+            }
+            if caps.focus {
+                h.write(b"\x1b[?1004h")?;   // get focus event
+            }
+            if caps.paste {
+                h.write(b"\x1b[?2004h")?;   // bracketed paste
+            }
             h.flush()?;
 
             unsafe {
@@ -771,12 +1490,23 @@ impl Term {
             }
         }
 
+        let caps = capabilities();
         let stdout = io::stdout();
         let mut h = stdout.lock();
-        h.write(b"\x1b[?1004l").unwrap();   // stop focus event
-        h.write(b"\x1b[?1002l").unwrap();   // stop mouse tracking
-        h.write(b"\x1b[?1000l").unwrap();   // stop mouse event
-        h.write(b"\x1b[?47l").unwrap();     // go back to main screen
+        if caps.paste {
+            h.write(b"\x1b[?2004l").unwrap();   // stop bracketed paste
+        }
+        if caps.focus {
+            h.write(b"\x1b[?1004l").unwrap();   // stop focus event
+        }
+        if caps.mouse {
+            h.write(b"\x1b[?1006l").unwrap();   // stop SGR format
+            h.write(b"\x1b[?1002l").unwrap();   // stop mouse tracking
+            h.write(b"\x1b[?1000l").unwrap();   // stop mouse event
+        }
+        if caps.altscreen {
+            h.write(b"\x1b[?47l").unwrap();     // go back to main screen
+        }
         h.write(b"\x1b[u").unwrap();        // restore cursor
         h.flush().unwrap();
 
@@ -788,16 +1518,18 @@ impl Term {
 }
 
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Input {
     Noinput,
     Error,
     UnknownEscSeq,
     Key(char),
-    Click(Pos),
-    ClickRelease(Pos),
+    Mouse(Mouse),
+    Special(Specialkey, u8),    // a key sending an escape sequence, with the SHIFT, ALT and CTRL bits
+    Paste(String),              // text pasted in bracketed paste mode
     EscZ,               // shift + tab -> "\x1b[Z"
     Resize,
+    Wakeup,
 }
 
 impl fmt::Display for Input {
@@ -808,49 +1540,210 @@ impl fmt::Display for Input {
             Error                           => f.write_str(&"Error"),
             UnknownEscSeq                   => f.write_str(&"Unknown"),
             Key(c)                          => Input::fmt_key_name(*c, f),
-            Click(Pos { x, y })             => write!(f, "click ({},{})'", y, x),
-            ClickRelease(Pos { x, y })      => write!(f, "unclick ({},{})'", y, x),
+            Mouse(m)                        => m.fmt(f),
+            Special(key, modifiers)         => Input::fmt_special(*key, *modifiers, f),
+            Paste(text)                     => write!(f, "paste ({} bytes)", text.len()),
             EscZ                            => f.write_str(&"EscZ"),
             Resize                          => f.write_str(&"Resize"),
+            Wakeup                          => f.write_str(&"Wakeup"),
         }
     }
 }
 
-impl Input {
-    // return Some(name) for keys with a special description
-    fn key_descr(c: char) -> Option<&'static str> {
-        let r = match c {
-            CTRL_AT             => &"^@",
-            CTRL_A              => &"^A",
-            CTRL_B              => &"^B",
-            CTRL_C              => &"^C",
-            CTRL_D              => &"^D",
-            CTRL_E              => &"^E",
-            CTRL_F              => &"^F",
-            CTRL_G              => &"^G",
-            BACKSPACE           => &"Backspace",
-            TAB                 => &"TAB",
-            CTRL_J              => &"^J",
-            CTRL_K              => &"^K",
-            CTRL_L              => &"^L",
-            ENTER               => &"Enter",
-            CTRL_N              => &"^N",
-            CTRL_O              => &"^O",
-            CTRL_P              => &"^P",
-            CTRL_Q              => &"^Q",
-            CTRL_R              => &"^R",
-            CTRL_S              => &"^S",
-            CTRL_T              => &"^T",
-            CTRL_U              => &"^U",
-            CTRL_V              => &"^V",
-            CTRL_W              => &"^W",
-            CTRL_X              => &"^X",
-            CTRL_Y              => &"^Y",
-            CTRL_Z              => &"^Z",
-            ESC                 => &"Esc",
-            CTRL_BACKSLASH      => &"^\\",
-            CTRL_RIGHT_BRACKET  => &"^]",
-            CTRL_CARET          => &"^^",
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Specialkey {
+    Up,
+    Down,
+    Right,
+    Left,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    F(u8),
+}
+
+// Modifier keys of special keys, as encoded in escape sequences minus one.
+pub const SHIFT : u8 = 1;
+pub const ALT   : u8 = 2;
+pub const CTRL  : u8 = 4;
+
+const MODIFIER_NAMES : &[(u8, &str)] = &[(CTRL, "C-"), (ALT, "A-"), (SHIFT, "S-")];
+
+impl Specialkey {
+    fn name(self) -> String {
+        use Specialkey::*;
+        let name = match self {
+            Up          => "Up",
+            Down        => "Down",
+            Right       => "Right",
+            Left        => "Left",
+            Home        => "Home",
+            End         => "End",
+            PageUp      => "PgUp",
+            PageDown    => "PgDn",
+            Insert      => "Insert",
+            Delete      => "Delete",
+            F(n)        => return format!("F{}", n),
+        };
+        name.to_string()
+    }
+
+    fn parse(name: &str) -> Option<Specialkey> {
+        use Specialkey::*;
+        let keys = [Up, Down, Right, Left, Home, End, PageUp, PageDown, Insert, Delete];
+        if let Some(&key) = keys.iter().find(|k| k.name() == name) {
+            return Some(key)
+        }
+        match name.trim_start_matches('F').parse::<u8>() {
+            Ok(n) if name.starts_with('F') && (1..=12).contains(&n) => Some(F(n)),
+            _ => None,
+        }
+    }
+
+    // The key of the "\x1b[n~" escape sequence.
+    fn from_tilde_code(n: i32) -> Option<Specialkey> {
+        use Specialkey::*;
+        let key = match n {
+            1 | 7           => Home,
+            2               => Insert,
+            3               => Delete,
+            4 | 8           => End,
+            5               => PageUp,
+            6               => PageDown,
+            11 ..= 15       => F(n as u8 - 10),
+            17 ..= 21       => F(n as u8 - 11),
+            23 | 24         => F(n as u8 - 12),
+            _               => return None,
+        };
+        Some(key)
+    }
+
+    // The key of the "\x1b[c" and "\x1bOc" escape sequences.
+    fn from_final_char(c: char) -> Option<Specialkey> {
+        use Specialkey::*;
+        let key = match c {
+            'A' => Up,
+            'B' => Down,
+            'C' => Right,
+            'D' => Left,
+            'H' => Home,
+            'F' => End,
+            'P' => F(1),
+            'Q' => F(2),
+            'R' => F(3),
+            'S' => F(4),
+            _   => return None,
+        };
+        Some(key)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Button {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+    Unknown,    // releases in the legacy format do not tell which button, and other buttons
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mouseaction {
+    Press,
+    Release,
+    Motion,     // with the button pressed
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mouse {
+    pub pos:        Pos,    // screen position, from (0,0)
+    pub button:     Button,
+    pub action:     Mouseaction,
+    pub shift:      bool,
+    pub alt:        bool,
+    pub ctrl:       bool,
+}
+
+impl Mouse {
+    // Decode the button code of a mouse report. Bits 0 and 1 are the button, 4, 8 and 16 the
+    // modifier keys, 32 is set for motions and 64 for wheel buttons. The legacy format reports
+    // releases as button 3, the SGR format with the 'release' flag.
+    fn mk_mouse(code: i32, pos: Pos, release: bool) -> Mouse {
+        let button = match (code & 64 != 0, code & 3) {
+            (false, 0)  => Button::Left,
+            (false, 1)  => Button::Middle,
+            (false, 2)  => Button::Right,
+            (true, 0)   => Button::WheelUp,
+            (true, 1)   => Button::WheelDown,
+            _           => Button::Unknown,
+        };
+        let action = match (release || (code & 64 == 0 && code & 3 == 3), code & 32 != 0) {
+            (true, _)       => Mouseaction::Release,
+            (false, true)   => Mouseaction::Motion,
+            (false, false)  => Mouseaction::Press,
+        };
+        Mouse {
+            pos,
+            button,
+            action,
+            shift:  code & 4 != 0,
+            alt:    code & 8 != 0,
+            ctrl:   code & 16 != 0,
+        }
+    }
+}
+
+impl fmt::Display for Mouse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let modifiers = [(self.ctrl, "ctrl "), (self.alt, "alt "), (self.shift, "shift ")];
+        for &(is_set, name) in modifiers.iter() {
+            if is_set {
+                f.write_str(name)?;
+            }
+        }
+        write!(f, "{:?} {:?} ({},{})", self.button, self.action, self.pos.y, self.pos.x)
+    }
+}
+
+impl Input {
+    // return Some(name) for keys with a special description
+    fn key_descr(c: char) -> Option<&'static str> {
+        let r = match c {
+            CTRL_AT             => &"^@",
+            CTRL_A              => &"^A",
+            CTRL_B              => &"^B",
+            CTRL_C              => &"^C",
+            CTRL_D              => &"^D",
+            CTRL_E              => &"^E",
+            CTRL_F              => &"^F",
+            CTRL_G              => &"^G",
+            BACKSPACE           => &"Backspace",
+            TAB                 => &"TAB",
+            CTRL_J              => &"^J",
+            CTRL_K              => &"^K",
+            CTRL_L              => &"^L",
+            ENTER               => &"Enter",
+            CTRL_N              => &"^N",
+            CTRL_O              => &"^O",
+            CTRL_P              => &"^P",
+            CTRL_Q              => &"^Q",
+            CTRL_R              => &"^R",
+            CTRL_S              => &"^S",
+            CTRL_T              => &"^T",
+            CTRL_U              => &"^U",
+            CTRL_V              => &"^V",
+            CTRL_W              => &"^W",
+            CTRL_X              => &"^X",
+            CTRL_Y              => &"^Y",
+            CTRL_Z              => &"^Z",
+            ESC                 => &"Esc",
+            CTRL_BACKSLASH      => &"^\\",
+            CTRL_RIGHT_BRACKET  => &"^]",
+            CTRL_CARET          => &"^^",
             CTRL_UNDERSCORE     => &"^_",
             SPACE               => &"Space",
             DEL                 => &"Del",
@@ -859,6 +1752,45 @@ impl Input {
         Some(r)
     }
 
+    // The key called 'name' in the config file: a single character, a special key as described
+    // by key_descr(), or a key sending an escape sequence with optional "C-", "A-" and "S-"
+    // modifier prefixes, like "C-Left".
+    pub fn parse_key(name: &str) -> Option<Input> {
+        let mut rest = name;
+        let mut modifiers = 0;
+        while let Some(&(m, prefix)) = MODIFIER_NAMES.iter().find(|&&(_, prefix)| rest.starts_with(prefix) && rest.len() > 2) {
+            modifiers |= m;
+            rest = &rest[prefix.len()..];
+        }
+        if let Some(key) = Specialkey::parse(rest) {
+            return Some(Input::Special(key, modifiers))
+        }
+        if modifiers != 0 {
+            return None
+        }
+        let mut chars = name.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            return Some(Input::Key(c))
+        }
+        if name == "EscZ" {
+            return Some(Input::EscZ)
+        }
+        let bytes = name.as_bytes();
+        if bytes.len() == 2 && bytes[0] == b'^' && (b'@' <= bytes[1] && bytes[1] <= b'_') {
+            return Some(Input::Key((bytes[1] - b'@') as char))    // any control key, like ^H for Backspace
+        }
+        (0u8..128).map(|b| b as char).find(|&c| Input::key_descr(c) == Some(name)).map(Input::Key)
+    }
+
+    fn fmt_special(key: Specialkey, modifiers: u8, f: &mut fmt::Formatter) -> fmt::Result {
+        for &(m, prefix) in MODIFIER_NAMES {
+            if modifiers & m != 0 {
+                f.write_str(prefix)?;
+            }
+        }
+        f.write_str(&key.name())
+    }
+
     fn fmt_key_name(c: char, f: &mut fmt::Formatter) -> fmt::Result {
         match Input::key_descr(c) {
             Some(s) => f.write_str(s),
@@ -912,6 +1844,7 @@ pub const ENTER                 : char = CTRL_M;
 
 // Special code
 pub const RESIZE                : char = 255 as char; //'\xff';
+pub const WAKEUP                : char = 254 as char; // background work is ready to be shown
 
 
 pub fn is_printable(c : char) -> bool {
@@ -936,58 +1869,341 @@ pub fn push_char(chan: &SyncSender<char>) {
     }
 }
 
-pub fn pull_input(chan: &Receiver<char>) -> Re<Input> {
-    use Input::*;
-    use std::sync::mpsc::TryRecvError::*;
+// Time to wait for the rest of an escape sequence. An escape key followed by other keys typed
+// later is not a sequence.
+const ESC_TIMEOUT_MS : u64 = 25;
 
-    let c = chan.recv()?;
+// Time to wait for the rest of a pasted text before giving up on its end sequence.
+const PASTE_TIMEOUT_MS : u64 = 1000;
+const PASTE_END : &str = "\x1b[201~";
+
+// Decodes the characters read from the terminal into inputs.
+pub struct Inputreader {
+    chan:       Receiver<char>,
+    // Read but not handled yet: a key after an escape key which is not part of an escape sequence,
+    // and resize and wakeup signals arriving in the middle of an escape sequence or of a paste.
+    pending:    VecDeque<char>,
+}
 
-    if c == RESIZE {
-        return Ok(Resize);
+impl Inputreader {
+    pub fn mk_inputreader(chan: Receiver<char>) -> Inputreader {
+        Inputreader {
+            chan,
+            pending: VecDeque::new(),
+        }
     }
 
-    if c != ESC {
-        return Ok(Key(c))
+    pub fn pull(&mut self) -> Re<Input> {
+        use Input::*;
+
+        let c = match self.pending.pop_front() {
+            Some(c) => c,
+            None    => self.chan.recv()?,
+        };
+
+        if c == RESIZE {
+            return Ok(Resize);
+        }
+
+        if c == WAKEUP {
+            return Ok(Wakeup);
+        }
+
+        if c != ESC {
+            return Ok(Key(c))
+        }
+
+        // Escape: unless a sequence start follows immediately, this was just an escape key.
+        match self.next() {
+            Some('[')   => self.pull_csi(),
+            Some('O')   => self.pull_ss3(),
+            Some(c)     => {
+                self.pending.push_back(c);
+                Ok(Key(ESC))
+            }
+            None        => Ok(Key(ESC)),
+        }
     }
 
-    // Escape: if no more char immediately available, return ESC, otherwise parse an escape sequence
+    // The next character of an escape sequence, or None if it does not come in time.
+    fn next(&mut self) -> Option<char> {
+        self.next_within(ESC_TIMEOUT_MS)
+    }
 
-    match chan.try_recv() {
-        Ok(c) if c == '['       => (),                          // Escape sequence: continue parsing
-        Ok(_)                   => return Ok(UnknownEscSeq),    // Error while parsing: bail out
-        Err(Empty)              => return Ok(Key(ESC)),         // Nothing more: this was just an escape key
-        Err(e)                  => return er!(e.description()),
+    // The next character read from the terminal, after setting aside resize and wakeup signals.
+    fn next_within(&mut self, timeout_ms: u64) -> Option<char> {
+        let timeout = std::time::Duration::from_millis(timeout_ms);
+        loop {
+            match self.chan.recv_timeout(timeout) {
+                Ok(c) if c == RESIZE || c == WAKEUP => self.pending.push_back(c),
+                Ok(c)   => return Some(c),
+                Err(_)  => return None,
+            }
+        }
     }
 
-    match chan.recv()? {
-        'M' =>  (), // Mouse click, handled below
-        'Z' =>  return Ok(EscZ),
-        _   =>  return Ok(UnknownEscSeq),
+    // Read a decimal number, and the character after it.
+    fn next_number(&mut self) -> Option<(i32, char)> {
+        let mut n : i32 = 0;
+        loop {
+            let c = self.next()?;
+            match c.to_digit(10) {
+                Some(d) => n = n.saturating_mul(10).saturating_add(d as i32),
+                None    => return Some((n, c)),
+            }
+        }
     }
 
-    // Mouse click
-    // TODO: support other mouse modes
-    let c2 = chan.recv()? as i32;
-    let mut x = chan.recv()? as i32 - 33;
-    let mut y = chan.recv()? as i32 - 33;
-    if x < 0 {
-        x += 255;
+    // Sequences after "\x1b[": "<params>c" for cursor keys, "<params>~" for other keys, with
+    // params "n" or "n;modifiers" where modifiers are one more than the modifier bits.
+    fn pull_csi(&mut self) -> Re<Input> {
+        use Input::*;
+        let c = match self.next() {
+            Some(c) => c,
+            None    => return Ok(UnknownEscSeq),
+        };
+        match c {
+            'M' => return Ok(self.pull_mouse_legacy()),
+            '<' => return Ok(self.pull_mouse_sgr()),
+            'Z' => return Ok(EscZ),
+            '[' => {
+                // Linux console function keys: "\x1b[[A" to "\x1b[[E"
+                let key = match self.next() {
+                    Some(c @ 'A' ..= 'E')   => Special(Specialkey::F(c as u8 - b'A' + 1), 0),
+                    _                       => UnknownEscSeq,
+                };
+                return Ok(key)
+            }
+            _ => (),
+        }
+
+        let mut params = Vec::new();
+        let mut n = 0;
+        let mut c = c;
+        loop {
+            match c {
+                '0' ..= '9' => n = min(n * 10 + (c as i32 - '0' as i32), 10000),
+                ';'         => {
+                    params.push(n);
+                    n = 0;
+                }
+                _           => break,
+            }
+            c = match self.next() {
+                Some(c) => c,
+                None    => return Ok(UnknownEscSeq),
+            };
+        }
+        params.push(n);
+
+        let modifiers = match params.get(1) {
+            Some(&m) if m >= 1  => (m - 1) as u8 & (SHIFT | ALT | CTRL),
+            _                   => 0,
+        };
+        if c == '~' && params[0] == 200 {
+            return Ok(self.pull_paste())
+        }
+        let key = match c {
+            '~' => Specialkey::from_tilde_code(params[0]),
+            _   => Specialkey::from_final_char(c),
+        };
+        match key {
+            Some(key)   => Ok(Special(key, modifiers)),
+            None        => Ok(UnknownEscSeq),
+        }
     }
-    if y < 0 {
-        y += 255;
+
+    // Text after "\x1b[200~" until "\x1b[201~". Like for keys, every char is a byte read from the
+    // terminal. Line breaks are sent as '\r' and become '\n'.
+    fn pull_paste(&mut self) -> Input {
+        let mut text = String::new();
+        while !text.ends_with(PASTE_END) {
+            match self.next_within(PASTE_TIMEOUT_MS) {
+                Some(c) => text.push(c),
+                None    => break,
+            }
+        }
+        if text.ends_with(PASTE_END) {
+            let n = text.len() - PASTE_END.len();
+            text.truncate(n);
+        }
+        Input::Paste(text.replace("\r\n", "\n").replace('\r', "\n"))
     }
 
-    let p = pos(x,y);
+    // Sequences after "\x1bO": cursor keys in application mode, and F1 to F4.
+    fn pull_ss3(&mut self) -> Re<Input> {
+        match self.next().and_then(Specialkey::from_final_char) {
+            Some(key)   => Ok(Input::Special(key, 0)),
+            None        => Ok(Input::UnknownEscSeq),
+        }
+    }
 
-    let r = match c2 & 3 /* ignore modifier keys */ {
-        0 ... 2 =>  Click(p),
-        3       =>  ClickRelease(p),
-        _       =>  UnknownEscSeq,
-    };
+    // Legacy mouse format: button code, column and line as single bytes offset by 32, which cannot
+    // go past column 223.
+    fn pull_mouse_legacy(&mut self) -> Input {
+        let mut bytes = [0; 3];
+        for b in bytes.iter_mut() {
+            match self.next() {
+                Some(c) => *b = c as i32 - 32,
+                None    => return Input::UnknownEscSeq,
+            }
+        }
+        let [code, x, y] = bytes;
+        if x < 1 || y < 1 {
+            return Input::UnknownEscSeq
+        }
+        Input::Mouse(Mouse::mk_mouse(code, pos(x - 1, y - 1), false))
+    }
+
+    // SGR mouse format after "\x1b[<": "b;x;yM" for presses and motions, "b;x;ym" for releases.
+    fn pull_mouse_sgr(&mut self) -> Input {
+        let (code, x, y, c) = match (self.next_number(), self.next_number(), self.next_number()) {
+            (Some((code, ';')), Some((x, ';')), Some((y, c))) => (code, x, y, c),
+            _ => return Input::UnknownEscSeq,
+        };
+        if (c != 'M' && c != 'm') || x < 1 || y < 1 {
+            return Input::UnknownEscSeq
+        }
+        Input::Mouse(Mouse::mk_mouse(code, pos(x - 1, y - 1), c == 'm'))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+use super::*;
+use std::sync::mpsc;
+
+// The inputs decoded from the characters of 'text', read at once.
+fn inputs(text: &str) -> Vec<Input> {
+    let (sender, receiver) = mpsc::channel();
+    for c in text.chars() {
+        sender.send(c).unwrap();
+    }
+    drop(sender);
+    let mut reader = Inputreader::mk_inputreader(receiver);
+    let mut inputs = Vec::new();
+    while let Ok(i) = reader.pull() {
+        inputs.push(i);
+    }
+    inputs
+}
+
+fn mouse(button: Button, action: Mouseaction, x: i32, y: i32) -> Input {
+    Input::Mouse(Mouse { pos: pos(x, y), button, action, shift: false, alt: false, ctrl: false })
+}
+
+#[test]
+fn mk_mouse() {
+    let m = Mouse::mk_mouse(0, pos(4,2), false);
+    assert_eq!((m.button, m.action, m.pos), (Button::Left, Mouseaction::Press, pos(4,2)));
+    assert!(!m.shift && !m.alt && !m.ctrl);
+    assert_eq!(Mouse::mk_mouse(1, pos(0,0), false).button, Button::Middle);
+    assert_eq!(Mouse::mk_mouse(2, pos(0,0), false).button, Button::Right);
+
+    let m = Mouse::mk_mouse(0, pos(0,0), true);
+    assert_eq!((m.button, m.action), (Button::Left, Mouseaction::Release));
+    let m = Mouse::mk_mouse(3, pos(0,0), false);
+    assert_eq!((m.button, m.action), (Button::Unknown, Mouseaction::Release));
+    let m = Mouse::mk_mouse(32, pos(0,0), false);
+    assert_eq!((m.button, m.action), (Button::Left, Mouseaction::Motion));
+
+    let m = Mouse::mk_mouse(64, pos(0,0), false);
+    assert_eq!((m.button, m.action), (Button::WheelUp, Mouseaction::Press));
+    let m = Mouse::mk_mouse(65, pos(0,0), false);
+    assert_eq!((m.button, m.action), (Button::WheelDown, Mouseaction::Press));
+
+    let m = Mouse::mk_mouse(4 | 16 | 2, pos(0,0), false);
+    assert_eq!(m.button, Button::Right);
+    assert!(m.shift && !m.alt && m.ctrl);
+    assert!(Mouse::mk_mouse(8, pos(0,0), false).alt);
+}
+
+#[test]
+fn pull_mouse() {
+    assert_eq!(inputs("\x1b[<0;5;3M"), vec![mouse(Button::Left, Mouseaction::Press, 4, 2)]);
+    assert_eq!(inputs("\x1b[<0;5;3m"), vec![mouse(Button::Left, Mouseaction::Release, 4, 2)]);
+    assert_eq!(inputs("\x1b[<32;300;120M"), vec![mouse(Button::Left, Mouseaction::Motion, 299, 119)]);
+    assert_eq!(inputs("\x1b[<65;1;1M"), vec![mouse(Button::WheelDown, Mouseaction::Press, 0, 0)]);
+    assert_eq!(inputs("\x1b[M\x20\x25\x23"), vec![mouse(Button::Left, Mouseaction::Press, 4, 2)]);
+    assert_eq!(inputs("\x1b[M\x23\x25\x23"), vec![mouse(Button::Unknown, Mouseaction::Release, 4, 2)]);
+
+    assert_eq!(inputs("\x1b[<0;0;3M"), vec![Input::UnknownEscSeq]);
+    assert_eq!(inputs("\x1b[<0;5;3X"), vec![Input::UnknownEscSeq]);
+    assert_eq!(inputs("\x1b[<0;5"), vec![Input::UnknownEscSeq]);
+}
+
+#[test]
+fn pull_csi() {
+    use Specialkey::*;
+    assert_eq!(inputs("\x1b[A"), vec![Input::Special(Up, 0)]);
+    assert_eq!(inputs("\x1b[1;2A"), vec![Input::Special(Up, SHIFT)]);
+    assert_eq!(inputs("\x1b[1;3D"), vec![Input::Special(Left, ALT)]);
+    assert_eq!(inputs("\x1b[1;5C"), vec![Input::Special(Right, CTRL)]);
+    assert_eq!(inputs("\x1b[1;8H"), vec![Input::Special(Home, SHIFT | ALT | CTRL)]);
+    assert_eq!(inputs("\x1b[F"), vec![Input::Special(End, 0)]);
+    assert_eq!(inputs("\x1b[Z"), vec![Input::EscZ]);
+    assert_eq!(inputs("\x1b[1;5X"), vec![Input::UnknownEscSeq]);
+    // A sequence cut short is not mistaken for the keys after it.
+    assert_eq!(inputs("\x1b[1;"), vec![Input::UnknownEscSeq]);
+}
 
-    Ok(r)
+#[test]
+fn pull_csi_tilde() {
+    use Specialkey::*;
+    assert_eq!(inputs("\x1b[1~"), vec![Input::Special(Home, 0)]);
+    assert_eq!(inputs("\x1b[2~"), vec![Input::Special(Insert, 0)]);
+    assert_eq!(inputs("\x1b[3~"), vec![Input::Special(Delete, 0)]);
+    assert_eq!(inputs("\x1b[3;5~"), vec![Input::Special(Delete, CTRL)]);
+    assert_eq!(inputs("\x1b[4~"), vec![Input::Special(End, 0)]);
+    assert_eq!(inputs("\x1b[5~"), vec![Input::Special(PageUp, 0)]);
+    assert_eq!(inputs("\x1b[6;2~"), vec![Input::Special(PageDown, SHIFT)]);
+    assert_eq!(inputs("\x1b[11~"), vec![Input::Special(F(1), 0)]);
+    assert_eq!(inputs("\x1b[15~"), vec![Input::Special(F(5), 0)]);
+    assert_eq!(inputs("\x1b[17~"), vec![Input::Special(F(6), 0)]);
+    assert_eq!(inputs("\x1b[24;3~"), vec![Input::Special(F(12), ALT)]);
+    assert_eq!(inputs("\x1b[16~"), vec![Input::UnknownEscSeq]);
+    assert_eq!(inputs("\x1b[99~"), vec![Input::UnknownEscSeq]);
 }
 
+#[test]
+fn pull_ss3() {
+    use Specialkey::*;
+    assert_eq!(inputs("\x1bOA"), vec![Input::Special(Up, 0)]);
+    assert_eq!(inputs("\x1bOP"), vec![Input::Special(F(1), 0)]);
+    assert_eq!(inputs("\x1bOS"), vec![Input::Special(F(4), 0)]);
+    assert_eq!(inputs("\x1b[[A"), vec![Input::Special(F(1), 0)]);
+    assert_eq!(inputs("\x1b[[E"), vec![Input::Special(F(5), 0)]);
+}
+
+#[test]
+fn pull_escape_key() {
+    assert_eq!(inputs("\x1b"), vec![Input::Key(ESC)]);
+    assert_eq!(inputs("\x1bx"), vec![Input::Key(ESC), Input::Key('x')]);
+    assert_eq!(inputs("\x1b\x1b[A"), vec![Input::Key(ESC), Input::Special(Specialkey::Up, 0)]);
+}
+
+#[test]
+fn pull_escape_timeout() {
+    // Keys typed after an escape key, once the sequence timeout is over, are not a sequence.
+    let (sender, receiver) = mpsc::channel();
+    let mut reader = Inputreader::mk_inputreader(receiver);
+    sender.send(ESC).unwrap();
+    assert_eq!(reader.pull().unwrap(), Input::Key(ESC));
+    sender.send('[').unwrap();
+    sender.send('A').unwrap();
+    assert_eq!(reader.pull().unwrap(), Input::Key('['));
+    assert_eq!(reader.pull().unwrap(), Input::Key('A'));
+}
+
+#[test]
+fn pull_resize_in_sequence() {
+    let text : String = vec!['\x1b', '[', '1', ';', RESIZE, '5', 'C'].into_iter().collect();
+    assert_eq!(inputs(&text), vec![Input::Special(Specialkey::Right, CTRL), Input::Resize]);
+}
+
+} // mod tests
+
 } // mod term
 
 
@@ -1007,6 +2223,7 @@ use conf::CONF;
 use text::Buffer;
 use util::*;
 use core::*;
+use term::capabilities;
 
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -1024,11 +2241,46 @@ pub struct Framebuffer {
     bg:         Vec<i32>,
     cursor:     Pos,            // Absolute screen coordinate relative to (0,0).
     buffer:     Vec<u8>,        // used for storing frame data before writing to the terminal
+    colordepth: Colordepth,
 }
 
 const frame_default_text : u8 = ' ' as u8;
-const frame_default_fg : i32 = 0; // Black
-const frame_default_bg : i32 = 7; // White
+
+// Append the SGR parameters selecting a foreground or background color code, with as many colors
+// as the terminal supports.
+fn append_color(dst: &mut Vec<u8>, depth: Colordepth, code: i32, is_fg: bool) {
+    let mut numbuf = [0 as u8; 8];
+    let mut number = |dst: &mut Vec<u8>, n: i32| {
+        let len = itoa10_left(&mut numbuf, n);
+        dst.extend_from_slice(&numbuf[..len]);
+    };
+    match depth {
+        Colordepth::Truecolor if code & COLORCODE_RGB != 0 => {
+            let (r, g, b) = colorcode_rgb(code);
+            dst.extend_from_slice(if is_fg { b"38;2;" } else { b"48;2;" });
+            number(dst, r as i32);
+            dst.push(b';');
+            number(dst, g as i32);
+            dst.push(b';');
+            number(dst, b as i32);
+        }
+        Colordepth::Truecolor | Colordepth::Ansi256 => {
+            dst.extend_from_slice(if is_fg { b"38;5;" } else { b"48;5;" });
+            number(dst, colorcode_256(code));
+        }
+        Colordepth::Monochrome => (),
+        Colordepth::Ansi16 => {
+            let c = colorcode_16(code);
+            let base = match (is_fg, c < 8) {
+                (true, true)    => 30,
+                (true, false)   => 90 - 8,
+                (false, true)   => 40,
+                (false, false)  => 100 - 8,
+            };
+            number(dst, base + c);
+        }
+    }
+}
 
 impl Framebuffer {
     pub fn mk_framebuffer(window: Pos) -> Framebuffer {
@@ -1037,17 +2289,18 @@ impl Framebuffer {
         Framebuffer {
             window,
             text:       vec![frame_default_text; len],
-            fg:         vec![frame_default_fg; len],
-            bg:         vec![frame_default_bg; len],
+            fg:         vec![colorcode(CONF.color_default.fg); len],
+            bg:         vec![colorcode(CONF.color_default.bg); len],
             cursor:     pos(0,0),
             buffer:     vec![0; 64 * 1024],
+            colordepth: capabilities().colordepth,
         }
     }
 
     pub fn clear(&mut self) {
         fill(&mut self.text, frame_default_text);
-        fill(&mut self.fg,   frame_default_fg);
-        fill(&mut self.bg,   frame_default_bg);
+        fill(&mut self.fg,   colorcode(CONF.color_default.fg));
+        fill(&mut self.bg,   colorcode(CONF.color_default.bg));
     }
 
     pub fn put_line(&mut self, pos: Pos, src: &[u8]) {
@@ -1087,7 +2340,7 @@ impl Framebuffer {
         }
     }
 
-    fn set_cursor(&mut self, new_cursor: Pos) {
+    pub fn set_cursor(&mut self, new_cursor: Pos) {
         let mut x = new_cursor.x;
         let mut y = new_cursor.y;
         x = max(x, 0);
@@ -1131,23 +2384,15 @@ impl Framebuffer {
             }
 
 
-            if CONF.draw_colors {
+            if CONF.draw_colors && self.colordepth != Colordepth::Monochrome {
                 let mut j = l;
                 loop {
                     let k = self.find_color_end(j, r);
 
-                    { // fg color
-                        append(&mut buffer, b"\x1b[38;5;");
-                        let n = itoa10_left(&mut numbuf1, self.fg[j]);
-                        append(&mut buffer, &numbuf1[..n]);
-                    }
-
-                    { // bg color
-                        append(&mut buffer, b";48;5;");
-                        let n = itoa10_left(&mut numbuf2, self.bg[j]);
-                        append(&mut buffer, &numbuf2[..n]);
-                    }
-
+                    append(&mut buffer, b"\x1b[");
+                    append_color(&mut buffer, self.colordepth, self.fg[j], true);
+                    append(&mut buffer, b";");
+                    append_color(&mut buffer, self.colordepth, self.bg[j], false);
                     append(&mut buffer, b"m");
                     append(&mut buffer, &self.text[j..k]);
                     if k == r {
@@ -1235,8 +2480,8 @@ pub struct Screen {
 impl Screen {
     pub fn mk_screen(window: Rec) -> Screen {
         let lineno_len = 5;
-        let (header, filearea) = window.vsplit(1);
-        let (linenoarea, textarea) = filearea.hsplit(lineno_len);
+        let (header, filearea) = window.vsplit(window.y0() + 1);
+        let (linenoarea, textarea) = filearea.hsplit(filearea.x0() + lineno_len);
 
         Screen {
             window,
@@ -1257,11 +2502,10 @@ impl Screen {
         // header
         {
             framebuffer.put_line(self.header.min, drawinfo.header.as_bytes());
-            framebuffer.put_color(self.header, CONF.color_header_active);
+            let header_color = if drawinfo.is_active { CONF.color_header_active } else { CONF.color_header_inactive };
+            framebuffer.put_color(self.header, header_color);
         }
 
-        // FUTURE: this will need to be skipped for an inactive view sharing a buffer if the active
-        // view has pushed a change show that this inactive view should update its cursor.
         if drawinfo.draw == Draw::Header {
             return
         }
@@ -1300,10 +2544,122 @@ impl Screen {
             framebuffer.put_color(self.textarea.row(cursor_screen_position.y), CONF.color_cursor_lines);
             framebuffer.put_color(self.textarea.column(cursor_screen_position.x), CONF.color_cursor_lines);
         }
+
+        // selection
+        if let Some((start, stop, linewise)) = drawinfo.selection {
+            let first = max(start.y, file_base_offset.y);
+            let last = min(stop.y, file_base_offset.y + self.textarea.h() - 1);
+            for y in first..last + 1 {
+                let row = self.textarea.row(y - file_base_offset.y + frame_base_offset.y);
+                let x0 = if linewise || y > start.y { 0 } else { start.x - file_base_offset.x };
+                let x1 = if linewise || y < stop.y { row.w() } else { stop.x + 1 - file_base_offset.x };
+                let x0 = max(0, x0);
+                let x1 = min(row.w(), x1);
+                if x0 < x1 {
+                    framebuffer.put_color(rec(row.x0() + x0, row.y0(), row.x0() + x1, row.y1()), CONF.color_selection);
+                }
+            }
+        }
+    }
+
+    pub fn textarea_size(&self) -> Pos {
+        self.textarea.size()
+    }
+
+    pub fn contains(&self, p: Pos) -> bool {
+        let w = self.window;
+        w.x0() <= p.x && p.x < w.x1() && w.y0() <= p.y && p.y < w.y1()
+    }
+
+    pub fn header_contains(&self, p: Pos) -> bool {
+        self.header.y0() <= p.y && p.y < self.header.y1()
+    }
+
+    // Offset of p from the origin of the text area, clamped inside it.
+    pub fn text_offset(&self, p: Pos) -> Pos {
+        let t = self.textarea;
+        pos(max(t.x0(), min(t.x1() - 1, p.x)), max(t.y0(), min(t.y1() - 1, p.y))) - t.min
+    }
+
+    pub fn area(&self) -> Rec {
+        self.window
+    }
+}
+
+
+// A box of text entries drawn on top of a Screen area, with one selected entry.
+pub struct Popup {
+    window:         Rec,
+    header:         Rec,
+    entryarea:      Rec,
+}
+
+impl Popup {
+    // Center a popup of at most 'size' inside 'area'.
+    pub fn mk_popup(area: Rec, size: Pos) -> Popup {
+        let w = min(size.x, area.w() - 4);
+        let h = min(size.y, area.h() - 2);
+        let offset = pos((area.w() - w) / 2, (area.h() - h) / 2);
+        let window = (area.min + offset).extrude(pos(w, h));
+        let (header, entryarea) = window.vsplit(window.y0() + 1);
+
+        Popup {
+            window,
+            header,
+            entryarea,
+        }
+    }
+
+    // A popup filling all of 'window', for side panels.
+    pub fn mk_panel(window: Rec) -> Popup {
+        let (header, entryarea) = window.vsplit(window.y0() + 1);
+
+        Popup {
+            window,
+            header,
+            entryarea,
+        }
+    }
+
+    pub fn put_entries(&self, framebuffer: &mut Framebuffer, popupinfo: &Popupinfo) {
+        let w = usize(self.window.w());
+        let mut linebuf = vec![' ' as u8; w];
+
+        fill(&mut linebuf, ' ' as u8);
+        copy(&mut linebuf, popupinfo.title.as_bytes());
+        framebuffer.put_line(self.header.min, &linebuf);
+        let header_color = if popupinfo.is_active { CONF.color_header_active } else { CONF.color_header_inactive };
+        framebuffer.put_color(self.header, header_color);
+
+        // Scroll entries so that the selected entry is always visible.
+        let h = usize(self.entryarea.h());
+        let first = if popupinfo.selected < h { 0 } else { popupinfo.selected + 1 - h };
+        for i in 0..h {
+            fill(&mut linebuf, ' ' as u8);
+            if let Some(entry) = popupinfo.entries.get(first + i) {
+                copy(&mut linebuf[1..], entry.as_bytes());
+            }
+            framebuffer.put_line(self.entryarea.min + pos(0, i32(i)), &linebuf);
+        }
+        framebuffer.put_color(self.entryarea, CONF.color_popup);
+
+        if popupinfo.selected < popupinfo.entries.len() {
+            let row = self.entryarea.y0() + i32(popupinfo.selected - first);
+            framebuffer.put_color(self.entryarea.row(row), CONF.color_popup_selected);
+        }
     }
 }
 
 
+// Helper data object for Popup::put_entries
+pub struct Popupinfo<'a> {
+    pub title:              &'a str,
+    pub entries:            &'a [&'a str],
+    pub selected:           usize,
+    pub is_active:          bool,
+}
+
+
 // Helper data object for Screen::draw
 pub struct Drawinfo<'a> {
     pub header:             &'a str,
@@ -1313,6 +2669,7 @@ pub struct Drawinfo<'a> {
     pub draw:               Draw,
     pub relative_lineno:    bool,
     pub is_active:          bool,
+    pub selection:          Option<(Pos, Pos, bool)>,   // first and last selected positions, whole lines if set
 }
 
 
@@ -1326,6 +2683,7 @@ mod text {
 
 
 use std;
+use std::cmp::max;
 use std::cmp::min;
 use std::fs;
 use std::io::Write;
@@ -1334,6 +2692,7 @@ use std::mem::swap;
 use core::*;
 use util::*;
 use ioutil;
+use tags;
 
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -1343,6 +2702,30 @@ pub enum InsertMode {
 }
 
 
+// What a text object selects around a position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Textobject {
+    Word,
+    Quote(u8),
+    Block(u8, u8),  // open and close brackets
+    Paragraph,
+    Indent,         // lines indented at least as much as the line at the position
+}
+
+// Open bracket, close bracket, and whether the match of 'c' is after it.
+fn bracket_pair(c: u8) -> Option<(u8, u8, bool)> {
+    match c {
+        b'(' => Some((b'(', b')', true)),
+        b'[' => Some((b'[', b']', true)),
+        b'{' => Some((b'{', b'}', true)),
+        b')' => Some((b')', b'(', false)),
+        b']' => Some((b']', b'[', false)),
+        b'}' => Some((b'}', b'{', false)),
+        _    => None,
+    }
+}
+
+
 #[cfg(windows)]
 const LINE_ENDING: &'static [u8] = b"\r\n";
 #[cfg(not(windows))]
@@ -1407,6 +2790,8 @@ pub struct Buffer {
     opbuffer:               OpBuffer,
     snapshot_buffer:        SnapshotBuffer,
 
+    marks:                  Vec<(char, Pos)>,   // local marks, moving with the text
+
     // TODO: should this track the current insert / command mode ?
 }
 
@@ -1482,7 +2867,8 @@ impl Buffer {
                 ops:                Vec::new(),
                 cursor:             0,
                 pending:            0,
-            }
+            },
+            marks:              Vec::new(),
         }
     }
 
@@ -1500,6 +2886,8 @@ impl Buffer {
         Ok(())
     }
 
+    // Stage a snapshot of the buffer before a change, unless one is already staged: all changes
+    // until snapshot_finish() are undone together.
     pub fn snapshot_take(&mut self, cursor: Pos) {
         let snapshot = Snapshot::take_snapshot(cursor, &self);
         self.snapshot_buffer.push(snapshot);
@@ -1509,33 +2897,577 @@ impl Buffer {
         self.snapshot_buffer.push(s);
     }
 
-    pub fn char_at(&self, lineno: usize, colno: usize) -> char {
-        // UTF8: need to iterate from line start
-        self.line_get(lineno).char_at(colno)
-    }
-
-    pub fn nlines(&self) -> i32 {
-        i32(self.lines.len())
+    // Close the undo step of the staged snapshot, if any.
+    pub fn snapshot_finish(&mut self) {
+        self.snapshot_buffer.finish_command(self.opbuffer.cursor);
     }
 
-    pub fn line_last(&self) -> usize {
-        self.lines.len() - 1
+    pub fn mark_set(&mut self, name: char, p: Pos) {
+        self.marks.retain(|&(c, _)| c != name);
+        self.marks.push((name, p));
     }
 
-    pub fn line_len(&self, lineno: usize) -> usize {
-        // UTF8: count number of chars
-        self.lines[lineno].len()
+    pub fn mark_get(&self, name: char) -> Option<Pos> {
+        self.marks.iter().find(|&&(c, _)| c == name).map(|&(_, p)| p)
     }
 
-    fn line_get(&self, lineno: usize) -> Line {
-        Line {
-            range: self.lines[lineno],
-            text: &self.text,
+    // Move marks with the lines they point to, and forget marks on deleted lines.
+    fn marks_adjust(&mut self, shifts: &[Lineshift]) {
+        if shifts.is_empty() {
+            return
         }
+        let marks = self.marks.drain(..).filter_map(|(c, p)| {
+            Lineshift::adjust_all(shifts, p).map(|p| (c, p))
+        }).collect();
+        self.marks = marks;
     }
 
-    fn line_set(&mut self, lineno: usize, range: Range) {
-        self.lines[lineno] = range;
+    // The identifier under p, if any.
+    pub fn word_at(&self, p: Pos) -> Option<String> {
+        let (colno, lineno) = p.usize();
+        let len = self.line_len(lineno);
+        let is_word = tags::is_identifier_char;
+        if colno >= len || !is_word(self.char_at(lineno, colno)) {
+            return None
+        }
+
+        let mut start = colno;
+        while start > 0 && is_word(self.char_at(lineno, start - 1)) {
+            start -= 1;
+        }
+        let mut stop = colno;
+        while stop < len && is_word(self.char_at(lineno, stop)) {
+            stop += 1;
+        }
+
+        Some((start..stop).map(|i| self.char_at(lineno, i)).collect())
+    }
+
+    // Line number of the first line equal to 'text', or of the first line containing 'text'.
+    pub fn line_find(&self, text: &str) -> Option<i32> {
+        let text = text.as_bytes();
+        let exact = self.iter_all().position(|line| line == text);
+        let partial = || self.iter_all().position(|line| line.windows(text.len()).any(|w| w == text));
+        exact.or_else(partial).map(i32)
+    }
+
+    pub fn char_at(&self, lineno: usize, colno: usize) -> char {
+        // UTF8: need to iterate from line start
+        self.line_get(lineno).char_at(colno)
+    }
+
+    pub fn line_text(&self, lineno: usize) -> &[u8] {
+        self.line_get(lineno).to_slice()
+    }
+
+    // Motions. Positions at the end of a line stand for the line break and count as a space.
+
+    pub fn pos_next(&self, p: Pos) -> Option<Pos> {
+        if usize(p.x) < self.line_len(usize(p.y)) {
+            return Some(p + pos(1,0))
+        }
+        if p.y + 1 < self.nlines() {
+            return Some(pos(0, p.y + 1))
+        }
+        None
+    }
+
+    pub fn pos_prev(&self, p: Pos) -> Option<Pos> {
+        if p.x > 0 {
+            return Some(p - pos(1,0))
+        }
+        if p.y > 0 {
+            return Some(pos(i32(self.line_len(usize(p.y - 1))), p.y - 1))
+        }
+        None
+    }
+
+    // 0 for spaces, 1 for identifier characters, 2 for punctuation and others.
+    pub fn class_at(&self, p: Pos) -> u8 {
+        let (colno, lineno) = p.usize();
+        if colno >= self.line_len(lineno) {
+            return 0
+        }
+        match self.char_at(lineno, colno) {
+            c if c.is_ascii_whitespace()        => 0,
+            c if tags::is_identifier_char(c)    => 1,
+            _                                   => 2,
+        }
+    }
+
+    // Start of the next word, or the end of the buffer.
+    pub fn word_next(&self, p: Pos) -> Pos {
+        let class = self.class_at(p);
+        let mut q = p;
+        while class != 0 && self.class_at(q) == class {
+            match self.pos_next(q) {
+                Some(n) => q = n,
+                None    => return q,
+            }
+        }
+        while self.class_at(q) == 0 {
+            match self.pos_next(q) {
+                Some(n) => q = n,
+                None    => return q,
+            }
+        }
+        q
+    }
+
+    // Start of the word before p, or of the current word if p is inside it.
+    pub fn word_prev(&self, p: Pos) -> Pos {
+        let mut q = match self.pos_prev(p) {
+            Some(q) => q,
+            None    => return p,
+        };
+        while self.class_at(q) == 0 {
+            match self.pos_prev(q) {
+                Some(n) => q = n,
+                None    => return q,
+            }
+        }
+        let class = self.class_at(q);
+        while let Some(n) = self.pos_prev(q) {
+            if self.class_at(n) != class {
+                break
+            }
+            q = n;
+        }
+        q
+    }
+
+    // Last character of the word after p, or of the current word if p is inside it.
+    pub fn word_end(&self, p: Pos) -> Pos {
+        let mut q = match self.pos_next(p) {
+            Some(q) => q,
+            None    => return p,
+        };
+        while self.class_at(q) == 0 {
+            match self.pos_next(q) {
+                Some(n) => q = n,
+                None    => return q,
+            }
+        }
+        let class = self.class_at(q);
+        while let Some(n) = self.pos_next(q) {
+            if self.class_at(n) != class {
+                break
+            }
+            q = n;
+        }
+        q
+    }
+
+    pub fn line_is_blank(&self, lineno: usize) -> bool {
+        self.line_text(lineno).iter().all(|c| c.is_ascii_whitespace())
+    }
+
+    // The first blank line after the paragraph at or after line p.y, or the end of the buffer.
+    pub fn paragraph_next(&self, p: Pos) -> Pos {
+        let n = self.lines.len();
+        let mut y = usize(p.y);
+        while y < n && self.line_is_blank(y) {
+            y += 1;
+        }
+        while y < n && !self.line_is_blank(y) {
+            y += 1;
+        }
+        if y == n {
+            return pos(i32(self.line_len(n - 1)), i32(n - 1))
+        }
+        pos(0, i32(y))
+    }
+
+    // The first blank line before the paragraph at or before line p.y, or the start of the buffer.
+    pub fn paragraph_prev(&self, p: Pos) -> Pos {
+        let mut y = usize(p.y);
+        while y > 0 && self.line_is_blank(y) {
+            y -= 1;
+        }
+        while y > 0 && !self.line_is_blank(y) {
+            y -= 1;
+        }
+        pos(0, i32(y))
+    }
+
+    // The bracket matching the first bracket at or after p on its line.
+    pub fn bracket_match(&self, p: Pos) -> Option<Pos> {
+        let (colno, lineno) = p.usize();
+        let line = self.line_text(lineno);
+        let start = (colno..line.len()).find(|&i| bracket_pair(line[i]).is_some())?;
+        let (open, close, forward) = bracket_pair(line[start])?;
+        let mut depth = 0;
+        let mut q = pos(i32(start), p.y);
+        loop {
+            let (x, y) = q.usize();
+            if x < self.line_len(y) {
+                let c = self.line_text(y)[x];
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Some(q)
+                    }
+                }
+            }
+            q = if forward { self.pos_next(q)? } else { self.pos_prev(q)? };
+        }
+    }
+
+    // The next occurrence of 'pattern' after p, or before p if going backward, wrapping around the
+    // ends of the buffer.
+    pub fn search(&self, pattern: &[u8], p: Pos, forward: bool) -> Option<Pos> {
+        if pattern.is_empty() {
+            return None
+        }
+        let n = self.lines.len();
+        let (colno, lineno) = p.usize();
+        let find = |y: usize, from: usize, to: usize| -> Option<Pos> {
+            let line = self.line_text(y);
+            let to = min(to, line.len());
+            let matches = |&x: &usize| line[x..].starts_with(pattern);
+            let x = if forward { (from..to).find(matches) } else { (from..to).rev().find(matches) };
+            x.map(|x| pos(i32(x), i32(y)))
+        };
+        if forward {
+            find(lineno, colno + 1, usize::MAX)
+                .or_else(|| (1..n).filter_map(|i| find((lineno + i) % n, 0, usize::MAX)).next())
+                .or_else(|| find(lineno, 0, colno + 1))
+        } else {
+            find(lineno, 0, colno)
+                .or_else(|| (1..n).filter_map(|i| find((lineno + n - i) % n, 0, usize::MAX)).next())
+                .or_else(|| find(lineno, colno, usize::MAX))
+        }
+    }
+
+    // Text objects. The inner object is only the text itself, the whole object also includes
+    // surrounding spaces, quotes, brackets or lines. Objects are returned as a range, or as the
+    // first and last lines for objects made of whole lines.
+
+    pub fn object_range(&self, p: Pos, object: Textobject, inner: bool, count: usize) -> Option<(Pos, Pos, bool)> {
+        match object {
+            Textobject::Word => {
+                let (start, stop) = self.word_object(p, inner, count);
+                Some((start, stop, false))
+            }
+            Textobject::Quote(q) => {
+                let (start, stop) = self.quote_object(p, q, inner)?;
+                Some((start, stop, false))
+            }
+            Textobject::Block(open, close) => {
+                self.block_object(p, open, close, inner, count)
+            }
+            Textobject::Paragraph => {
+                let (first, last) = self.paragraph_object(usize(p.y), inner, count);
+                Some((pos(0, i32(first)), pos(0, i32(last)), true))
+            }
+            Textobject::Indent => {
+                let (first, last) = self.indent_object(usize(p.y), inner)?;
+                Some((pos(0, i32(first)), pos(0, i32(last)), true))
+            }
+        }
+    }
+
+    // 'count' runs of characters of the same class on the line of p, starting with the run at p.
+    // The whole word also takes the spaces after it, or before it at the end of the line.
+    fn word_object(&self, p: Pos, inner: bool, count: usize) -> (Pos, Pos) {
+        let line = self.line_text(usize(p.y));
+        let class = |x: usize| self.class_at(pos(i32(x), p.y));
+        let run_end = |x: usize| (x..line.len()).find(|&i| class(i) != class(x)).unwrap_or(line.len());
+        let x = min(usize(p.x), line.len());
+        let mut start = x;
+        while start > 0 && start < line.len() && class(start - 1) == class(x) {
+            start -= 1;
+        }
+        let mut stop = x;
+        let mut spaces = false;
+        for _ in 0..count {
+            if stop >= line.len() {
+                break
+            }
+            spaces |= class(stop) == 0;
+            stop = run_end(stop);
+            // Around objects take a word and the spaces next to it as one.
+            if !inner && stop < line.len() && (class(stop) == 0) != (class(stop - 1) == 0) {
+                spaces |= class(stop) == 0;
+                stop = run_end(stop);
+            }
+        }
+        if !inner && !spaces {
+            while start > 0 && class(start - 1) == 0 {
+                start -= 1;
+            }
+        }
+        (pos(i32(start), p.y), pos(i32(stop), p.y))
+    }
+
+    // The quoted string on the line of p containing p, or the first one after p. Quotes are
+    // paired from the start of the line, ignoring quotes escaped by a backslash. The whole string
+    // also takes the spaces after it, or before it.
+    fn quote_object(&self, p: Pos, q: u8, inner: bool) -> Option<(Pos, Pos)> {
+        let line = self.line_text(usize(p.y));
+        let quotes : Vec<usize> = (0..line.len()).filter(|&i| line[i] == q && (i == 0 || line[i - 1] != b'\\')).collect();
+        let x = usize(p.x);
+        let (open, close) = quotes.chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| (pair[0], pair[1]))
+            .find(|&(_, close)| x <= close)?;
+        if inner {
+            return Some((pos(i32(open + 1), p.y), pos(i32(close), p.y)))
+        }
+        let mut start = open;
+        let mut stop = close + 1;
+        while stop < line.len() && line[stop].is_ascii_whitespace() {
+            stop += 1;
+        }
+        if stop == close + 1 {
+            while start > 0 && line[start - 1].is_ascii_whitespace() {
+                start -= 1;
+            }
+        }
+        Some((pos(i32(start), p.y), pos(i32(stop), p.y)))
+    }
+
+    // The 'count'th block of brackets enclosing p, or starting at p. The inner text of a block
+    // spanning several lines is made of its inner lines when the brackets end and start lines.
+    fn block_object(&self, p: Pos, open: u8, close: u8, inner: bool, count: usize) -> Option<(Pos, Pos, bool)> {
+        let char_at = |q: Pos| {
+            let (x, y) = q.usize();
+            if x < self.line_len(y) { self.line_text(y)[x] } else { b'\n' }
+        };
+        let mut start = p;
+        if char_at(start) != open {
+            start = self.pos_prev(start)?;
+        }
+        for i in 0..count {
+            if i > 0 {
+                start = self.pos_prev(start)?;
+            }
+            let mut depth = 0;
+            loop {
+                let c = char_at(start);
+                if c == open && depth == 0 {
+                    break
+                }
+                if c == close {
+                    depth += 1;
+                } else if c == open {
+                    depth -= 1;
+                }
+                start = self.pos_prev(start)?;
+            }
+        }
+        let stop = self.bracket_match(start)?;
+        if !inner {
+            return Some((start, stop + pos(1,0), false))
+        }
+        let first = start + pos(1,0);
+        let open_ends_line = usize(first.x) == self.line_len(usize(first.y));
+        let close_starts_line = self.line_text(usize(stop.y))[..usize(stop.x)].iter().all(|c| c.is_ascii_whitespace());
+        if stop.y > start.y + 1 && open_ends_line && close_starts_line {
+            return Some((pos(0, start.y + 1), pos(0, stop.y - 1), true))
+        }
+        Some((first, stop, false))
+    }
+
+    // 'count' runs of blank or non blank lines, starting with the run around 'lineno'. The whole
+    // paragraph also takes the blank lines after it, or before it at the end of the buffer.
+    fn paragraph_object(&self, lineno: usize, inner: bool, count: usize) -> (usize, usize) {
+        let n = self.lines.len();
+        let blank = self.line_is_blank(lineno);
+        let run_end = |y: usize| {
+            let b = self.line_is_blank(y);
+            (y..n).find(|&i| self.line_is_blank(i) != b).unwrap_or(n)
+        };
+        let mut first = lineno;
+        while first > 0 && self.line_is_blank(first - 1) == blank {
+            first -= 1;
+        }
+        let mut stop = lineno;
+        let mut blanks = false;
+        for _ in 0..count {
+            if stop >= n {
+                break
+            }
+            blanks |= self.line_is_blank(stop);
+            stop = run_end(stop);
+            if !inner && stop < n && self.line_is_blank(stop) != self.line_is_blank(stop - 1) {
+                blanks |= self.line_is_blank(stop);
+                stop = run_end(stop);
+            }
+        }
+        if !inner && !blanks {
+            while first > 0 && self.line_is_blank(first - 1) {
+                first -= 1;
+            }
+        }
+        (first, stop - 1)
+    }
+
+    fn indent_of(&self, lineno: usize) -> usize {
+        self.line_text(lineno).iter().take_while(|c| c.is_ascii_whitespace()).count()
+    }
+
+    // The lines around 'lineno' indented at least as much as the first non blank line at or after
+    // it, without blank lines at the ends. The whole block also takes the line before it.
+    fn indent_object(&self, lineno: usize, inner: bool) -> Option<(usize, usize)> {
+        let n = self.lines.len();
+        let y = (lineno..n).find(|&y| !self.line_is_blank(y))?;
+        let indent = self.indent_of(y);
+        let inside = |y: usize| self.line_is_blank(y) || self.indent_of(y) >= indent;
+        let mut first = y;
+        while first > 0 && inside(first - 1) {
+            first -= 1;
+        }
+        let mut last = y;
+        while last + 1 < n && inside(last + 1) {
+            last += 1;
+        }
+        while self.line_is_blank(first) {
+            first += 1;
+        }
+        while self.line_is_blank(last) {
+            last -= 1;
+        }
+        if !inner && first > 0 {
+            first -= 1;
+        }
+        Some((first, last))
+    }
+
+    // Range operations. Ranges go from 'start' to 'stop' excluded, across lines.
+
+    pub fn range_text(&self, start: Pos, stop: Pos) -> Vec<u8> {
+        let mut text = Vec::new();
+        for y in usize(start.y)..usize(stop.y) + 1 {
+            let line = self.line_text(y);
+            let from = if y == usize(start.y) { min(usize(start.x), line.len()) } else { 0 };
+            let to = if y == usize(stop.y) { min(usize(stop.x), line.len()) } else { line.len() };
+            text.extend_from_slice(&line[from..max(from, to)]);
+            if y < usize(stop.y) {
+                text.push(b'\n');
+            }
+        }
+        text
+    }
+
+    // The text of lines 'first' to 'last' included, each followed by a line break.
+    pub fn lines_text(&self, first: usize, last: usize) -> Vec<u8> {
+        let mut text = Vec::new();
+        for y in first..last + 1 {
+            text.extend_from_slice(self.line_text(y));
+            text.push(b'\n');
+        }
+        text
+    }
+
+    fn text_append(&mut self, bytes: &[u8]) -> Range {
+        let start = self.text.len();
+        self.text.extend_from_slice(bytes);
+        range(start, self.text.len())
+    }
+
+    pub fn range_delete(&mut self, start: Pos, stop: Pos) -> Opresult {
+        let (x0, y0) = start.usize();
+        let (x1, y1) = stop.usize();
+        let mut line = self.line_text(y0)[..min(x0, self.line_len(y0))].to_vec();
+        line.extend_from_slice(&self.line_text(y1)[min(x1, self.line_len(y1))..]);
+        let line = self.text_append(&line);
+        self.push_op(Op { lineno: y0, line, op_type: Optype::Rep });
+        for _ in y0..y1 {
+            self.push_op(Op { lineno: y0 + 1, line: range(0, 0), op_type: Optype::Del });
+        }
+        Opresult::Change(start)
+    }
+
+    // Delete lines 'first' to 'last' included. Deleting all lines leaves an empty line.
+    pub fn lines_delete(&mut self, first: usize, last: usize) -> Opresult {
+        let mut lineno = first;
+        if last + 1 - first == self.lines.len() {
+            let line = self.line_empty();
+            self.push_op(Op { lineno, line, op_type: Optype::Rep });
+            lineno += 1;
+        }
+        for _ in lineno..last + 1 {
+            self.push_op(Op { lineno, line: range(0, 0), op_type: Optype::Del });
+        }
+        let remaining = self.lines.len() - (last + 1 - lineno);
+        Opresult::Change(pos(0, i32(min(first, remaining - 1))))
+    }
+
+    // Replace lines 'first' to 'last' included with their transformation by 'f'.
+    pub fn lines_map(&mut self, first: usize, last: usize, f: &dyn Fn(&[u8]) -> Vec<u8>) -> Opresult {
+        for y in first..last + 1 {
+            let text = f(self.line_text(y));
+            let line = self.text_append(&text);
+            self.push_op(Op { lineno: y, line, op_type: Optype::Rep });
+        }
+        Opresult::Change(pos(0, i32(first)))
+    }
+
+    // Replace every character of a range with its transformation by 'f'.
+    pub fn range_map(&mut self, start: Pos, stop: Pos, f: fn(u8) -> u8) -> Opresult {
+        for y in usize(start.y)..usize(stop.y) + 1 {
+            let mut text = self.line_text(y).to_vec();
+            let from = if y == usize(start.y) { min(usize(start.x), text.len()) } else { 0 };
+            let to = if y == usize(stop.y) { min(usize(stop.x), text.len()) } else { text.len() };
+            for c in text[from..max(from, to)].iter_mut() {
+                *c = f(*c);
+            }
+            let line = self.text_append(&text);
+            self.push_op(Op { lineno: y, line, op_type: Optype::Rep });
+        }
+        Opresult::Change(start)
+    }
+
+    // Insert text containing line breaks at p. The cursor goes to the end of the inserted text.
+    pub fn text_insert(&mut self, p: Pos, text: &[u8]) -> Opresult {
+        let (colno, lineno) = p.usize();
+        let current = self.line_text(lineno).to_vec();
+        let colno = min(colno, current.len());
+        let chunks : Vec<&[u8]> = text.split(|&c| c == b'\n').collect();
+        let last = chunks.len() - 1;
+        let mut cursor = p;
+        for (i, chunk) in chunks.iter().enumerate() {
+            let mut line = Vec::new();
+            if i == 0 {
+                line.extend_from_slice(&current[..colno]);
+            }
+            line.extend_from_slice(chunk);
+            cursor = pos(i32(line.len()), i32(lineno + i));
+            if i == last {
+                line.extend_from_slice(&current[colno..]);
+            }
+            let line = self.text_append(&line);
+            let op_type = if i == 0 { Optype::Rep } else { Optype::Ins };
+            self.push_op(Op { lineno: lineno + i, line, op_type });
+        }
+        Opresult::Change(cursor)
+    }
+
+    pub fn nlines(&self) -> i32 {
+        i32(self.lines.len())
+    }
+
+    pub fn line_last(&self) -> usize {
+        self.lines.len() - 1
+    }
+
+    pub fn line_len(&self, lineno: usize) -> usize {
+        // UTF8: count number of chars
+        self.lines[lineno].len()
+    }
+
+    fn line_get(&self, lineno: usize) -> Line {
+        Line {
+            range: self.lines[lineno],
+            text: &self.text,
+        }
+    }
+
+    fn line_set(&mut self, lineno: usize, range: Range) {
+        self.lines[lineno] = range;
     }
 
     fn line_get_slice<'a>(&'a self, offset: Pos) -> &'a[u8] {
@@ -1553,6 +3485,13 @@ impl Buffer {
         let lineno = usize(p.y);
         check!(lineno < self.lines.len());
 
+        // Never delete the last remaining line, empty it instead.
+        if self.nlines() == 1 {
+            let line = self.line_empty();
+            self.push_op(Op { lineno, line, op_type: Optype::Rep });
+            return Opresult::Change(pos(0,0))
+        }
+
         self.push_op(Op {
             lineno,
             line:           Range { start: 0, stop: 0 },
@@ -1635,10 +3574,23 @@ impl Buffer {
         self.push_op(Op { lineno, line, op_type: Optype::Rep });
     }
 
+    // Make sure a line can be edited in place without changing the text of other lines or of
+    // previous versions of that line: it must be at the end of the text, and have been copied
+    // there since the staged snapshot. Otherwise the line is copied first.
+    fn line_own(&mut self, lineno: usize) {
+        let r = self.lines[lineno];
+        if r.stop == self.text.len() && r.start >= self.snapshot_buffer.text_staged() {
+            return
+        }
+        self.prepare_insert(lineno);
+        self.ops_do();  // replacing a line never shifts other lines
+    }
+
     pub fn char_insert(&mut self, mode: InsertMode, p: Pos, c: char) -> Opresult {
         let (colno, lineno) = p.usize();
         // check that we are operating in Insert mode !
         // TODO: think about auto linebreak
+        self.line_own(lineno);
         match mode {
             InsertMode::Insert  => {
                 let line = &mut self.lines[lineno];
@@ -1662,9 +3614,7 @@ impl Buffer {
     pub fn char_delete(&mut self, cursor: Pos) {
         let (colno, lineno) = cursor.usize();
 
-        if self.lines[lineno].stop != self.text.len() {
-            self.prepare_insert(lineno);
-        }
+        self.line_own(lineno);
 
         let Range { start, stop } = self.lines[lineno];
         let linelen = stop - start - 1;
@@ -1702,7 +3652,7 @@ impl Buffer {
             }
 
             // else join lines
-            // BUG: use a line op here
+            self.line_own(lineno);
             self.lines[lineno].stop -= 1;
             self.line_join(cursor);
             return Opresult::Change(cursor)
@@ -1753,44 +3703,30 @@ impl Buffer {
         r
     }
 
-    // TODO: reenable undo
-    // this should be a straightforward as bumping the ops cursor and rebatching ops
-    pub fn undo(&mut self) -> Opresult {
-        match self.snapshot_buffer.undo() {
-            Some(snapshot) => {
-//                self.ops_undo(s.op_cursor);
-//                self.dirty = s.dirty;
-//                // TODO: introduce text cursor and adjust it here
-//                Opresult::Change(s.cursor)
-                Opresult::Noop
-            }
-            None => {
-                Opresult::Noop
-            }
-        }
+    // Revert the last undo step. Returns the cursor position before that step and the line
+    // insertions and deletions that happened, or None if there is nothing to undo.
+    pub fn undo(&mut self) -> Option<(Pos, Vec<Lineshift>)> {
+        self.snapshot_finish();
+        let snapshot = self.snapshot_buffer.undo()?;
+        let shifts = self.ops_undo(snapshot.op_cursor);
+        self.dirty = snapshot.dirty;
+        Some((snapshot.cursor, shifts))
     }
 
-    // TODO: reenable redo
-    // this should be a straightforward as bumping the ops cursor and rebatching ops
-    pub fn redo(&mut self) -> Opresult {
-        match self.snapshot_buffer.redo() {
-            Some(snapshot) => {
-//                self.ops_redo(s.op_cursor);
-//                // CLEANUP: this should be done as an Opresult ?
-//                // TODO adjust text cursor
-//                self.dirty = s.dirty;
-//                self.snapshot_next += 1;
-//                Opresult::Change(s.cursor)
-                Opresult::Noop
-            }
-            None => {
-                Opresult::Noop
-            }
-        }
+    // Apply again the last undone step.
+    pub fn redo(&mut self) -> Option<(Pos, Vec<Lineshift>)> {
+        self.snapshot_finish();
+        let (snapshot, next) = self.snapshot_buffer.redo()?;
+        let op_cursor_next = next.map(|s| s.op_cursor).unwrap_or(self.opbuffer.ops.len());
+        let shifts = self.ops_redo(op_cursor_next);
+        self.dirty = true;
+        Some((snapshot.cursor, shifts))
     }
 
+    // New ops discard the ops undone before.
     fn push_op(&mut self, op: Op) {
-        self.opbuffer.ops.insert(self.opbuffer.pending, op);
+        self.opbuffer.ops.truncate(self.opbuffer.pending);
+        self.opbuffer.ops.push(op);
         self.opbuffer.pending += 1;
     }
 
@@ -1798,7 +3734,9 @@ impl Buffer {
         (self.opbuffer.cursor..self.opbuffer.pending)
     }
 
-    pub fn ops_do(&mut self) {
+    // Apply all pending ops and return the line insertions and deletions that happened, in order.
+    pub fn ops_do(&mut self) -> Vec<Lineshift> {
+        let mut shifts = Vec::new();
         // 1) need to move the thing out first ...
         // 2) or I can make an index range instead and only copy grab the op.op_type by value ...
         for i in self.pending_ops() {
@@ -1813,21 +3751,27 @@ impl Buffer {
             }
             match op_type {
                 Optype::Del => {
-                    self.opbuffer.ops[i].line = line;
+                    self.opbuffer.ops[i].line = self.lines.remove(lineno);
+                    shifts.push(Lineshift::Deleted(lineno));
                 }
                 Optype::Ins => {
                     self.lines.insert(lineno,line);
+                    shifts.push(Lineshift::Inserted(lineno));
                 }
                 Optype::Rep => {
                     swap(&mut self.opbuffer.ops[i].line, &mut self.lines[lineno]);
                 }
             }
         }
-        self.opbuffer.pending = self.opbuffer.cursor;
+        self.opbuffer.cursor = self.opbuffer.pending;
+        self.marks_adjust(&shifts);
+
+        shifts
     }
 
-    fn ops_undo(&mut self, op_cursor_prev: usize) {
+    fn ops_undo(&mut self, op_cursor_prev: usize) -> Vec<Lineshift> {
         check!(op_cursor_prev <= self.opbuffer.cursor);
+        let mut shifts = Vec::new();
         self.opbuffer.cursor = op_cursor_prev;
         for i in self.pending_ops().rev() {
             let op_type;
@@ -1842,9 +3786,11 @@ impl Buffer {
             match op_type {
                 Optype::Del => {
                     self.lines.insert(lineno, line);
+                    shifts.push(Lineshift::Inserted(lineno));
                 }
                 Optype::Ins => {
-                    self.lines.remove(lineno);
+                    self.opbuffer.ops[i].line = self.lines.remove(lineno);
+                    shifts.push(Lineshift::Deleted(lineno));
                 }
                 Optype::Rep => {
                     swap(&mut self.opbuffer.ops[i].line, &mut self.lines[lineno]);
@@ -1852,12 +3798,15 @@ impl Buffer {
             }
         }
         self.opbuffer.pending = op_cursor_prev;
+        self.marks_adjust(&shifts);
+
+        shifts
     }
 
-    fn ops_redo(&mut self, op_cursor_next: usize) {
+    fn ops_redo(&mut self, op_cursor_next: usize) -> Vec<Lineshift> {
         check!(self.opbuffer.cursor <= op_cursor_next);
         self.opbuffer.pending = op_cursor_next;
-        self.ops_do();
+        self.ops_do()
     }
 }
 
@@ -1884,17 +3833,19 @@ impl SnapshotBuffer {
         if self.pending {
             return;
         }
-        if self.snapshots.len() == self.cursor {
-            self.snapshots.reserve(1);
-        }
-        self.snapshots[self.cursor] = snapshot;
+        self.snapshots.truncate(self.cursor);
+        self.snapshots.push(snapshot);
         self.pending = true;
     }
 
-    // Save snapshot if staged, reset staged state.
-    fn finish_command(&mut self) {
+    // Save snapshot if staged and followed by ops up to 'op_cursor', reset staged state.
+    fn finish_command(&mut self, op_cursor: usize) {
         if self.pending {
-            self.cursor += 1;
+            if self.snapshots[self.cursor].op_cursor == op_cursor {
+                self.snapshots.truncate(self.cursor);
+            } else {
+                self.cursor += 1;
+            }
         }
         self.pending = false;
     }
@@ -1908,28 +3859,36 @@ impl SnapshotBuffer {
         self.cursor == 0
     }
 
+    // Text appended after this offset is not referenced by any undo step.
+    fn text_staged(&self) -> usize {
+        match self.snapshots.last() {
+            Some(s) if self.pending => s.text_cursor,
+            _                       => usize::MAX,
+        }
+    }
+
+    // The snapshot before the last undo step.
     fn undo(&mut self) -> Option<Snapshot> {
         check!(!self.pending);
         if self.cursor == 0 {
             return None
         }
 
-        let snapshot = self.snapshots[self.cursor];
         self.cursor -= 1;
-
-        Some(snapshot)
+        Some(self.snapshots[self.cursor])
     }
 
-    fn redo(&mut self) -> Option<Snapshot> {
+    // The snapshot before the next undone step, and the snapshot after it if there is one.
+    fn redo(&mut self) -> Option<(Snapshot, Option<Snapshot>)> {
         check!(!self.pending);
         if self.cursor == self.snapshots.len() {
             return None
         }
 
         let snapshot = self.snapshots[self.cursor];
-        self.cursor -= 1;
+        self.cursor += 1;
 
-        Some(snapshot)
+        Some((snapshot, self.snapshots.get(self.cursor).cloned()))
     }
 }
 
@@ -1975,6 +3934,34 @@ enum Optype {
     Rep,
 }
 
+// A line insertion or deletion applied by ops_do(), used for moving positions stored outside of the
+// Buffer along with the text they point to.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Lineshift {
+    Inserted(usize),
+    Deleted(usize),
+}
+
+impl Lineshift {
+    // Returns None if the line of p was deleted.
+    pub fn adjust(self, p: Pos) -> Option<Pos> {
+        use self::Lineshift::*;
+        match self {
+            Inserted(lineno) if i32(lineno) <= p.y  => Some(p + pos(0,1)),
+            Deleted(lineno) if i32(lineno) == p.y   => None,
+            Deleted(lineno) if i32(lineno) < p.y    => Some(p - pos(0,1)),
+            _                                       => Some(p),
+        }
+    }
+
+    pub fn adjust_all(shifts: &[Lineshift], mut p: Pos) -> Option<Pos> {
+        for s in shifts {
+            p = s.adjust(p)?;
+        }
+        Some(p)
+    }
+}
+
 // A linear history of operations.
 // OpHistory has two cursors:
 //  - the current cursor represents the points of the last frame drawn
@@ -1988,44 +3975,888 @@ struct OpBuffer {
     pending:    usize,
 }
 
-} // mod text
+#[cfg(test)]
+mod tests {
 
+use super::*;
 
+fn buffer(text: &str) -> Buffer {
+    Buffer::from_text(text.as_bytes().to_vec())
+}
 
+fn content(b: &Buffer) -> String {
+    b.iter_all().map(|line| String::from_utf8_lossy(line).into_owned()).collect::<Vec<String>>().join("\n")
+}
 
-/* CORE TYPE DEFINITION */
+// Apply the ops of a buffer operation and return the cursor it leaves.
+fn apply(b: &mut Buffer, r: Opresult) -> Pos {
+    b.ops_do();
+    match r {
+        Opresult::Change(p) => p,
+        r                   => panic!("unexpected result {:?}", r),
+    }
+}
 
-// The core editor structure
-// TODO: add open buffer list, open views, open screens
-struct Editor {
-    window:         Pos,        // The dimensions of the editor and backend terminal window
-    mainscreen:     Rec,        // The screen area for displaying file content and menus.
-    footer:         Rec,
-    buffer:         Buffer,     // The one file loaded in the editor
-    view:           View,       // The one view of the one file loaded
-    screen:         Screen,     // The one screen associated to the one file loaded
+#[test]
+fn word_next() {
+    let b = buffer("foo bar.baz\n  qux");
+    assert_eq!(b.word_next(pos(0,0)), pos(4,0));
+    assert_eq!(b.word_next(pos(1,0)), pos(4,0));
+    assert_eq!(b.word_next(pos(4,0)), pos(7,0));
+    assert_eq!(b.word_next(pos(7,0)), pos(8,0));
+    assert_eq!(b.word_next(pos(8,0)), pos(2,1));
+    assert_eq!(b.word_next(pos(2,1)), pos(5,1));
 }
 
-#[derive(Debug)]
-enum Move {
-    Left,
-    Right,
-    Up,
-    Down,
-    Start,
-    End,
+#[test]
+fn word_prev() {
+    let b = buffer("foo bar.baz\n  qux");
+    assert_eq!(b.word_prev(pos(2,1)), pos(8,0));
+    assert_eq!(b.word_prev(pos(8,0)), pos(7,0));
+    assert_eq!(b.word_prev(pos(6,0)), pos(4,0));
+    assert_eq!(b.word_prev(pos(4,0)), pos(0,0));
+    assert_eq!(b.word_prev(pos(0,0)), pos(0,0));
 }
 
-#[derive(Debug)]
-enum MovementMode {
-    Chars,
-    Lines,
-    Blocks,
-    Words,
-    Digits,
-    Numbers,
-    Paragraphs,
-    Parens,
+#[test]
+fn word_end() {
+    let b = buffer("foo bar.baz\n  qux");
+    assert_eq!(b.word_end(pos(0,0)), pos(2,0));
+    assert_eq!(b.word_end(pos(2,0)), pos(6,0));
+    assert_eq!(b.word_end(pos(6,0)), pos(7,0));
+    assert_eq!(b.word_end(pos(10,0)), pos(4,1));
+    assert_eq!(b.word_end(pos(4,1)), pos(5,1));
+}
+
+#[test]
+fn paragraph_next() {
+    let b = buffer("a\nb\n\nc\n\nd");
+    assert_eq!(b.paragraph_next(pos(0,0)), pos(0,2));
+    assert_eq!(b.paragraph_next(pos(0,1)), pos(0,2));
+    assert_eq!(b.paragraph_next(pos(0,2)), pos(0,4));
+    assert_eq!(b.paragraph_next(pos(0,3)), pos(0,4));
+    assert_eq!(b.paragraph_next(pos(0,4)), pos(1,5));
+}
+
+#[test]
+fn paragraph_prev() {
+    let b = buffer("a\nb\n\nc\n\nd");
+    assert_eq!(b.paragraph_prev(pos(0,5)), pos(0,4));
+    assert_eq!(b.paragraph_prev(pos(0,4)), pos(0,2));
+    assert_eq!(b.paragraph_prev(pos(0,3)), pos(0,2));
+    assert_eq!(b.paragraph_prev(pos(0,2)), pos(0,0));
+    assert_eq!(b.paragraph_prev(pos(0,1)), pos(0,0));
+}
+
+#[test]
+fn bracket_match() {
+    let b = buffer("f(a[b], c)\n{\n}\n(x");
+    assert_eq!(b.bracket_match(pos(0,0)), Some(pos(9,0)));
+    assert_eq!(b.bracket_match(pos(3,0)), Some(pos(5,0)));
+    assert_eq!(b.bracket_match(pos(9,0)), Some(pos(1,0)));
+    assert_eq!(b.bracket_match(pos(0,1)), Some(pos(0,2)));
+    assert_eq!(b.bracket_match(pos(0,2)), Some(pos(0,1)));
+    assert_eq!(b.bracket_match(pos(0,3)), None);
+    assert_eq!(b.bracket_match(pos(1,3)), None);
+}
+
+#[test]
+fn search() {
+    let b = buffer("ab ab\nxab");
+    assert_eq!(b.search(b"ab", pos(0,0), true), Some(pos(3,0)));
+    assert_eq!(b.search(b"ab", pos(3,0), true), Some(pos(1,1)));
+    assert_eq!(b.search(b"ab", pos(1,1), true), Some(pos(0,0)));
+    assert_eq!(b.search(b"ab", pos(0,0), false), Some(pos(1,1)));
+    assert_eq!(b.search(b"ab", pos(3,0), false), Some(pos(0,0)));
+    assert_eq!(b.search(b"x", pos(0,1), true), Some(pos(0,1)));
+    assert_eq!(b.search(b"zz", pos(0,0), true), None);
+    assert_eq!(b.search(b"", pos(0,0), true), None);
+}
+
+#[test]
+fn range_delete() {
+    let mut b = buffer("one two\nthree four");
+    let r = b.range_delete(pos(1,0), pos(3,0));
+    assert_eq!(apply(&mut b, r), pos(1,0));
+    assert_eq!(content(&b), "o two\nthree four");
+
+    let r = b.range_delete(pos(2,0), pos(6,1));
+    assert_eq!(apply(&mut b, r), pos(2,0));
+    assert_eq!(content(&b), "o four");
+}
+
+#[test]
+fn lines_delete() {
+    let mut b = buffer("a\nb\nc");
+    let r = b.lines_delete(1, 1);
+    assert_eq!(apply(&mut b, r), pos(0,1));
+    assert_eq!(content(&b), "a\nc");
+
+    // Deleting the last line leaves the cursor on the new last line.
+    let mut b = buffer("a\nb\nc");
+    let r = b.lines_delete(2, 2);
+    assert_eq!(apply(&mut b, r), pos(0,1));
+    assert_eq!(content(&b), "a\nb");
+
+    let mut b = buffer("a\nb\nc");
+    let r = b.lines_delete(1, 2);
+    assert_eq!(apply(&mut b, r), pos(0,0));
+    assert_eq!(content(&b), "a");
+
+    let mut b = buffer("a\nb\nc");
+    let r = b.lines_delete(0, 2);
+    assert_eq!(apply(&mut b, r), pos(0,0));
+    assert_eq!(b.nlines(), 1);
+    assert_eq!(content(&b), "");
+}
+
+#[test]
+fn text_insert() {
+    let mut b = buffer("ab\ncd");
+    let r = b.text_insert(pos(1,0), b"xy");
+    assert_eq!(apply(&mut b, r), pos(3,0));
+    assert_eq!(content(&b), "axyb\ncd");
+
+    let r = b.text_insert(pos(1,1), b"1\n2\n3");
+    assert_eq!(apply(&mut b, r), pos(1,3));
+    assert_eq!(content(&b), "axyb\nc1\n2\n3d");
+
+    let r = b.text_insert(pos(0,0), b"\n");
+    assert_eq!(apply(&mut b, r), pos(0,1));
+    assert_eq!(content(&b), "\naxyb\nc1\n2\n3d");
+}
+
+#[test]
+fn range_delete_undo_redo() {
+    let mut b = buffer("one two\nthree");
+    b.snapshot_take(pos(4,0));
+    let r = b.range_delete(pos(4,0), pos(2,1));
+    apply(&mut b, r);
+    b.snapshot_finish();
+    assert_eq!(content(&b), "one ree");
+
+    assert_eq!(b.undo().map(|(p, _)| p), Some(pos(4,0)));
+    assert_eq!(content(&b), "one two\nthree");
+    assert!(b.undo().is_none());
+
+    assert!(b.redo().is_some());
+    assert_eq!(content(&b), "one ree");
+    assert!(b.redo().is_none());
+}
+
+#[test]
+fn lines_delete_undo_redo() {
+    let mut b = buffer("a\nb\nc");
+    b.snapshot_take(pos(0,2));
+    let r = b.lines_delete(1, 2);
+    apply(&mut b, r);
+    b.snapshot_finish();
+    assert_eq!(content(&b), "a");
+
+    assert_eq!(b.undo().map(|(p, _)| p), Some(pos(0,2)));
+    assert_eq!(content(&b), "a\nb\nc");
+    assert!(b.redo().is_some());
+    assert_eq!(content(&b), "a");
+}
+
+#[test]
+fn word_object() {
+    let b = buffer("foo  bar baz\n");
+    assert_eq!(b.word_object(pos(1,0), true, 1), (pos(0,0), pos(3,0)));
+    assert_eq!(b.word_object(pos(1,0), false, 1), (pos(0,0), pos(5,0)));
+    assert_eq!(b.word_object(pos(0,0), true, 2), (pos(0,0), pos(5,0)));
+    assert_eq!(b.word_object(pos(0,0), true, 3), (pos(0,0), pos(8,0)));
+    assert_eq!(b.word_object(pos(0,0), false, 2), (pos(0,0), pos(9,0)));
+    // Spaces are a run of their own, and the whole object takes the word after them.
+    assert_eq!(b.word_object(pos(3,0), true, 1), (pos(3,0), pos(5,0)));
+    assert_eq!(b.word_object(pos(3,0), false, 1), (pos(3,0), pos(8,0)));
+    // At the end of the line, the whole word takes the spaces before it.
+    assert_eq!(b.word_object(pos(10,0), true, 1), (pos(9,0), pos(12,0)));
+    assert_eq!(b.word_object(pos(10,0), false, 1), (pos(8,0), pos(12,0)));
+    // Counts do not go past the end of the line.
+    assert_eq!(b.word_object(pos(10,0), true, 5), (pos(9,0), pos(12,0)));
+    assert_eq!(b.word_object(pos(0,1), false, 1), (pos(0,1), pos(0,1)));
+}
+
+#[test]
+fn block_object() {
+    let b = buffer("f(a, (b), c)");
+    assert_eq!(b.block_object(pos(6,0), b'(', b')', true, 1), Some((pos(6,0), pos(7,0), false)));
+    assert_eq!(b.block_object(pos(6,0), b'(', b')', false, 1), Some((pos(5,0), pos(8,0), false)));
+    assert_eq!(b.block_object(pos(6,0), b'(', b')', true, 2), Some((pos(2,0), pos(11,0), false)));
+    assert_eq!(b.block_object(pos(6,0), b'(', b')', false, 2), Some((pos(1,0), pos(12,0), false)));
+    // On the brackets themselves.
+    assert_eq!(b.block_object(pos(1,0), b'(', b')', true, 1), Some((pos(2,0), pos(11,0), false)));
+    assert_eq!(b.block_object(pos(11,0), b'(', b')', true, 1), Some((pos(2,0), pos(11,0), false)));
+    assert_eq!(b.block_object(pos(8,0), b'(', b')', true, 1), Some((pos(2,0), pos(11,0), false)));
+    assert_eq!(b.block_object(pos(6,0), b'(', b')', true, 3), None);
+    assert_eq!(b.block_object(pos(0,0), b'(', b')', true, 1), None);
+    assert_eq!(b.block_object(pos(6,0), b'[', b']', true, 1), None);
+}
+
+#[test]
+fn block_object_lines() {
+    // The inner text of a block with brackets ending and starting lines is its inner lines.
+    let b = buffer("f {\n  a\n  b\n}");
+    assert_eq!(b.block_object(pos(2,1), b'{', b'}', true, 1), Some((pos(0,1), pos(0,2), true)));
+    assert_eq!(b.block_object(pos(2,1), b'{', b'}', false, 1), Some((pos(2,0), pos(1,3), false)));
+
+    let b = buffer("{a\nb}");
+    assert_eq!(b.block_object(pos(0,1), b'{', b'}', true, 1), Some((pos(1,0), pos(1,1), false)));
+}
+
+} // mod tests
+
+} // mod text
+
+
+
+
+/* CTAGS AND ETAGS INDEXES */
+mod tags {
+
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use util::*;
+use ioutil;
+
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Tagaddress {
+    Lineno(i32),            // 1-based line number
+    Pattern(String),        // text of the line, without search delimiters and anchors
+}
+
+// One definition of an identifier.
+#[derive(Debug, Clone)]
+pub struct Tag {
+    pub name:       String,
+    pub filepath:   String,
+    pub address:    Tagaddress,
+}
+
+const TAGFILE_CTAGS : &'static str = "tags";
+const TAGFILE_ETAGS : &'static str = "TAGS";
+
+// Look for a tag file in the directory of 'filepath' and in all its parent directories.
+pub fn find_tagfile(filepath: &str) -> Option<PathBuf> {
+    let path = PathBuf::from(ioutil::canonical_path(filepath));
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        for name in &[TAGFILE_CTAGS, TAGFILE_ETAGS] {
+            let candidate = d.join(name);
+            if candidate.is_file() {
+                return Some(candidate)
+            }
+        }
+        dir = d.parent();
+    }
+    None
+}
+
+// Find all definitions of 'name' in a tag file. File paths of the returned tags are resolved
+// relatively to the directory of the tag file.
+pub fn lookup(tagfile: &Path, name: &str) -> Re<Vec<Tag>> {
+    let text = ioutil::file_load(&tagfile.to_string_lossy())?;
+    let text = String::from_utf8_lossy(&text);
+    let basedir = tagfile.parent().unwrap_or(Path::new("."));
+
+    let is_etags = tagfile.file_name().is_some_and(|n| n == TAGFILE_ETAGS);
+    let mut tags = if is_etags {
+        parse_etags(&text, name)
+    } else {
+        parse_ctags(&text, name)
+    };
+
+    for t in tags.iter_mut() {
+        t.filepath = basedir.join(&t.filepath).to_string_lossy().into_owned();
+    }
+
+    Ok(tags)
+}
+
+// Exuberant/Universal ctags format: one tag per line,
+//  name<TAB>filepath<TAB>address;"<TAB>extension fields
+// where address is either a line number or a /^pattern$/ or ?^pattern$? search command.
+fn parse_ctags(text: &str, name: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    for line in text.lines() {
+        if line.starts_with("!_TAG_") {
+            continue
+        }
+        let mut fields = line.splitn(3, '\t');
+        let (tagname, filepath, rest) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(n), Some(f), Some(r)) => (n, f, r),
+            _                           => continue,
+        };
+        if tagname != name {
+            continue
+        }
+        let address = match rest.rfind(";\"") {
+            Some(i) => &rest[..i],
+            None    => rest,
+        };
+        if let Some(address) = parse_ctags_address(address) {
+            tags.push(Tag {
+                name:       tagname.to_string(),
+                filepath:   filepath.to_string(),
+                address,
+            });
+        }
+    }
+    tags
+}
+
+fn parse_ctags_address(address: &str) -> Option<Tagaddress> {
+    if let Ok(n) = address.trim().parse::<i32>() {
+        return Some(Tagaddress::Lineno(n))
+    }
+
+    let bytes = address.as_bytes();
+    if bytes.len() < 2 {
+        return None
+    }
+    let delim = bytes[0];
+    if (delim != b'/' && delim != b'?') || bytes[bytes.len() - 1] != delim {
+        return None
+    }
+
+    let mut pattern = &address[1..address.len() - 1];
+    if pattern.starts_with("^") {
+        pattern = &pattern[1..];
+    }
+    if pattern.ends_with("$") && !pattern.ends_with("\\$") {
+        pattern = &pattern[..pattern.len() - 1];
+    }
+
+    // Unescape search delimiters and backslashes.
+    let mut unescaped = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(d) = chars.next() {
+                unescaped.push(d);
+            }
+            continue
+        }
+        unescaped.push(c);
+    }
+
+    Some(Tagaddress::Pattern(unescaped))
+}
+
+// Emacs etags format: a sequence of sections, one per source file,
+//  \x0c
+//  filepath,size
+//  line text\x7fname\x01lineno,offset
+// where the explicit name part is optional, in which case the name is the last identifier of
+// the line text.
+fn parse_etags(text: &str, name: &str) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    let mut filepath = "";
+    let mut lines = text.lines();
+    while let Some(line) = lines.next() {
+        if line == "\x0c" {
+            filepath = lines.next().and_then(|l| l.rsplit_once(',')).map(|(path, _)| path).unwrap_or("");
+            continue
+        }
+
+        let (def, location) = match line.find('\x7f') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None    => continue,
+        };
+        let (tagname, location) = match location.find('\x01') {
+            Some(i) => (&location[..i], &location[i + 1..]),
+            None    => (last_identifier(def), location),
+        };
+        if tagname != name {
+            continue
+        }
+
+        let lineno = location.split(',').next().and_then(|n| n.parse::<i32>().ok());
+        let address = match lineno {
+            Some(n) => Tagaddress::Lineno(n),
+            None    => Tagaddress::Pattern(def.to_string()),
+        };
+        tags.push(Tag {
+            name:       tagname.to_string(),
+            filepath:   filepath.to_string(),
+            address,
+        });
+    }
+    tags
+}
+
+pub fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn last_identifier(text: &str) -> &str {
+    let end = text.rfind(is_identifier_char).map_or(0, |i| i + 1);
+    let start = text[..end].rfind(|c| !is_identifier_char(c)).map_or(0, |i| i + 1);
+    &text[start..end]
+}
+
+
+} // mod tags
+
+
+
+
+/* DIRECTORY TRAVERSAL */
+mod explore;
+
+
+
+
+/* FUZZY FILE FINDER */
+mod finder {
+
+
+use std::cmp::max;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc::SyncSender;
+use std::thread;
+use std::time::SystemTime;
+
+use explore;
+use term::WAKEUP;
+
+
+const MAX_RESULTS : usize = 200;
+const INDEX_BATCH : usize = 1000;
+
+// Index of all file paths under a project root, and fuzzy matching of queries against it.
+// Indexing and matching both happen in background threads which wake up the input loop when new
+// files or new results are available.
+pub struct Finder {
+    pub root:       PathBuf,
+    index:          Arc<RwLock<Vec<String>>>,
+    indexing:       Arc<AtomicBool>,
+    refresh_send:   Sender<()>,
+    query_send:     Sender<(usize, String)>,
+    result_recv:    Receiver<(usize, Vec<String>)>,
+    generation:     usize,      // id of the last query sent
+    indexed:        usize,      // size of the index when the last query was sent
+    query:          String,
+}
+
+impl Finder {
+    pub fn mk_finder(root: PathBuf, wakeup: SyncSender<char>) -> Finder {
+        let index = Arc::new(RwLock::new(Vec::new()));
+        let indexing = Arc::new(AtomicBool::new(false));
+        let (refresh_send, refresh_recv) = mpsc::channel();
+        let (query_send, query_recv) = mpsc::channel();
+        let (result_send, result_recv) = mpsc::channel();
+
+        {
+            let root = root.clone();
+            let index = index.clone();
+            let indexing = indexing.clone();
+            let wakeup = wakeup.clone();
+            thread::spawn(move || indexer(&root, &index, &indexing, &refresh_recv, &wakeup));
+        }
+        {
+            let index = index.clone();
+            thread::spawn(move || matcher(&index, &query_recv, &result_send, &wakeup));
+        }
+
+        let finder = Finder {
+            root,
+            index,
+            indexing,
+            refresh_send,
+            query_send,
+            result_recv,
+            generation:     0,
+            indexed:        0,
+            query:          String::new(),
+        };
+        finder.refresh();
+        finder
+    }
+
+    // Ask for the index to be updated with the current content of the project.
+    pub fn refresh(&self) {
+        let _ = self.refresh_send.send(());
+    }
+
+    pub fn query(&mut self, query: &str) {
+        self.generation += 1;
+        self.indexed = self.len();
+        self.query = query.to_string();
+        let _ = self.query_send.send((self.generation, self.query.clone()));
+    }
+
+    // Results of the last query if they arrived since the last call. If the index changed since the
+    // last query, that query is sent again.
+    pub fn results(&mut self) -> Option<Vec<String>> {
+        let mut latest = None;
+        while let Ok((generation, results)) = self.result_recv.try_recv() {
+            if generation == self.generation {
+                latest = Some(results);
+            }
+        }
+        if self.len() != self.indexed {
+            let query = self.query.clone();
+            self.query(&query);
+        }
+        latest
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.read().map(|index| index.len()).unwrap_or(0)
+    }
+
+    pub fn is_indexing(&self) -> bool {
+        self.indexing.load(Ordering::Relaxed)
+    }
+}
+
+fn wake(wakeup: &SyncSender<char>) {
+    // If the input channel is full, the input loop will wake up anyway.
+    let _ = wakeup.try_send(WAKEUP);
+}
+
+// Walk the project for every refresh request. Listings of directories whose modification time did
+// not change are reused from the previous walk. The first walk publishes files as they are found,
+// later walks replace the index at once when done.
+fn indexer(root: &Path, index: &RwLock<Vec<String>>, indexing: &AtomicBool,
+           refresh_recv: &Receiver<()>, wakeup: &SyncSender<char>) {
+    let mut listings : HashMap<PathBuf, (SystemTime, Vec<explore::Entry>)> = HashMap::new();
+    let mut first = true;
+
+    while refresh_recv.recv().is_ok() {
+        while refresh_recv.try_recv().is_ok() {} // coalesce pending requests
+        indexing.store(true, Ordering::Relaxed);
+
+        let mut files = Vec::new();
+        let mut batch = Vec::new();
+        let mut seen = HashMap::new();
+        {
+            let mut list = |dir: &Path| {
+                let mtime = dir.metadata()?.modified()?;
+                let entries = match listings.get(dir) {
+                    Some(&(t, ref entries)) if t == mtime => entries.clone(),
+                    _ => explore::list_dir(dir)?,
+                };
+                seen.insert(dir.to_path_buf(), (mtime, entries.clone()));
+                Ok(entries)
+            };
+            let mut visit = |entry: &explore::Entry| {
+                if entry.is_dir {
+                    return
+                }
+                let path = entry.path.strip_prefix(root).unwrap_or(&entry.path);
+                let path = path.to_string_lossy().into_owned();
+                if first {
+                    batch.push(path);
+                    if batch.len() == INDEX_BATCH {
+                        if let Ok(mut index) = index.write() {
+                            index.extend(batch.drain(..));
+                        }
+                        wake(wakeup);
+                    }
+                } else {
+                    files.push(path);
+                }
+            };
+            explore::walk_project(root, &mut list, &mut visit);
+        }
+
+        if let Ok(mut index) = index.write() {
+            if first {
+                index.extend(batch.drain(..));
+            } else {
+                *index = files;
+            }
+        }
+        listings = seen;
+        first = false;
+        indexing.store(false, Ordering::Relaxed);
+        wake(wakeup);
+    }
+}
+
+// Score the index against the latest query, skipping queries made stale by newer ones.
+fn matcher(index: &RwLock<Vec<String>>, query_recv: &Receiver<(usize, String)>,
+           result_send: &Sender<(usize, Vec<String>)>, wakeup: &SyncSender<char>) {
+    while let Ok(mut request) = query_recv.recv() {
+        while let Ok(newer) = query_recv.try_recv() {
+            request = newer;
+        }
+        let (generation, query) = request;
+        let query : Vec<u8> = query.to_lowercase().into_bytes();
+
+        let mut scored : Vec<(i32, String)> = match index.read() {
+            Ok(index) => index.iter().filter_map(|path| {
+                score(&query, path.as_bytes()).map(|s| (s, path.clone()))
+            }).collect(),
+            Err(_) => continue,
+        };
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.len().cmp(&b.1.len())));
+        scored.truncate(MAX_RESULTS);
+
+        let results = scored.into_iter().map(|(_, path)| path).collect();
+        if result_send.send((generation, results)).is_err() {
+            return
+        }
+        wake(wakeup);
+    }
+}
+
+const SCORE_MATCH           : i32 = 16;
+const SCORE_CONSECUTIVE     : i32 = 12;
+const SCORE_SEGMENT_START   : i32 = 24;     // match right after a '/'
+const SCORE_WORD_START      : i32 = 12;     // match after '_', '-', '.', ' ' or on a camelCase hump
+const SCORE_BASENAME        : i32 = 4;      // match inside the file name
+const SCORE_GAP             : i32 = -1;     // per skipped char
+const SCORE_GAP_MAX         : i32 = -8;
+
+// Score 'path' against a lowercase 'query' if the query is a subsequence of the path, higher is
+// better. Matching is done right to left so that queries preferably match file names.
+pub fn score(query: &[u8], path: &[u8]) -> Option<i32> {
+    let basename_start = path.iter().rposition(|&c| c == b'/').map_or(0, |i| i + 1);
+
+    let mut total = 0;
+    let mut q = query.len();
+    let mut prev_match : Option<usize> = None;
+    let mut i = path.len();
+    while q > 0 && i > 0 {
+        i -= 1;
+        if path[i].to_ascii_lowercase() != query[q - 1] {
+            continue
+        }
+        q -= 1;
+
+        total += SCORE_MATCH;
+        match prev_match {
+            Some(j) if j == i + 1   => total += SCORE_CONSECUTIVE,
+            Some(j)                 => total += max(SCORE_GAP_MAX, SCORE_GAP * (j - i - 1) as i32),
+            None                    => (),
+        }
+        if i == 0 || path[i - 1] == b'/' {
+            total += SCORE_SEGMENT_START;
+        } else if is_word_start(path[i - 1], path[i]) {
+            total += SCORE_WORD_START;
+        }
+        if i >= basename_start {
+            total += SCORE_BASENAME;
+        }
+        prev_match = Some(i);
+    }
+
+    if q > 0 {
+        return None
+    }
+    Some(total)
+}
+
+fn is_word_start(prev: u8, c: u8) -> bool {
+    match prev {
+        b'_' | b'-' | b'.' | b' '   => true,
+        _                           => prev.is_ascii_lowercase() && c.is_ascii_uppercase(),
+    }
+}
+
+
+} // mod finder
+
+
+
+/* COMMAND LINE ARGUMENTS */
+
+const USAGE : &'static str = "usage: czl [-R] [+LINE] [FILE[:LINE[:COL]] | DIR]... [--] [FILE]...";
+
+// Command line arguments.
+struct Args {
+    files:      Vec<Filearg>,
+    dirs:       Vec<String>,    // directories to open in the explorer
+    readonly:   bool,           // refuse to save files opened from the command line, unless forced
+    help:       bool,
+}
+
+// A file to open, and where to put the cursor in it.
+struct Filearg {
+    path:       String,
+    cursor:     Option<Pos>,
+}
+
+impl Args {
+    fn parse<I>(args: I) -> Re<Args> where I : Iterator<Item=String> {
+        let mut parsed = Args {
+            files:      Vec::new(),
+            dirs:       Vec::new(),
+            readonly:   false,
+            help:       false,
+        };
+        let mut lineno = None;      // from +LINE, for the next file
+        let mut options = true;
+
+        for arg in args {
+            if options {
+                match arg.as_str() {
+                    "--" => {
+                        options = false;
+                        continue
+                    }
+                    "-R" => {
+                        parsed.readonly = true;
+                        continue
+                    }
+                    "-h" | "--help" => {
+                        parsed.help = true;
+                        continue
+                    }
+                    _ if arg.starts_with("+") => {
+                        match arg[1..].parse::<i32>() {
+                            Ok(n) if n > 0  => lineno = Some(n),
+                            _               => return er!(format!("invalid line number: {}", arg)),
+                        }
+                        continue
+                    }
+                    _ if arg.starts_with("-") && arg.len() > 1 =>
+                        return er!(format!("unknown option: {}", arg)),
+                    _ => (),
+                }
+            }
+
+            if Path::new(&arg).is_dir() {
+                parsed.dirs.push(arg);
+                continue
+            }
+            let (path, mut cursor) = Args::split_position(&arg);
+            if let Some(n) = lineno.take() {
+                cursor = Some(pos(0, n - 1));
+            }
+            parsed.files.push(Filearg {
+                path,
+                cursor,
+            });
+        }
+
+        if lineno.is_some() {
+            return er!("+LINE must be followed by a file")
+        }
+
+        Ok(parsed)
+    }
+
+    // Split "path:line:col" or "path:line", as printed by compilers and grep, unless 'arg' is the
+    // name of an existing file.
+    fn split_position(arg: &str) -> (String, Option<Pos>) {
+        if Path::new(arg).exists() {
+            return (arg.to_string(), None)
+        }
+        let number = |s: &str| s.parse::<i32>().ok().filter(|&n| n > 0);
+        let trimmed = arg.trim_end_matches(':');
+
+        let fields : Vec<&str> = trimmed.rsplitn(3, ':').collect();
+        if fields.len() == 3 && !fields[2].is_empty() {
+            if let (Some(col), Some(line)) = (number(fields[0]), number(fields[1])) {
+                return (fields[2].to_string(), Some(pos(col - 1, line - 1)))
+            }
+        }
+        let fields : Vec<&str> = trimmed.rsplitn(2, ':').collect();
+        if fields.len() == 2 && !fields[1].is_empty() {
+            if let Some(line) = number(fields[0]) {
+                return (fields[1].to_string(), Some(pos(0, line - 1)))
+            }
+        }
+
+        (arg.to_string(), None)
+    }
+}
+
+
+
+/* CORE TYPE DEFINITION */
+
+// The core editor structure
+struct Editor {
+    window:         Pos,        // The dimensions of the editor and backend terminal window
+    mainscreen:     Rec,        // The screen area for displaying file content and menus.
+    footer:         Rec,
+    buffers:        Bufferlist, // All files loaded in the editor
+    view:           View,       // The focused view, showing one of the loaded files
+    screen:         Screen,     // The screen associated to the focused view
+    pane_id:        usize,      // id of the pane of the focused view and screen
+    panes:          Vec<Pane>,  // All other views and screens
+    layout:         Layout,     // How panes share the main screen
+    next_pane_id:   usize,
+    tabname:        String,     // name of the tab page shown
+    tab:            usize,      // position of the tab page shown in the tab bar
+    tabs:           Vec<Tabpage>, // All other tab pages, in tab bar order
+    marks:          Vec<Mark>,  // Global marks, persisted in the session file
+    picker:         Option<Picker>,
+    explorer:       Option<Explorer>,
+    prompt:         Option<Prompt>,
+    cmdline:        Option<Cmdline>,
+    cmdhistory:     Vec<String>,
+    message:        Option<String>, // shown in the footer until the next command
+    macros:         Vec<(char, Vec<Input>)>,    // recorded inputs by register
+    recording:      Option<Recording>,
+    replaying:      usize,      // depth of nested macro replays
+    failed:         bool,       // set when a movement cannot be done, to stop macro replays
+    change:         Option<Change>, // the last change, for repeating it
+    insert_ops:     Vec<InsertOp>,      // ops typed so far in the current insert session
+    yank:           Option<Yank>,
+    dragging:       bool,           // a click in a text area started, the mouse can select text
+    search:         Option<String>, // the last searched text
+    keymaps:        Keymaps,
+    keys:           Vec<Input>, // keys typed so far of a key sequence bound in a keymap
+    count:          usize,      // count typed before a command, or 0
+    finder:         Option<finder::Finder>,
+    wakeup:         Option<SyncSender<char>>,   // for waking up the input loop from other threads
+}
+
+// Inputs being recorded into a macro register.
+struct Recording {
+    register:       char,
+    inputs:         Vec<Input>,
+    sequence_start: usize,      // where the current key sequence started in 'inputs'
+}
+
+// Limit for counts typed before commands.
+const COUNT_MAX : usize = 9999;
+
+// Lines scrolled by one step of the mouse wheel.
+const SCROLL_LINES : i32 = 3;
+
+// Limit for macros replaying themselves.
+const MACRO_DEPTH_MAX : usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+enum Move {
+    Left,
+    Right,
+    Up,
+    Down,
+    Start,
+    End,
+}
+
+#[derive(Debug)]
+enum MovementMode {
+    Chars,
+    Lines,
+    Blocks,
+    Words,
+    Digits,
+    Numbers,
+    Paragraphs,
+    Parens,
     Brackets,
     Braces,
     Selection,
@@ -2038,6 +4869,20 @@ enum Mode {
     Command,
     Insert(InsertMode),
     PendingInsert(InsertMode),
+    MarkSet,
+    MarkGoto,
+    MacroRecord,    // waiting for the register to record into
+    MacroReplay,    // waiting for the register to replay
+    Operator(Operator, usize),      // waiting for the motion of an operator typed with a count
+    OperatorMark(Operator, usize),  // waiting for the mark ending the motion of an operator
+    Object(Option<Operator>, usize, bool),  // waiting for the text object of an operator, or of
+                                            // the selection without operator, inner if set
+    Visual(bool),   // selecting text from the view anchor to the cursor, whole lines if set
+    Pick,
+    Explorer,
+    Prompt,
+    Pane,       // waiting for a pane or tab page command after ^W
+    Cmdline,
 }
 
 
@@ -2047,6 +4892,18 @@ const MODE_INSERT   : &'static str = "Insert   ";
 const MODE_PINSERT  : &'static str = "Insert?  ";
 const MODE_REPLACE  : &'static str = "Replace  ";
 const MODE_PREPLACE : &'static str = "Replace? ";
+const MODE_MARKSET  : &'static str = "Mark     ";
+const MODE_MARKGOTO : &'static str = "Goto mark";
+const MODE_RECORD   : &'static str = "Record   ";
+const MODE_REPLAY   : &'static str = "Replay   ";
+const MODE_OPERATOR : &'static str = "Operator ";
+const MODE_VISUAL   : &'static str = "Visual   ";
+const MODE_VLINES   : &'static str = "Lines    ";
+const MODE_PICK     : &'static str = "Pick     ";
+const MODE_EXPLORER : &'static str = "Explorer ";
+const MODE_PROMPT   : &'static str = "Prompt   ";
+const MODE_PANE     : &'static str = "Pane     ";
+const MODE_CMDLINE  : &'static str = "Cmdline  ";
 const MODE_EXIT     : &'static str = "Exit     ";
 
 impl Mode {
@@ -2060,6 +4917,19 @@ impl Mode {
             Insert(InsertMode::Replace)             => CONF.color_mode_replace,
             PendingInsert(InsertMode::Insert)       => CONF.color_mode_insert,
             PendingInsert(InsertMode::Replace)      => CONF.color_mode_replace,
+            MarkSet                                 => CONF.color_mode_command,
+            MarkGoto                                => CONF.color_mode_command,
+            MacroRecord                             => CONF.color_mode_command,
+            MacroReplay                             => CONF.color_mode_command,
+            Operator(..)                            => CONF.color_mode_command,
+            OperatorMark(..)                        => CONF.color_mode_command,
+            Object(..)                              => CONF.color_mode_command,
+            Visual(_)                               => CONF.color_mode_command,
+            Pick                                    => CONF.color_mode_command,
+            Explorer                                => CONF.color_mode_command,
+            Prompt                                  => CONF.color_mode_command,
+            Pane                                    => CONF.color_mode_command,
+            Cmdline                                 => CONF.color_mode_command,
             Exit                                    => CONF.color_mode_exit,
         }
     }
@@ -2072,6 +4942,21 @@ impl Mode {
             Insert(InsertMode::Replace)             => MODE_REPLACE,
             PendingInsert(InsertMode::Insert)       => MODE_PINSERT,
             PendingInsert(InsertMode::Replace)      => MODE_PREPLACE,
+            MarkSet                                 => MODE_MARKSET,
+            MarkGoto                                => MODE_MARKGOTO,
+            MacroRecord                             => MODE_RECORD,
+            MacroReplay                             => MODE_REPLAY,
+            Operator(..)                            => MODE_OPERATOR,
+            OperatorMark(..)                        => MODE_OPERATOR,
+            Object(Some(_), ..)                     => MODE_OPERATOR,
+            Object(None, ..)                        => MODE_VISUAL,
+            Visual(false)                           => MODE_VISUAL,
+            Visual(true)                            => MODE_VLINES,
+            Pick                                    => MODE_PICK,
+            Explorer                                => MODE_EXPLORER,
+            Prompt                                  => MODE_PROMPT,
+            Pane                                    => MODE_PANE,
+            Cmdline                                 => MODE_CMDLINE,
             Exit                                    => MODE_EXIT,
         }
     }
@@ -2087,97 +4972,491 @@ impl Mode {
             return Ok(m)
         }
 
+        if i == Input::Wakeup {
+            return Ok(m)
+        }
+
+        if let Input::Mouse(mouse) = i {
+            return Ok(e.mouse_input(m, mouse))
+        }
+
         use Mode::*;
         let next = match m {
             Command => {
-                let op = Mode::input_to_command_op(i, e);
-                let next = do_command(op, e)?;
+                e.message = None;
+                if let Input::Paste(ref text) = i {
+                    e.paste(text);
+                    e.view_update();
+                    return Ok(Command)
+                }
+                if let Input::Key(c @ '0' ..= '9') = i {
+                    if e.keys.is_empty() && (c != '0' || e.count > 0) {
+                        let digit = c as usize - '0' as usize;
+                        e.count = min(COUNT_MAX, e.count * 10 + digit);
+                        return Ok(Command)
+                    }
+                }
+                let op = match e.key_input(Keymode::Command, i) {
+                    Keylookup::Bound(command)   => (command.op)(e),
+                    Keylookup::Pending          => return Ok(Command),
+                    Keylookup::Unbound(keys)    => {
+                        // A started sequence which did not match is not dropped silently.
+                        if keys.len() > 1 {
+                            e.message = Some(format!("{} is not bound", keys_name(&keys)));
+                        }
+                        CommandOp::Noop
+                    }
+                };
+                let count = max(1, std::mem::replace(&mut e.count, 0));
+                let next = do_command(op, count, e)?;
                 // should this instead be managed per operation in a more scoped way ?
-                e.view.update(&e.buffer);
+                e.view_update();
                 next
             }
 
             Insert(mode) => {
-                let command = Mode::input_to_insert_op(mode, i, e);
-                do_buffer_command(command, e);
-                Insert(mode)
+                if let Input::Paste(ref text) = i {
+                    e.insert_ops.push(InsertOp::Paste(text.clone()));
+                    e.paste(text);
+                    e.view_update();
+                    return Ok(Insert(mode))
+                }
+                match e.key_input(Keymode::Insert, i) {
+                    Keylookup::Bound(command) => {
+                        let op = (command.op)(e);
+                        Mode::do_insert_command(op, mode, e)
+                    }
+                    Keylookup::Pending => Insert(mode),
+                    Keylookup::Unbound(keys) => {
+                        for k in keys {
+                            if let Input::Key(c) = k {
+                                let command = buffercommand(e.view.cursor, BufferOpType::InsertChar(c));
+                                Mode::do_insert_command(CommandOp::BufferOp(command), mode, e);
+                            }
+                        }
+                        Insert(mode)
+                    }
+                }
             }
 
             PendingInsert(mode) => {
-                // CHECKME: do I need an explicit snapshot here ?:w
-                //e.buffer.snapshot(e.view.cursor);
-                e.buffer.prepare_insert(usize(e.view.cursor.y));
+                // The whole insert session is one undo step, and one change for repeating.
+                e.buffers.buffer_mut(e.view.buffer_id).snapshot_take(e.view.cursor);
+                e.insert_ops.clear();
                 let insertmode = Insert(mode);
                 Mode::process_input(insertmode, i, e)?
             }
 
+            MarkSet => {
+                if let Input::Key(c) = i {
+                    e.mark_set(c);
+                }
+                Command
+            }
+
+            MarkGoto => {
+                if let Input::Key(c) = i {
+                    e.mark_goto(c);
+                }
+                e.view_update();
+                Command
+            }
+
+            MacroRecord => {
+                if let Input::Key(c) = i {
+                    e.macro_record(c);
+                }
+                Command
+            }
+
+            MacroReplay => {
+                let count = max(1, std::mem::replace(&mut e.count, 0));
+                match i {
+                    Input::Key(c)   => e.macro_replay(c, count)?,
+                    _               => Command,
+                }
+            }
+
+            Operator(operator, count) => {
+                let next = e.operator_input(operator, count, i);
+                e.view_update();
+                next
+            }
+
+            OperatorMark(operator, count) => {
+                let next = match i {
+                    Input::Key(c)   => e.operator_motion(operator, Motion::Mark(c), count),
+                    _               => Command,
+                };
+                e.view_update();
+                next
+            }
+
+            Object(operator, count, inner) => {
+                let object = match i {
+                    Input::Key(c)   => textobject(c),
+                    _               => None,
+                };
+                let next = match (operator, object) {
+                    (Some(operator), Some(object)) =>
+                        e.operator_motion(operator, Motion::Object(object, inner), count),
+                    (None, Some(object)) =>
+                        e.visual_object(object, inner, count),
+                    (Some(_), None) => Command,
+                    (None, None)    => Visual(false),
+                };
+                e.view_update();
+                next
+            }
+
+            Visual(linewise) => {
+                let next = e.visual_input(linewise, i);
+                e.view_update();
+                next
+            }
+
+            Pick => {
+                let next = e.picker_input(i);
+                e.view_update();
+                next
+            }
+
+            Explorer => {
+                let next = e.explorer_input(i);
+                e.view_update();
+                next
+            }
+
+            Prompt => {
+                e.prompt_input(i)
+            }
+
+            Pane => {
+                let next = e.pane_input(i);
+                e.view_update();
+                next
+            }
+
+            Cmdline => {
+                let next = e.cmdline_input(i);
+                e.view_update();
+                next
+            }
+
             Exit => {
                 panic!("cannot process input in Exit state")
             }
         };
 
-        // FUTURE: if the cursor moved position, update all other views of that file whose cursors is below lineno
-
         // TODO: instead of aborting, handle input error processing, and check if any dirty files
         // need saving !
 
         Ok(next)
     }
 
-    fn input_to_command_op(i: Input, e: &Editor) -> CommandOp {
-        use Input::*;
+    // Run a command bound in the Insert keymap.
+    fn do_insert_command(op: CommandOp, mode: InsertMode, e: &mut Editor) -> Mode {
         use CommandOp::*;
-        use BufferOpType::*;
-        match i {
-            // TODO: more sophisticated cursor movement ...
-            // TODO: handle mouse click
-            Key('h')    => BufferMove(MoveOp::Movement(Move::Left)),
-            Key('j')    => BufferMove(MoveOp::Movement(Move::Down)),
-            Key('k')    => BufferMove(MoveOp::Movement(Move::Up)),
-            Key('l')    => BufferMove(MoveOp::Movement(Move::Right)),
-            Key(' ')    => BufferMove(MoveOp::Recenter),
-            Key(CTRL_D) => BufferMove(MoveOp::PageDown),
-            Key(CTRL_U) => BufferMove(MoveOp::PageUp),
-            Key(CTRL_H) => BufferMove(MoveOp::FileStart),
-            Key(CTRL_L) => BufferMove(MoveOp::FileEnd),
-            Key('o')    => BufferOp(buffercommand(e.view.cursor + pos(0,1), LineNew)),
-            Key('O')    => BufferOp(buffercommand(e.view.cursor,            LineNew)),
-            Key('q')    => BufferOp(buffercommand(e.view.cursor,            LineJoin)),
-            Key(ENTER)  => BufferOp(buffercommand(e.view.cursor,            LineBreak)),
-            Key('d')    => BufferOp(buffercommand(e.view.cursor,            LineDel)),
-            Key('x')    => BufferOp(buffercommand(e.view.cursor,            CharDelete)),
-            Key(CTRL_X) => BufferOp(buffercommand(e.view.cursor,            CharBackspace)),
-            Key('u')    => BufferOp(buffercommand(e.view.cursor,            Undo)),
-            Key('r')    => BufferOp(buffercommand(e.view.cursor,            Redo)),
-            Key('\t')   => SwitchInsert,
-            Key(CTRL_R) => SwitchReplace,
-            Key('s')    => Save(format!("{}.tmp", e.view.filepath)),
-            Key('\\')   => ClearConsole,
-            //Key('b')
-            //            => panic!("BOOM !"),
-            //            //=> return er!("BOOM !"),
-            _           => CommandOp::Noop,
-        }
-    }
-
-    fn input_to_insert_op(mode: InsertMode, i: Input, e: &Editor) -> BufferCommand {
-        use Input::*;
-        use BufferOpType::*;
-        let optype = match i {
-            Key(ESC) | EscZ                 => SwitchCommand,
-            Key(ENTER)                      => LineBreak,
-            Key(TAB)                        => InsertChar(TAB),
-            Key(DEL)                        => CharBackspace,
-            Key(BACKSPACE)                  => CharDelete,
-            Key(c)                          => InsertChar(c),
-            _                               => Noop,
-        };
+        match op {
+            BufferOp(mut command) => {
+                command.mode = Some(mode);
+                e.insert_ops.push(InsertOp::Buffer(command.optype));
+                do_buffer_command(command, e);
+            }
+            BufferMove(m) => {
+                // Like in vim, only what is typed after the last movement gets repeated.
+                e.insert_ops.clear();
+                do_buffer_move(m, e);
+                e.view_update();
+            }
+            SwitchCommand => {
+                let ops = std::mem::replace(&mut e.insert_ops, Vec::new());
+                match e.change {
+                    // The text typed after a change operator is part of that change.
+                    Some(Change::Operator(::Operator::Change, _, _, ref mut typed @ None)) =>
+                        *typed = Some(ops),
+                    _ if !ops.is_empty() =>
+                        e.change = Some(Change::Insert(mode, ops)),
+                    _ => (),
+                }
+                return Mode::Command
+            }
+            _ => (),    // the Insert keymap only has insert commands
+        }
+        Mode::Insert(mode)
+    }
+}
+
+
+/* KEY BINDINGS */
+
+// The modes in which a named command can be bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Keymode {
+    Command,
+    Insert,
+    Any,
+}
+
+// A command that keys can be bound to in the config file.
+struct Keycommand {
+    name:   &'static str,
+    mode:   Keymode,
+    op:     fn(&Editor) -> CommandOp,
+}
+
+static KEYCOMMANDS : &[Keycommand] = &[
+    Keycommand { name: "move_left",         mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::Movement(Move::Left)) },
+    Keycommand { name: "move_down",         mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::Movement(Move::Down)) },
+    Keycommand { name: "move_up",           mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::Movement(Move::Up)) },
+    Keycommand { name: "move_right",        mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::Movement(Move::Right)) },
+    Keycommand { name: "recenter",          mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::Recenter) },
+    Keycommand { name: "page_down",         mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::PageDown) },
+    Keycommand { name: "page_up",           mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::PageUp) },
+    Keycommand { name: "file_start",        mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::FileStart) },
+    Keycommand { name: "file_end",          mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::FileEnd) },
+    Keycommand { name: "word_next",         mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::WordNext) },
+    Keycommand { name: "word_previous",     mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::WordPrev) },
+    Keycommand { name: "word_end",          mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::WordEnd) },
+    Keycommand { name: "paragraph_next",    mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::ParagraphNext) },
+    Keycommand { name: "paragraph_previous", mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::ParagraphPrev) },
+    Keycommand { name: "bracket_match",     mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::BracketMatch) },
+    Keycommand { name: "line_start",        mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::LineStart) },
+    Keycommand { name: "line_end",          mode: Keymode::Any,     op: |_| CommandOp::BufferMove(MoveOp::LineEnd) },
+    Keycommand { name: "search",            mode: Keymode::Command, op: |_| CommandOp::SwitchSearch },
+    Keycommand { name: "search_next",       mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::SearchNext) },
+    Keycommand { name: "search_previous",   mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::SearchPrev) },
+    Keycommand { name: "jump_back",         mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::JumpBack) },
+    Keycommand { name: "jump_forward",      mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::JumpForward) },
+    Keycommand { name: "line_new_below",    mode: Keymode::Command, op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor + pos(0,1), BufferOpType::LineNew)) },
+    Keycommand { name: "line_new_above",    mode: Keymode::Command, op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::LineNew)) },
+    Keycommand { name: "line_join",         mode: Keymode::Command, op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::LineJoin)) },
+    Keycommand { name: "line_break",        mode: Keymode::Any,     op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::LineBreak)) },
+    Keycommand { name: "line_delete",       mode: Keymode::Command, op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::LineDel)) },
+    Keycommand { name: "char_delete",       mode: Keymode::Any,     op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::CharDelete)) },
+    Keycommand { name: "char_backspace",    mode: Keymode::Any,     op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::CharBackspace)) },
+    Keycommand { name: "delete",            mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Delete) },
+    Keycommand { name: "change",            mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Change) },
+    Keycommand { name: "yank",              mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Yank) },
+    Keycommand { name: "indent",            mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Indent) },
+    Keycommand { name: "dedent",            mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Dedent) },
+    Keycommand { name: "lowercase",         mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Lowercase) },
+    Keycommand { name: "uppercase",         mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Uppercase) },
+    Keycommand { name: "togglecase",        mode: Keymode::Command, op: |_| CommandOp::Operator(Operator::Togglecase) },
+    Keycommand { name: "visual",            mode: Keymode::Command, op: |_| CommandOp::SwitchVisual(false) },
+    Keycommand { name: "visual_lines",      mode: Keymode::Command, op: |_| CommandOp::SwitchVisual(true) },
+    Keycommand { name: "put_after",         mode: Keymode::Command, op: |_| CommandOp::Put(false) },
+    Keycommand { name: "put_before",        mode: Keymode::Command, op: |_| CommandOp::Put(true) },
+    Keycommand { name: "repeat",            mode: Keymode::Command, op: |_| CommandOp::Repeat },
+    Keycommand { name: "undo",              mode: Keymode::Command, op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::Undo)) },
+    Keycommand { name: "redo",              mode: Keymode::Command, op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::Redo)) },
+    Keycommand { name: "insert",            mode: Keymode::Command, op: |_| CommandOp::SwitchInsert },
+    Keycommand { name: "replace",           mode: Keymode::Command, op: |_| CommandOp::SwitchReplace },
+    Keycommand { name: "insert_exit",       mode: Keymode::Insert,  op: |_| CommandOp::SwitchCommand },
+    Keycommand { name: "insert_tab",        mode: Keymode::Insert,  op: |e| CommandOp::BufferOp(buffercommand(e.view.cursor, BufferOpType::InsertChar(TAB))) },
+    Keycommand { name: "save_copy",         mode: Keymode::Command, op: |e| CommandOp::Save(format!("{}.tmp", e.buffers.get(e.view.buffer_id).filepath)) },
+    Keycommand { name: "buffer_next",       mode: Keymode::Command, op: |_| CommandOp::BufferSwitch(1) },
+    Keycommand { name: "buffer_previous",   mode: Keymode::Command, op: |_| CommandOp::BufferSwitch(-1) },
+    Keycommand { name: "buffer_close",      mode: Keymode::Command, op: |_| CommandOp::BufferClose },
+    Keycommand { name: "buffer_explorer",   mode: Keymode::Command, op: |_| CommandOp::BufferExplorer },
+    Keycommand { name: "directory_explorer", mode: Keymode::Command, op: |_| CommandOp::DirectoryExplorer },
+    Keycommand { name: "file_finder",       mode: Keymode::Command, op: |_| CommandOp::FileFinder },
+    Keycommand { name: "pane",              mode: Keymode::Command, op: |_| CommandOp::SwitchPane },
+    Keycommand { name: "cmdline",           mode: Keymode::Command, op: |_| CommandOp::SwitchCmdline },
+    Keycommand { name: "console_clear",     mode: Keymode::Command, op: |_| CommandOp::ClearConsole },
+    Keycommand { name: "mark_set",          mode: Keymode::Command, op: |_| CommandOp::SwitchMarkSet },
+    Keycommand { name: "mark_goto",         mode: Keymode::Command, op: |_| CommandOp::SwitchMarkGoto },
+    Keycommand { name: "macro_record",      mode: Keymode::Command, op: |_| CommandOp::MacroToggle },
+    Keycommand { name: "macro_replay",      mode: Keymode::Command, op: |_| CommandOp::SwitchMacroReplay },
+    Keycommand { name: "tag_goto",          mode: Keymode::Command, op: |_| CommandOp::TagGoto },
+    Keycommand { name: "tag_pop",           mode: Keymode::Command, op: |_| CommandOp::BufferMove(MoveOp::TagPop) },
+];
+
+// Default bindings of the Command keymap, as space separated key names and command names.
+static KEYS_COMMAND : &[(&str, &str)] = &[
+    ("h",           "move_left"),
+    ("j",           "move_down"),
+    ("k",           "move_up"),
+    ("l",           "move_right"),
+    ("Left",        "move_left"),
+    ("Down",        "move_down"),
+    ("Up",          "move_up"),
+    ("Right",       "move_right"),
+    ("Space",       "recenter"),
+    ("^D",          "page_down"),
+    ("^U",          "page_up"),
+    ("PgDn",        "page_down"),
+    ("PgUp",        "page_up"),
+    ("^H",          "file_start"),
+    ("^L",          "file_end"),
+    ("w",           "word_next"),
+    ("B",           "word_previous"),
+    ("e",           "word_end"),
+    ("}",           "paragraph_next"),
+    ("{",           "paragraph_previous"),
+    ("%",           "bracket_match"),
+    ("0",           "line_start"),
+    ("$",           "line_end"),
+    ("Home",        "line_start"),
+    ("End",         "line_end"),
+    ("/",           "search"),
+    ("n",           "search_next"),
+    ("N",           "search_previous"),
+    ("^O",          "jump_back"),
+    ("^P",          "jump_forward"),
+    ("o",           "line_new_below"),
+    ("O",           "line_new_above"),
+    ("q",           "line_join"),
+    ("Enter",       "line_break"),
+    ("d",           "delete"),
+    ("c",           "change"),
+    ("y",           "yank"),
+    (">",           "indent"),
+    ("<",           "dedent"),
+    ("g u",         "lowercase"),
+    ("g U",         "uppercase"),
+    ("g ~",         "togglecase"),
+    ("v",           "visual"),
+    ("V",           "visual_lines"),
+    ("p",           "put_after"),
+    ("P",           "put_before"),
+    ("x",           "char_delete"),
+    ("Delete",      "char_delete"),
+    ("^X",          "char_backspace"),
+    (".",           "repeat"),
+    ("u",           "undo"),
+    ("r",           "redo"),
+    ("TAB",         "insert"),
+    ("Insert",      "insert"),
+    ("^R",          "replace"),
+    ("s",           "save_copy"),
+    ("^N",          "buffer_next"),
+    ("^B",          "buffer_previous"),
+    ("Q",           "buffer_close"),
+    ("b",           "buffer_explorer"),
+    ("E",           "directory_explorer"),
+    ("^F",          "file_finder"),
+    ("^W",          "pane"),
+    (":",           "cmdline"),
+    ("\\",          "console_clear"),
+    ("m",           "mark_set"),
+    ("'",           "mark_goto"),
+    ("M",           "macro_record"),
+    ("@",           "macro_replay"),
+    ("^]",          "tag_goto"),
+    ("^T",          "tag_pop"),
+];
+
+// Default bindings of the Insert keymap. Keys without binding insert themselves.
+static KEYS_INSERT : &[(&str, &str)] = &[
+    ("Esc",         "insert_exit"),
+    ("EscZ",        "insert_exit"),
+    ("Enter",       "line_break"),
+    ("TAB",         "insert_tab"),
+    ("Del",         "char_backspace"),
+    ("Backspace",   "char_delete"),
+    ("Delete",      "char_delete"),
+    ("Left",        "move_left"),
+    ("Down",        "move_down"),
+    ("Up",          "move_up"),
+    ("Right",       "move_right"),
+    ("PgDn",        "page_down"),
+    ("PgUp",        "page_up"),
+    ("Home",        "line_start"),
+    ("End",         "line_end"),
+];
+
+fn keycommand_find(name: &str) -> Option<&'static Keycommand> {
+    KEYCOMMANDS.iter().find(|c| c.name == name)
+}
+
+fn keys_name(keys: &[Input]) -> String {
+    keys.iter().map(|k| k.to_string()).collect::<Vec<String>>().join(" ")
+}
+
+// Key sequences bound to commands, for one mode.
+struct Keymap {
+    bindings: Vec<(Vec<Input>, &'static Keycommand)>,
+}
+
+enum Keylookup {
+    Bound(&'static Keycommand),
+    Pending,                // the keys are the start of longer bindings
+    Unbound(Vec<Input>),
+}
+
+impl Keymap {
+    // Apply the bindings of the config file over the default bindings. A binding replaces the
+    // bindings with the same keys, or with keys starting the other binding keys, which could never
+    // be reached otherwise. Unknown commands and config bindings replacing each other this way are
+    // returned as error messages, default bindings replaced this way are logged.
+    fn mk_keymap(mode: Keymode, defaults: &[(&str, &str)], config: &[Keybinding]) -> (Keymap, Vec<String>) {
+        let mut bindings = Vec::new();
+        for &(keys, name) in defaults {
+            let keys = keys.split(' ').map(|k| Input::parse_key(k).unwrap()).collect();
+            bindings.push((keys, keycommand_find(name).unwrap()));
+        }
+        let mut keymap = Keymap { bindings };
+
+        let mut errors = Vec::new();
+        for (i, b) in config.iter().enumerate() {
+            let command = match keycommand_find(&b.command) {
+                _ if b.command == "none"                => None,
+                Some(c) if c.mode == mode || c.mode == Keymode::Any => Some(c),
+                Some(_) => {
+                    errors.push(format!("{}: command '{}' cannot be bound in {:?} mode", b.origin, b.command, mode));
+                    continue
+                }
+                None => {
+                    errors.push(format!("{}: unknown command '{}'", b.origin, b.command));
+                    continue
+                }
+            };
+            let overlaps = |keys: &Vec<Input>| keys.starts_with(&b.keys) || b.keys.starts_with(keys);
+            for earlier in &config[..i] {
+                if overlaps(&earlier.keys) && keycommand_find(&earlier.command).is_some() {
+                    errors.push(format!("{}: '{}' conflicts with '{}' bound to {} at {}",
+                                        b.origin, keys_name(&b.keys), keys_name(&earlier.keys),
+                                        earlier.command, earlier.origin));
+                }
+            }
+            for (keys, c) in keymap.bindings.iter().filter(|(keys, _)| overlaps(keys)) {
+                if !config[..i].iter().any(|earlier| &earlier.keys == keys) {
+                    logconsole(&format!("{}: '{}' replaces the default binding of '{}' to {}",
+                                        b.origin, keys_name(&b.keys), keys_name(keys), c.name));
+                }
+            }
+            keymap.bindings.retain(|(keys, _)| !overlaps(keys));
+            if let Some(c) = command {
+                keymap.bindings.push((b.keys.clone(), c));
+            }
+        }
+
+        (keymap, errors)
+    }
 
-        BufferCommand {
-            cursor: e.view.cursor,
-            optype,
-            mode: Some(mode),
+    fn lookup(&self, keys: &[Input]) -> Keylookup {
+        if let Some(&(_, c)) = self.bindings.iter().find(|(k, _)| k.as_slice() == keys) {
+            return Keylookup::Bound(c)
         }
+        if self.bindings.iter().any(|(k, _)| k.starts_with(keys)) {
+            return Keylookup::Pending
+        }
+        Keylookup::Unbound(keys.to_vec())
+    }
+}
+
+struct Keymaps {
+    command:    Keymap,
+    insert:     Keymap,
+}
+
+impl Keymaps {
+    fn load() -> (Keymaps, Vec<String>) {
+        let (command, mut errors) = Keymap::mk_keymap(Keymode::Command, KEYS_COMMAND, &CONF.keymap_command);
+        let (insert, insert_errors) = Keymap::mk_keymap(Keymode::Insert, KEYS_INSERT, &CONF.keymap_insert);
+        errors.extend(insert_errors);
+        (Keymaps { command, insert }, errors)
     }
 }
 
@@ -2185,13 +5464,36 @@ impl Mode {
 enum CommandOp {
     BufferOp(BufferCommand),
     BufferMove(MoveOp),
+    BufferSwitch(i32),  // go to the next or previous loaded file
+    BufferClose,
+    BufferExplorer,
+    DirectoryExplorer,
+    FileFinder,
     Save(String),
+    Open(String),
+    Quit(bool),         // exit the editor, even with unsaved changes if set
+    Set(String, String),
+    GotoLine(i32),
     SwitchInsert,
     SwitchReplace,
+    SwitchCommand,      // leave Insert mode
+    SwitchMarkSet,
+    SwitchMarkGoto,
+    SwitchMacroReplay,
+    Repeat,             // do the last change again
+    Operator(Operator), // wait for a motion and apply the operator to the text it covers
+    SwitchSearch,
+    SwitchVisual(bool), // select characters, or whole lines if set
+    Put(bool),          // insert the last yanked or deleted text after the cursor, or before if set
+    MacroToggle,        // start recording, or stop the current recording
+    SwitchPane,
+    SwitchCmdline,
+    TagGoto,
     ClearConsole,
     Noop,
 }
 
+#[derive(Debug, Clone, Copy)]
 enum MoveOp {
     Movement(Move),
     Recenter,
@@ -2199,6 +5501,94 @@ enum MoveOp {
     PageDown,
     FileStart,
     FileEnd,
+    JumpBack,
+    JumpForward,
+    TagPop,
+    WordNext,
+    WordPrev,
+    WordEnd,
+    ParagraphNext,
+    ParagraphPrev,
+    BracketMatch,
+    LineStart,
+    LineEnd,
+    SearchNext,
+    SearchPrev,
+}
+
+// The last change done in Command mode or in an insert session.
+#[derive(Debug, Clone)]
+enum Change {
+    Edit(Pos, BufferOpType),                // a buffer op, at an offset from the cursor
+    Insert(InsertMode, Vec<InsertOp>),      // the ops typed during an insert session
+    // An operator with its motion and count, and the ops typed after a change operator once its
+    // insert session is over.
+    Operator(Operator, Motion, usize, Option<Vec<InsertOp>>),
+}
+
+// What happened during an insert session, for repeating it.
+#[derive(Debug, Clone)]
+enum InsertOp {
+    Buffer(BufferOpType),
+    Paste(String),
+}
+
+// Operators act on the text covered by the motion following them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+    Indent,
+    Dedent,
+    Lowercase,
+    Uppercase,
+    Togglecase,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Motion {
+    Lines,          // the operator typed twice: whole lines from the cursor line
+    Move(MoveOp),
+    Mark(char),     // lines from the cursor line to the line of a mark
+    Object(Textobject, bool),   // a text object around the cursor, inner if set
+}
+
+// Text objects keys, after 'i' for inner objects or 'a' for whole objects.
+fn textobject(c: char) -> Option<Textobject> {
+    let object = match c {
+        'w'                 => Textobject::Word,
+        '"' | '\'' | '`'    => Textobject::Quote(c as u8),
+        '(' | ')' | 'b'     => Textobject::Block(b'(', b')'),
+        '[' | ']'           => Textobject::Block(b'[', b']'),
+        '{' | '}' | 'B'     => Textobject::Block(b'{', b'}'),
+        'p'                 => Textobject::Paragraph,
+        'i'                 => Textobject::Indent,
+        _                   => return None,
+    };
+    Some(object)
+}
+
+// How much of the text between the cursor and the target of a motion the motion covers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Motionkind {
+    Exclusive,
+    Inclusive,
+    Lines,
+}
+
+// Text from 'start' to 'stop' excluded, or whole lines from start.y to stop.y included.
+#[derive(Debug, Clone, Copy)]
+struct Textrange {
+    start:      Pos,
+    stop:       Pos,
+    linewise:   bool,
+}
+
+// The last yanked or deleted text. Linewise text ends with a line break.
+struct Yank {
+    text:       Vec<u8>,
+    linewise:   bool,
 }
 
 fn buffercommand(cursor: Pos, optype: BufferOpType) -> BufferCommand {
@@ -2224,7 +5614,6 @@ enum BufferOpType {
     Redo,
     // Insert specific
     InsertChar(char),
-    SwitchCommand,      // TODO: get rid of me !
     Noop,               // TODO: get rid of me ? Or at least shortcut earlier
 }
 
@@ -2236,7 +5625,7 @@ struct Cursor<'a> {
 // Store states related to navigation in a given file.
 // All positions are in text coordinate.
 struct View {
-    filepath:           String,
+    buffer_id:          usize,
     relative_lineno:    bool,
     movement_mode:      MovementMode,
     show_token:         bool,
@@ -2244,15 +5633,94 @@ struct View {
     show_selection:     bool,
     is_active:          bool,
     cursor:             Pos,
-    cursor_memory:      Pos,
+    cursor_memory:      Pos,        // desired cursor position for vertical movements
+    anchor:             Pos,        // the other end of the selection in Visual mode
     filearea:           Rec,
+    jumps:              Jumplist,
+    tagstack:           Vec<Jump>,  // positions before following a tag
     //selection:  Option<&[Selection]>
 }
 
+// A position in a file recorded before a large cursor movement.
+#[derive(Debug, Clone, PartialEq)]
+struct Jump {
+    filepath:   String,
+    cursor:     Pos,
+}
+
+impl Jump {
+    // Move the jump with the line it points to if it is inside 'filepath'. Jumps pointing to deleted
+    // lines stay on the same line number and get clamped when used.
+    fn adjust(&mut self, filepath: &str, shifts: &[Lineshift]) {
+        if self.filepath != filepath {
+            return
+        }
+        for s in shifts {
+            self.cursor = s.adjust(self.cursor).unwrap_or(self.cursor);
+        }
+    }
+}
+
+// The history of large cursor movements of a View, navigable backward and forward.
+struct Jumplist {
+    jumps:      Vec<Jump>,
+    cursor:     usize,      // index of the current jump, equal to jumps.len() when not navigating
+}
+
+const JUMPLIST_MAX : usize = 100;
+
+impl Jumplist {
+    fn mk_jumplist() -> Jumplist {
+        Jumplist {
+            jumps:  Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    // Record a new jump origin, forgetting any jump ahead of the current one.
+    fn push(&mut self, jump: Jump) {
+        self.jumps.truncate(self.cursor);
+        if self.jumps.last() != Some(&jump) {
+            self.jumps.push(jump);
+        }
+        if self.jumps.len() > JUMPLIST_MAX {
+            self.jumps.remove(0);
+        }
+        self.cursor = self.jumps.len();
+    }
+
+    // Go to the previous jump. When starting to navigate the list, 'current' is remembered so that
+    // it can be returned to with forward().
+    fn back(&mut self, current: Jump) -> Option<Jump> {
+        if self.cursor == 0 {
+            return None
+        }
+        if self.cursor == self.jumps.len() && self.jumps.last() != Some(&current) {
+            self.jumps.push(current);
+        }
+        self.cursor -= 1;
+        Some(self.jumps[self.cursor].clone())
+    }
+
+    fn forward(&mut self) -> Option<Jump> {
+        if self.cursor + 1 >= self.jumps.len() {
+            return None
+        }
+        self.cursor += 1;
+        Some(self.jumps[self.cursor].clone())
+    }
+
+    fn adjust(&mut self, filepath: &str, shifts: &[Lineshift]) {
+        for j in self.jumps.iter_mut() {
+            j.adjust(filepath, shifts);
+        }
+    }
+}
+
 impl View {
-    fn mk_fileview(filepath: String, screensize: Pos) -> View {
+    fn mk_fileview(buffer_id: usize, screensize: Pos) -> View {
         View {
-            filepath,
+            buffer_id,
             relative_lineno:    CONF.relative_lineno,
             movement_mode:      MovementMode::Chars,
             show_token:         false,
@@ -2261,7 +5729,10 @@ impl View {
             is_active:          true,
             cursor:             pos(0,0),
             cursor_memory:      pos(0,0),
+            anchor:             pos(0,0),
             filearea:           screensize.rec(),
+            jumps:              Jumplist::mk_jumplist(),
+            tagstack:           Vec::new(),
         }
     }
 
@@ -2277,6 +5748,21 @@ impl View {
         p
     }
 
+    // Move the cursor and remember its column as the desired column for vertical movements.
+    fn cursor_set(&mut self, buffer: &Buffer, p: Pos) {
+        self.cursor = p;
+        if buffer.nlines() > 0 {
+            self.cursor = View::cursor_adjust(buffer, p);
+        }
+        self.cursor_memory = self.cursor;
+    }
+
+    // Move the cursor to another line at the desired column. The real cursor column gets clamped
+    // by update() but the desired column is kept for the next vertical movement.
+    fn cursor_goto_line(&mut self, y: i32) {
+        self.cursor = pos(self.cursor_memory.x, y);
+    }
+
     // CHECKME: for cursor_next/prev, do I need to skip empty lines ?
 
     fn cursor_next(buffer: &Buffer, p: Pos) -> Pos {
@@ -2302,322 +5788,3095 @@ impl View {
             return pos(x, y)
         }
 
-        p // Hit beggining of file
+        p // Hit beggining of file
+    }
+
+    fn update(&mut self, buffer: &Buffer) {
+        if buffer.nlines() == 0 {
+            return;
+        }
+
+        self.cursor = View::cursor_adjust(buffer, self.cursor);
+
+        // text range adjustment
+        {
+            let p = self.cursor;
+
+            let mut dx = 0;
+            let mut dy = 0;
+
+            if p.y < self.filearea.min.y {
+                dy = p.y - self.filearea.min.y;
+            }
+            if self.filearea.max.y <= p.y {
+                dy = p.y + 1 - self.filearea.max.y;
+            }
+            if p.x < self.filearea.min.x {
+                dx = p.x - self.filearea.min.x;
+            }
+            if self.filearea.max.x <= p.x {
+                dx = p.x + 1 - self.filearea.max.x;
+            }
+
+            self.filearea = self.filearea + pos(dx, dy);
+        }
+    }
+
+    fn recenter(&mut self, _buffer: &Buffer) {
+        let size = self.filearea.size();
+        let y = max(0, self.cursor.y - size.y / 2);
+        // TODO: consider horizontal recenter too
+        self.filearea = pos(0, y).extrude(size);
+    }
+
+    fn go_page_down(&mut self, buffer: &Buffer) {
+        let y = min(buffer.nlines() - 1, self.cursor.y + 50);
+        self.cursor_goto_line(y);
+    }
+
+    fn go_page_up(&mut self, _buffer: &Buffer) {
+        let y = max(0, self.cursor.y - 50);
+        self.cursor_goto_line(y);
+    }
+
+    fn go_file_start(&mut self, _buffer: &Buffer) {
+        self.cursor_goto_line(0);
+    }
+
+    fn go_file_end(&mut self, buffer: &Buffer) {
+        self.cursor_goto_line(buffer.nlines() - 1);
+    }
+
+    // Move the text shown by 'dy' lines, and keep the cursor on a line shown.
+    fn scroll(&mut self, buffer: &Buffer, dy: i32) {
+        let top = max(0, min(buffer.nlines() - 1, self.filearea.min.y + dy));
+        self.filearea = self.filearea + pos(0, top - self.filearea.min.y);
+        let y = max(top, min(top + self.filearea.h() - 1, self.cursor.y));
+        self.cursor_goto_line(y);
+        self.update(buffer);
+    }
+
+    // A new view of the same buffer at the same position, with an empty history.
+    fn duplicate(&self) -> View {
+        let mut view = View::mk_fileview(self.buffer_id, self.filearea.size());
+        view.relative_lineno = self.relative_lineno;
+        view.cursor = self.cursor;
+        view.cursor_memory = self.cursor_memory;
+        view.filearea = self.filearea;
+        view
+    }
+
+    // Show another buffer at the last cursor and scroll position used for that buffer.
+    fn buffer_switch(&mut self, entry: &Bufferentry) {
+        self.buffer_id = entry.id;
+        self.cursor = entry.cursor;
+        self.cursor_memory = entry.cursor;
+        self.filearea = entry.filearea.min.extrude(self.filearea.size());
+    }
+}
+
+
+// A View and the Screen it is drawn in, when not focused.
+struct Pane {
+    id:         usize,
+    view:       View,
+    screen:     Screen,
+}
+
+// A layout of panes, when not shown. The shown tab page is held by the Editor directly.
+struct Tabpage {
+    name:       String,
+    pane_id:    usize,
+    view:       View,
+    screen:     Screen,
+    panes:      Vec<Pane>,
+    layout:     Layout,
+}
+
+// All views of 'panes' and 'tabs', for updating views other than the focused one.
+fn views_other<'a>(panes: &'a mut [Pane], tabs: &'a mut [Tabpage]) -> Vec<&'a mut View> {
+    let mut views : Vec<&mut View> = panes.iter_mut().map(|p| &mut p.view).collect();
+    for tab in tabs.iter_mut() {
+        views.push(&mut tab.view);
+        views.extend(tab.panes.iter_mut().map(|p| &mut p.view));
+    }
+    views
+}
+
+// How the main screen is shared between panes. Splits divide their area in two halves.
+enum Layout {
+    Pane(usize),                        // id of a pane
+    Vsplit(Box<Layout>, Box<Layout>),   // one pane above the other
+    Hsplit(Box<Layout>, Box<Layout>),   // panes side by side
+}
+
+impl Layout {
+    // Collect the area of every pane inside 'area', in screen order.
+    fn areas(&self, area: Rec, out: &mut Vec<(usize, Rec)>) {
+        match *self {
+            Layout::Pane(id) => out.push((id, area)),
+            Layout::Vsplit(ref up, ref down) => {
+                let (a, b) = area.vsplit(area.y0() + area.h() / 2);
+                up.areas(a, out);
+                down.areas(b, out);
+            }
+            Layout::Hsplit(ref left, ref right) => {
+                let (a, b) = area.hsplit(area.x0() + area.w() / 2);
+                left.areas(a, out);
+                right.areas(b, out);
+            }
+        }
+    }
+
+    // Divide the area of pane 'id' between that pane and pane 'new_id'.
+    fn split(&mut self, id: usize, new_id: usize, stacked: bool) {
+        let found = match *self {
+            Layout::Pane(i) => i == id,
+            Layout::Vsplit(ref mut a, ref mut b) | Layout::Hsplit(ref mut a, ref mut b) => {
+                a.split(id, new_id, stacked);
+                b.split(id, new_id, stacked);
+                false
+            }
+        };
+        if found {
+            let (a, b) = (Box::new(Layout::Pane(id)), Box::new(Layout::Pane(new_id)));
+            *self = if stacked { Layout::Vsplit(a, b) } else { Layout::Hsplit(a, b) };
+        }
+    }
+
+    // Collect the id of every pane, in screen order.
+    fn ids(&self, out: &mut Vec<usize>) {
+        match *self {
+            Layout::Pane(id) => out.push(id),
+            Layout::Vsplit(ref a, ref b) | Layout::Hsplit(ref a, ref b) => {
+                a.ids(out);
+                b.ids(out);
+            }
+        }
+    }
+
+    // Write the split tree in prefix notation, with panes replaced by their index in 'ids'.
+    fn encode(&self, ids: &[usize]) -> String {
+        match *self {
+            Layout::Pane(id) =>
+                ids.iter().position(|&i| i == id).unwrap_or(0).to_string(),
+            Layout::Vsplit(ref a, ref b) =>
+                format!("V {} {}", a.encode(ids), b.encode(ids)),
+            Layout::Hsplit(ref a, ref b) =>
+                format!("H {} {}", a.encode(ids), b.encode(ids)),
+        }
+    }
+
+    // Read a split tree written by encode(), replacing pane indexes with the ids in 'ids'.
+    fn decode(tokens: &mut std::str::SplitWhitespace, ids: &[usize]) -> Option<Layout> {
+        match tokens.next()? {
+            "V" => {
+                let a = Layout::decode(tokens, ids)?;
+                let b = Layout::decode(tokens, ids)?;
+                Some(Layout::Vsplit(Box::new(a), Box::new(b)))
+            }
+            "H" => {
+                let a = Layout::decode(tokens, ids)?;
+                let b = Layout::decode(tokens, ids)?;
+                Some(Layout::Hsplit(Box::new(a), Box::new(b)))
+            }
+            n => ids.get(n.parse::<usize>().ok()?).map(|&id| Layout::Pane(id)),
+        }
+    }
+
+    // Take out pane 'id', giving its area to the other side of its split.
+    fn remove(self, id: usize) -> Option<Layout> {
+        match self {
+            Layout::Pane(i) if i == id => None,
+            Layout::Pane(i) => Some(Layout::Pane(i)),
+            Layout::Vsplit(a, b) => match (a.remove(id), b.remove(id)) {
+                (Some(a), Some(b))  => Some(Layout::Vsplit(Box::new(a), Box::new(b))),
+                (a, b)              => a.or(b),
+            },
+            Layout::Hsplit(a, b) => match (a.remove(id), b.remove(id)) {
+                (Some(a), Some(b))  => Some(Layout::Hsplit(Box::new(a), Box::new(b))),
+                (a, b)              => a.or(b),
+            },
+        }
+    }
+}
+
+
+// A file loaded in the editor, with the last cursor position used for that file.
+struct Bufferentry {
+    id:                 usize,
+    filepath:           String,     // empty for a buffer not associated to a file yet
+    buffer:             Buffer,
+    cursor:             Pos,
+    filearea:           Rec,
+    readonly:           bool,
+}
+
+impl Bufferentry {
+    fn header(&self, view: &View) -> String {
+        format!("{}{}{} {:?}",
+                if self.filepath.is_empty() { "[no name]" } else { &self.filepath },
+                if self.buffer.dirty { "+" } else { " " },
+                if self.readonly { " [RO]" } else { "" },
+                view.movement_mode)
+    }
+}
+
+// All files loaded in the editor. Entries are referred to by id, which stay valid when other
+// entries are closed.
+struct Bufferlist {
+    entries:            Vec<Bufferentry>,
+    next_id:            usize,
+}
+
+impl Bufferlist {
+    fn mk_bufferlist() -> Bufferlist {
+        Bufferlist {
+            entries:    Vec::new(),
+            next_id:    0,
+        }
+    }
+
+    // Load a file, or return the id of the entry if that file is already loaded.
+    fn open(&mut self, filepath: &str, filearea: Rec) -> Re<usize> {
+        if let Some(id) = self.find(filepath) {
+            return Ok(id)
+        }
+        let buffer = Buffer::from_file(filepath)?;
+        Ok(self.push(filepath, buffer, filearea))
+    }
+
+    // Like open(), but a file which does not exist gets an empty buffer. That file is created when
+    // the buffer is saved.
+    fn open_or_new(&mut self, filepath: &str, filearea: Rec) -> Re<usize> {
+        if Path::new(filepath).exists() {
+            return self.open(filepath, filearea)
+        }
+        if let Some(id) = self.find(filepath) {
+            return Ok(id)
+        }
+        Ok(self.push(filepath, Buffer::from_text(Vec::new()), filearea))
+    }
+
+    // An empty buffer without a file.
+    fn open_scratch(&mut self, filearea: Rec) -> usize {
+        self.push("", Buffer::from_text(Vec::new()), filearea)
+    }
+
+    fn find(&self, filepath: &str) -> Option<usize> {
+        let canonical = ioutil::canonical_path(filepath);
+        self.entries.iter()
+            .find(|e| !e.filepath.is_empty() && ioutil::canonical_path(&e.filepath) == canonical)
+            .map(|e| e.id)
+    }
+
+    fn push(&mut self, filepath: &str, buffer: Buffer, filearea: Rec) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.push(Bufferentry {
+            id,
+            filepath:   filepath.to_string(),
+            buffer,
+            cursor:     pos(0,0),
+            filearea,
+            readonly:   false,
+        });
+        id
+    }
+
+    // Unload a file, refusing to lose unsaved changes unless 'force' is set.
+    fn close(&mut self, id: usize, force: bool) -> Re<()> {
+        let idx = self.index(id);
+        if self.entries[idx].buffer.dirty && !force {
+            return er!(format!("{} has unsaved changes", self.entries[idx].filepath))
+        }
+        self.entries.remove(idx);
+        Ok(())
+    }
+
+    fn index(&self, id: usize) -> usize {
+        match self.entries.iter().position(|e| e.id == id) {
+            Some(idx)   => idx,
+            None        => panic!("no buffer with id {}", id),
+        }
+    }
+
+    fn get(&self, id: usize) -> &Bufferentry {
+        let idx = self.index(id);
+        &self.entries[idx]
+    }
+
+    fn get_mut(&mut self, id: usize) -> &mut Bufferentry {
+        let idx = self.index(id);
+        &mut self.entries[idx]
+    }
+
+    fn buffer(&self, id: usize) -> &Buffer {
+        &self.get(id).buffer
+    }
+
+    fn buffer_mut(&mut self, id: usize) -> &mut Buffer {
+        &mut self.get_mut(id).buffer
+    }
+
+    // Id of the entry 'offset' positions away from entry 'id', wrapping around.
+    fn cycle(&self, id: usize, offset: i32) -> usize {
+        let n = i32(self.entries.len());
+        let idx = i32(self.index(id));
+        let next = ((idx + offset) % n + n) % n;
+        self.entries[usize(next)].id
+    }
+
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+
+// A popup list of entries for choosing one of them, narrowed down by a typed filter.
+struct Picker {
+    title:      String,
+    entries:    Vec<String>,
+    filter:     String,
+    matches:    Vec<usize>, // indexes of entries matching the filter
+    selected:   usize,      // index into matches
+    action:     Pickaction,
+}
+
+// What to do with the chosen entry, one variant per kind of Picker. Variants hold the data of
+// each entry, in the same order as the entries.
+enum Pickaction {
+    Tag(Vec<tags::Tag>),
+    Buffer(Vec<usize>),
+    File(PathBuf),      // entries are paths relative to that directory, matched by the Finder
+}
+
+impl Picker {
+    fn mk_picker(title: String, entries: Vec<String>, action: Pickaction) -> Picker {
+        let matches = (0..entries.len()).collect();
+        Picker {
+            title,
+            entries,
+            filter:     String::new(),
+            matches,
+            selected:   0,
+            action,
+        }
+    }
+
+    // Index of the selected entry, if any entry matches the filter.
+    fn chosen(&self) -> Option<usize> {
+        self.matches.get(self.selected).cloned()
+    }
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.matches.len() {
+            self.selected += 1;
+        }
+    }
+
+    fn select_prev(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+        }
+    }
+
+    fn filter_push(&mut self, c: char) {
+        self.filter.push(c);
+        self.filter_update();
+    }
+
+    fn filter_pop(&mut self) {
+        self.filter.pop();
+        self.filter_update();
+    }
+
+    fn filter_set(&mut self, filter: String) {
+        self.filter = filter;
+        self.filter_update();
+    }
+
+    // Entries of Pickers with their own matching are already filtered.
+    fn is_prefiltered(&self) -> bool {
+        matches!(self.action, Pickaction::File(_))
+    }
+
+    fn entries_set(&mut self, entries: Vec<String>) {
+        self.entries = entries;
+        self.filter_update();
+    }
+
+    fn filter_update(&mut self) {
+        if self.is_prefiltered() {
+            self.matches = (0..self.entries.len()).collect();
+            self.selected = min(self.selected, max(1, self.matches.len()) - 1);
+            return
+        }
+        let filter = self.filter.to_lowercase();
+        self.matches = (0..self.entries.len()).filter(|&i| {
+            self.entries[i].to_lowercase().contains(&filter)
+        }).collect();
+        self.selected = min(self.selected, max(1, self.matches.len()) - 1);
+    }
+
+    fn put(&self, framebuffer: &mut Framebuffer, area: Rec) {
+        let title = format!("{}> {}", self.title, self.filter);
+        let entries : Vec<&str> = self.matches.iter().map(|&i| self.entries[i].as_str()).collect();
+        let width = self.entries.iter().map(|e| e.len()).max().unwrap_or(0);
+        let mut size = pos(max(i32(width) + 2, 40), max(i32(self.entries.len()), 1) + 1);
+        if self.is_prefiltered() {
+            // Keep the popup steady while results come and go.
+            size = pos(max(size.x, 80), 21);
+        }
+        let popupinfo = Popupinfo {
+            title:      &title,
+            entries:    &entries,
+            selected:   self.selected,
+            is_active:  true,
+        };
+        Popup::mk_popup(area, size).put_entries(framebuffer, &popupinfo);
+    }
+}
+
+
+// The directory explorer panel, on the left of the main screen.
+struct Explorer {
+    tree:       explore::Tree,
+    selected:   usize,
+}
+
+const EXPLORER_WIDTH : i32 = 32;
+
+impl Explorer {
+    fn selected_node(&self) -> Option<&explore::Node> {
+        self.tree.nodes.get(self.selected)
+    }
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.tree.nodes.len() {
+            self.selected += 1;
+        }
+    }
+
+    fn select_prev(&mut self) {
+        if self.selected > 0 {
+            self.selected -= 1;
+        }
+    }
+
+    // Directory where new files should go: the selected directory if expanded, otherwise the
+    // directory of the selected entry.
+    fn target_dir(&self) -> PathBuf {
+        match self.selected_node() {
+            Some(node) if node.expanded => node.entry.path.clone(),
+            Some(_)                     => self.tree.parent_dir(self.selected),
+            None                        => self.tree.root.clone(),
+        }
+    }
+
+    fn refresh(&mut self, dir: &Path) {
+        if let Err(err) = self.tree.refresh(dir) {
+            logconsole(&format!("cannot list {}: {}", dir.display(), err));
+        }
+        self.selected = min(self.selected, max(1, self.tree.nodes.len()) - 1);
+    }
+
+    fn put(&self, framebuffer: &mut Framebuffer, area: Rec, is_active: bool) {
+        let width = usize(area.w()) - 1;
+        let entries : Vec<String> = self.tree.nodes.iter().map(|node| {
+            let indent = "  ".repeat(node.depth);
+            if node.entry.is_dir {
+                let marker = if node.expanded { "-" } else { "+" };
+                format!("{}{} {}/", indent, marker, node.entry.name)
+            } else {
+                let size = explore::size_human(node.entry.size);
+                let name = format!("{}  {}", indent, node.entry.name);
+                let padding = width.saturating_sub(name.len() + size.len() + 2);
+                format!("{}{} {}", name, " ".repeat(padding), size)
+            }
+        }).collect();
+        let entries : Vec<&str> = entries.iter().map(|e| e.as_str()).collect();
+        let title = self.tree.root.to_string_lossy();
+        let popupinfo = Popupinfo {
+            title:      &title,
+            entries:    &entries,
+            selected:   self.selected,
+            is_active,
+        };
+        Popup::mk_panel(area).put_entries(framebuffer, &popupinfo);
+    }
+}
+
+
+// A line of text input in the footer.
+struct Prompt {
+    message:    String,
+    input:      String,
+    action:     Promptaction,
+    mode:       Mode,       // mode to go back to once the prompt is done
+}
+
+// What to do with the input of a Prompt.
+enum Promptaction {
+    TabNew,
+    TabRename,
+    Create(PathBuf),        // create a file, or a directory if the input ends with '/', in that directory
+    Rename(PathBuf),        // rename that file or directory to the input
+    Confirm(Fileop),        // do the op if the input is 'y'
+    Search(Option<(Operator, usize)>),  // go to the next occurrence of the input, or apply that operator
+                                        // and count up to there
+}
+
+enum Fileop {
+    Create(PathBuf, bool),  // path, is_dir
+    Rename(PathBuf, PathBuf),
+    Delete(PathBuf, bool),  // path, is_dir
+}
+
+impl Prompt {
+    fn mk_prompt(message: String, input: String, action: Promptaction, mode: Mode) -> Prompt {
+        Prompt {
+            message,
+            input,
+            action,
+            mode,
+        }
+    }
+
+    fn mk_confirm(op: Fileop, mode: Mode) -> Prompt {
+        let message = match op {
+            Fileop::Create(ref p, true)     => format!("create directory {} ? (y/n) ", p.display()),
+            Fileop::Create(ref p, false)    => format!("create file {} ? (y/n) ", p.display()),
+            Fileop::Rename(ref p, ref q)    => format!("rename {} to {} ? (y/n) ", p.display(), q.display()),
+            Fileop::Delete(ref p, true)     => format!("delete directory {} and all its content ? (y/n) ", p.display()),
+            Fileop::Delete(ref p, false)    => format!("delete file {} ? (y/n) ", p.display()),
+        };
+        Prompt::mk_prompt(message, String::new(), Promptaction::Confirm(op), mode)
+    }
+
+    fn put(&self, framebuffer: &mut Framebuffer, area: Rec) {
+        let line = format!("{}{}", self.message, self.input);
+        framebuffer.put_line(area.min, line.as_bytes());
+        framebuffer.set_cursor(area.min + pos(i32(line.len()), 0));
+    }
+}
+
+impl Fileop {
+    fn apply(&self) -> Re<()> {
+        match *self {
+            Fileop::Create(ref p, true)     => fs::create_dir(p)?,
+            Fileop::Create(ref p, false)    => {
+                fs::OpenOptions::new().write(true).create_new(true).open(p)?;
+            }
+            Fileop::Rename(ref p, ref q)    => fs::rename(p, q)?,
+            Fileop::Delete(ref p, true)     => fs::remove_dir_all(p)?,
+            Fileop::Delete(ref p, false)    => fs::remove_file(p)?,
+        }
+        Ok(())
+    }
+
+    // Directories whose content changed.
+    fn dirs(&self) -> Vec<PathBuf> {
+        let parent = |p: &PathBuf| p.parent().map(Path::to_path_buf).unwrap_or_default();
+        match *self {
+            Fileop::Create(ref p, _)        => vec![parent(p)],
+            Fileop::Rename(ref p, ref q)    => vec![parent(p), parent(q)],
+            Fileop::Delete(ref p, _)        => vec![parent(p)],
+        }
+    }
+}
+
+
+// The ':' command line in the footer.
+struct Cmdline {
+    input:          String,
+    cursor:         usize,          // byte offset in input
+    history:        usize,          // index in the command history, equal to its length for a new line
+    completions:    Vec<String>,    // candidate lines for the line typed before the first Tab
+    completion:     usize,
+}
+
+// A command of the command line. 'parse' turns the argument and the '!' flag into a CommandOp.
+struct Excommand {
+    name:   &'static str,
+    arg:    Exarg,
+    parse:  fn(&Editor, &str, bool) -> Re<CommandOp>,
+}
+
+// What the argument of a command is, for completion.
+#[derive(Clone, Copy, PartialEq)]
+enum Exarg {
+    Nothing,
+    Path,
+    Setting,
+}
+
+// Commands are also found by unique prefix, except for numbers which go to that line.
+const EXCOMMANDS : &[Excommand] = &[
+    Excommand { name: "write", arg: Exarg::Path,     parse: ex_write },
+    Excommand { name: "edit",  arg: Exarg::Path,     parse: ex_edit },
+    Excommand { name: "quit",  arg: Exarg::Nothing,  parse: ex_quit },
+    Excommand { name: "set",   arg: Exarg::Setting,  parse: ex_set },
+];
+
+// Options of the focused view which can be changed with ':set opt=val', ':set opt' or ':set noopt'.
+const VIEW_OPTIONS : &[&str] = &["relative_lineno", "show_token", "show_neighbor", "show_selection"];
+
+const CMDLINE_HISTORY_MAX : usize = 100;
+
+fn ex_write(e: &Editor, arg: &str, bang: bool) -> Re<CommandOp> {
+    let path = if arg.is_empty() { e.filepath() } else { arg };
+    if path.is_empty() {
+        return er!("no file name")
+    }
+    if e.buffers.get(e.view.buffer_id).readonly && !bang {
+        return er!("read-only buffer (add ! to write anyway)")
+    }
+    Ok(CommandOp::Save(ioutil::expand_home(path)))
+}
+
+fn ex_edit(_e: &Editor, arg: &str, _bang: bool) -> Re<CommandOp> {
+    if arg.is_empty() {
+        return er!("missing file name")
+    }
+    Ok(CommandOp::Open(ioutil::expand_home(arg)))
+}
+
+fn ex_quit(_e: &Editor, arg: &str, bang: bool) -> Re<CommandOp> {
+    if !arg.is_empty() {
+        return er!(format!("trailing characters: {}", arg))
+    }
+    Ok(CommandOp::Quit(bang))
+}
+
+fn ex_set(_e: &Editor, arg: &str, _bang: bool) -> Re<CommandOp> {
+    let (name, value) = match arg.find('=') {
+        Some(i)                             => (&arg[..i], &arg[i + 1..]),
+        None if arg.starts_with("no")       => (&arg[2..], "false"),
+        None                                => (arg, "true"),
+    };
+    if name.is_empty() {
+        return er!("missing option name")
+    }
+    Ok(CommandOp::Set(name.trim().to_string(), value.trim().to_string()))
+}
+
+// Split a command line into its command, its '!' flag and its argument.
+fn ex_split(line: &str) -> (&str, bool, &str) {
+    let line = line.trim_start();
+    let end = line.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(line.len());
+    let (name, rest) = line.split_at(end);
+    let bang = rest.starts_with("!");
+    let rest = if bang { &rest[1..] } else { rest };
+    (name, bang, rest.trim())
+}
+
+fn ex_lookup(name: &str) -> Re<&'static Excommand> {
+    if let Some(c) = EXCOMMANDS.iter().find(|c| c.name == name) {
+        return Ok(c)
+    }
+    let found : Vec<&Excommand> = EXCOMMANDS.iter().filter(|c| c.name.starts_with(name)).collect();
+    match found.len() {
+        1 => Ok(found[0]),
+        0 => er!(format!("not a command: {}", name)),
+        _ => er!(format!("ambiguous command: {}", name)),
+    }
+}
+
+fn ex_parse(e: &Editor, line: &str) -> Re<CommandOp> {
+    let (name, bang, arg) = ex_split(line);
+    if name.is_empty() && arg.is_empty() {
+        return Ok(CommandOp::Noop)
+    }
+    if let Ok(n) = name.parse::<i32>() {
+        return Ok(CommandOp::GotoLine(n))
+    }
+    let command = ex_lookup(name)?;
+    (command.parse)(e, arg, bang)
+}
+
+// Candidate lines completing the command name or the argument of a command line.
+fn ex_complete(line: &str) -> Vec<String> {
+    let (name, _, arg) = ex_split(line);
+    let command_only = line.trim_start().len() == name.len();
+    if command_only {
+        return EXCOMMANDS.iter().filter(|c| c.name.starts_with(name)).map(|c| c.name.to_string()).collect()
+    }
+
+    let command = match ex_lookup(name) {
+        Ok(command) => command,
+        Err(_)      => return Vec::new(),
+    };
+    let head = &line[..line.len() - arg.len()];
+    let head = if arg.is_empty() && !head.ends_with(" ") { format!("{} ", head) } else { head.to_string() };
+    let candidates = match command.arg {
+        Exarg::Nothing  => Vec::new(),
+        Exarg::Setting  => VIEW_OPTIONS.iter().filter(|o| o.starts_with(arg)).map(|o| o.to_string()).collect(),
+        Exarg::Path     => path_complete(arg),
+    };
+    candidates.into_iter().map(|c| format!("{}{}", head, c)).collect()
+}
+
+// Paths completing 'prefix', with a trailing '/' for directories.
+fn path_complete(prefix: &str) -> Vec<String> {
+    let (dir, base) = match prefix.rfind('/') {
+        Some(i) => prefix.split_at(i + 1),
+        None    => ("", prefix),
+    };
+    let listed = if dir.is_empty() { ".".to_string() } else { ioutil::expand_home(dir) };
+    let entries = match explore::list_dir(Path::new(&listed)) {
+        Ok(entries) => entries,
+        Err(_)      => return Vec::new(),
+    };
+    entries.iter()
+        .filter(|e| e.name.starts_with(base) && (base.starts_with(".") || !e.name.starts_with(".")))
+        .map(|e| format!("{}{}{}", dir, e.name, if e.is_dir { "/" } else { "" }))
+        .collect()
+}
+
+impl Cmdline {
+    fn mk_cmdline(history: usize) -> Cmdline {
+        Cmdline {
+            input:          String::new(),
+            cursor:         0,
+            history,
+            completions:    Vec::new(),
+            completion:     0,
+        }
+    }
+
+    fn set(&mut self, input: String) {
+        self.cursor = input.len();
+        self.input = input;
+    }
+
+    fn insert(&mut self, c: char) {
+        self.input.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+    }
+
+    fn backspace(&mut self) {
+        if let Some(c) = self.input[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+            self.input.remove(self.cursor);
+        }
+    }
+
+    fn left(&mut self) {
+        if let Some(c) = self.input[..self.cursor].chars().next_back() {
+            self.cursor -= c.len_utf8();
+        }
+    }
+
+    fn right(&mut self) {
+        if let Some(c) = self.input[self.cursor..].chars().next() {
+            self.cursor += c.len_utf8();
+        }
+    }
+
+    // Delete the word before the cursor, and the spaces after that word.
+    fn word_delete(&mut self) {
+        let head = self.input[..self.cursor].trim_end();
+        let start = head.rfind([' ', '/']).map(|i| i + 1).unwrap_or(0);
+        let start = if start == self.cursor && start > 0 { start - 1 } else { start };
+        self.input.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    // Replace the line with the next completion candidate, cycling through candidates.
+    fn complete(&mut self) {
+        if self.completions.is_empty() {
+            self.completions = ex_complete(&self.input[..self.cursor]);
+            self.completion = 0;
+        } else {
+            self.completion = (self.completion + 1) % self.completions.len();
+        }
+        if let Some(line) = self.completions.get(self.completion).cloned() {
+            self.set(line);
+        }
+    }
+
+    fn put(&self, framebuffer: &mut Framebuffer, area: Rec) {
+        let line = format!(":{}", self.input);
+        framebuffer.put_line(area.min, line.as_bytes());
+        let x = 1 + self.input[..self.cursor].chars().count();
+        framebuffer.set_cursor(area.min + pos(i32(x), 0));
+    }
+}
+
+
+// TODO: find better place
+fn update_buffer(r: text::Opresult, e: &mut Editor) {
+    use text::Opresult::*;
+    match r {
+        Cursor(p) => {
+            e.view.cursor = p;
+            e.view.cursor_memory = p;
+        }
+        Change(p) => {
+            e.view.cursor = p;
+            e.view.cursor_memory = p;
+            let buffer = e.buffers.buffer_mut(e.view.buffer_id);
+            buffer.dirty = true;
+            let shifts = buffer.ops_do();
+            e.positions_adjust(&shifts);
+            // push snapshot
+        }
+        _ => (),
+    }
+}
+
+/* COMMAND AND BUFFER MANIPULATION */
+    // Movements and buffer ops are done 'count' times, other commands ignore the count.
+    fn do_command(op: CommandOp, count: usize, e: &mut Editor) -> Re<Mode> {
+        use CommandOp::*;
+        use Mode::*;
+        match op {
+            BufferMove(m) => {
+                for _ in 0..count {
+                    e.failed = false;
+                    do_buffer_move(m, e);
+                    if e.failed {
+                        break
+                    }
+                }
+            }
+
+            // All repetitions happen in the same undo step.
+            BufferOp(command) => {
+                let offset = command.cursor - e.view.cursor;
+                match command.optype {
+                    BufferOpType::Undo | BufferOpType::Redo => (),
+                    optype => e.change = Some(Change::Edit(offset, optype)),
+                }
+                for _ in 0..count {
+                    let command = BufferCommand { cursor: e.view.cursor + offset, .. command };
+                    do_buffer_command(command, e);
+                }
+            }
+
+            Repeat =>
+                e.change_repeat(count),
+
+            CommandOp::Operator(operator) =>
+                return Ok(Mode::Operator(operator, count)),
+
+            SwitchVisual(linewise) => {
+                e.view.anchor = e.view.cursor;
+                return Ok(Visual(linewise))
+            }
+
+            SwitchSearch => {
+                e.prompt = Some(::Prompt::mk_prompt("/".to_string(), String::new(), Promptaction::Search(None), Command));
+                return Ok(Prompt)
+            }
+
+            Put(before) => {
+                for _ in 0..count {
+                    e.yank_put(before);
+                }
+            }
+
+            BufferSwitch(offset) => {
+                let id = e.buffers.cycle(e.view.buffer_id, offset);
+                e.view_switch(id);
+            }
+
+            BufferClose =>
+                e.buffer_close(e.view.buffer_id, false),
+
+            BufferExplorer => {
+                e.picker = Some(e.buffer_explorer());
+                return Ok(Pick)
+            }
+
+            DirectoryExplorer => {
+                if e.explorer.is_none() {
+                    let dir = std::env::current_dir()?;
+                    e.explorer_open(&dir)?;
+                }
+                return Ok(Explorer)
+            }
+
+            FileFinder => {
+                e.finder_open()?;
+                return Ok(Pick)
+            }
+
+            Save(path) => {
+                let entry = e.buffers.get_mut(e.view.buffer_id);
+                let dirty = entry.buffer.dirty;
+                entry.buffer.to_file(&path)?;
+                if entry.filepath.is_empty() {
+                    entry.filepath = path;
+                } else if ioutil::canonical_path(&path) != ioutil::canonical_path(&entry.filepath) {
+                    // Writing a copy elsewhere does not save the buffer to its own file.
+                    entry.buffer.dirty = dirty;
+                }
+            }
+
+            ClearConsole => Debugconsole::clear(),
+
+            SwitchInsert => {
+                let mode = InsertMode::Insert;
+                return Ok(PendingInsert(mode))
+            }
+
+            SwitchReplace => {
+                let mode = InsertMode::Replace;
+                return Ok(PendingInsert(mode))
+            }
+
+            SwitchCommand =>
+                return Ok(Command),
+
+            SwitchMarkSet =>
+                return Ok(MarkSet),
+
+            SwitchMarkGoto =>
+                return Ok(MarkGoto),
+
+            SwitchMacroReplay => {
+                e.count = count;    // kept for after the register key
+                return Ok(MacroReplay)
+            }
+
+            MacroToggle => {
+                if e.recording.is_none() {
+                    return Ok(Mode::MacroRecord)
+                }
+                e.macro_stop();
+            }
+
+            SwitchPane =>
+                return Ok(Pane),
+
+            SwitchCmdline => {
+                e.cmdline = Some(::Cmdline::mk_cmdline(e.cmdhistory.len()));
+                return Ok(Cmdline)
+            }
+
+            Open(path) => {
+                let filearea = e.view.filearea.size().rec();
+                let id = e.buffers.open_or_new(&path, filearea)?;
+                e.jump_push();
+                e.view_switch(id);
+            }
+
+            Quit(force) => {
+                let dirty = e.buffers.entries.iter().filter(|b| b.buffer.dirty).count();
+                if dirty > 0 && !force {
+                    return er!(format!("{} buffer(s) with unsaved changes (add ! to quit anyway)", dirty))
+                }
+                return Ok(Exit)
+            }
+
+            Set(name, value) =>
+                e.option_set(&name, &value)?,
+
+            GotoLine(n) => {
+                e.jump_push();
+                e.view.cursor_set(e.buffers.buffer(e.view.buffer_id), pos(0, n - 1));
+            }
+
+            TagGoto =>
+                return Ok(e.tag_goto()),
+
+            Noop => (),
+        }
+
+        Ok(Command)
+    }
+
+    fn do_buffer_move(op: MoveOp, e: &mut Editor) {
+        use MoveOp::*;
+        match op {
+            Movement(mvt) => {
+                let cursor = e.view.cursor;
+                e.mv_cursor(mvt);
+                let buffer = e.buffers.buffer(e.view.buffer_id);
+                e.failed |= View::cursor_adjust(buffer, e.view.cursor) == View::cursor_adjust(buffer, cursor);
+            }
+
+            Recenter =>
+                e.view.recenter(e.buffers.buffer(e.view.buffer_id)),
+
+            PageUp => {
+                e.jump_push();
+                e.view.go_page_up(e.buffers.buffer(e.view.buffer_id))
+            }
+
+            PageDown => {
+                e.jump_push();
+                e.view.go_page_down(e.buffers.buffer(e.view.buffer_id))
+            }
+
+            FileStart => {
+                e.jump_push();
+                e.view.go_file_start(e.buffers.buffer(e.view.buffer_id))
+            }
+
+            FileEnd => {
+                e.jump_push();
+                e.view.go_file_end(e.buffers.buffer(e.view.buffer_id))
+            }
+
+            JumpBack => {
+                let current = e.jump_here();
+                match e.view.jumps.back(current) {
+                    Some(jump)  => e.jump_to(jump),
+                    None        => e.failed = true,
+                }
+            }
+
+            JumpForward => {
+                match e.view.jumps.forward() {
+                    Some(jump)  => e.jump_to(jump),
+                    None        => e.failed = true,
+                }
+            }
+
+            WordNext | WordPrev | WordEnd | ParagraphNext | ParagraphPrev | BracketMatch |
+            LineStart | LineEnd | SearchNext | SearchPrev => {
+                match e.motion_target(op, e.view.cursor) {
+                    Some((p, _)) => {
+                        if let ParagraphNext | ParagraphPrev | BracketMatch | SearchNext | SearchPrev = op {
+                            e.jump_push();
+                        }
+                        e.view.cursor_set(e.buffers.buffer(e.view.buffer_id), p);
+                        if let LineEnd = op {
+                            e.view.cursor_memory.x = i32::MAX;  // stay at line ends
+                        }
+                    }
+                    None => e.failed = true,
+                }
+            }
+
+            TagPop => {
+                match e.view.tagstack.pop() {
+                    Some(jump) => e.jump_to(jump),
+                    None => {
+                        logconsole("tag stack empty");
+                        e.failed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // TODO: move into buffer
+    // TODO: remote Editor from there and pass all the views instead
+    fn do_buffer_command(command: BufferCommand, e: &mut Editor) {
+        let cursor = command.cursor;
+
+        use BufferOpType::*;
+
+        // Undo and Redo bypass the normal flow.
+        if let Undo | Redo = command.optype {
+            let buffer = e.buffers.buffer_mut(e.view.buffer_id);
+            let restored = match command.optype {
+                Undo    => buffer.undo(),
+                _       => buffer.redo(),
+            };
+            match restored {
+                Some((p, shifts)) => {
+                    e.positions_adjust(&shifts);
+                    e.view.cursor_set(e.buffers.buffer(e.view.buffer_id), p);
+                }
+                None => logconsole("nothing to undo or redo"),
+            }
+            return
+        }
+
+        // All changes until the end of the current command or insert session are one undo step,
+        // see Editor::undo_step_close().
+        let buffer = e.buffers.buffer_mut(e.view.buffer_id);
+        buffer.snapshot_take(cursor);
+        let opresult = match command.optype {
+            Undo | Redo         => Opresult::Noop,
+
+            LineDel             => buffer.line_del(cursor),
+            LineNew             => buffer.line_new(cursor),
+            LineJoin            => buffer.line_join(cursor),
+            LineBreak           => buffer.line_break(cursor),
+            CharDelete          => buffer.del(cursor),
+            CharBackspace       => buffer.backspace(cursor),
+
+            InsertChar(c) if c == TAB => {
+                let n = CONF.tab_expansion - cursor.x % CONF.tab_expansion;
+                let mut p = cursor;
+                for _ in 0..n {
+                    buffer.char_insert(command.mode.unwrap(), p, ' ');
+                    p = p + pos(1,0);
+                }
+                Opresult::Change(p)
+            }
+            InsertChar(c) if !is_printable(c) => {
+                Opresult::Noop
+            }
+            InsertChar(c) => {
+                // TODO: check that raw text mutation: insert is always preceded by a snapshot
+                // and appropriate line copy
+                buffer.char_insert(command.mode.unwrap(), cursor, c)
+            }
+
+            Noop                => Opresult::Noop
+        };
+
+        // FIXME
+        // use snapshot below
+
+        match opresult {
+            Opresult::Cursor(new_cursor) => {
+                e.view.cursor = new_cursor;
+                e.view.cursor_memory = new_cursor;
+            }
+            Opresult::Change(new_cursor) => {
+                e.view.cursor = new_cursor;
+                e.view.cursor_memory = new_cursor;
+                // FIXME: partially avoid this if in insert mode ?
+                buffer.dirty = true;
+                let shifts = buffer.ops_do();
+                e.positions_adjust(&shifts);
+                //e.buffer.snapshot_buffer.save() ??
+            }
+            Opresult::Noop => (),
+                // FIXME: undo the snapshot push ?
+        }
+    }
+
+impl Editor {
+
+    fn mk_editor(args: &Args, keymaps: Keymaps) -> Re<Editor> {
+        let mut e = Editor::mk_scratch_editor(Term::size(), keymaps);
+        let scratch = e.view.buffer_id;
+        {
+            let filearea = e.screen.textarea_size().rec();
+            let mut ids = Vec::new();
+            for f in &args.files {
+                let id = match e.buffers.open_or_new(&f.path, filearea) {
+                    Ok(id)      => id,
+                    Err(err)    => {
+                        logconsole(&format!("cannot open {}: {}", f.path, err));
+                        continue
+                    }
+                };
+                let entry = e.buffers.get_mut(id);
+                entry.readonly |= args.readonly;
+                if let Some(p) = f.cursor {
+                    entry.cursor = View::cursor_adjust(&entry.buffer, p);
+                }
+                ids.push(id);
+            }
+            if let Some(&id) = ids.first() {
+                e.view.buffer_switch(e.buffers.get(id));
+                e.view.recenter(e.buffers.buffer(id));
+                e.buffers.close(scratch, true)?;
+            }
+        }
+
+        let session = match Session::load(&ioutil::expand_home(&CONF.sessionfile)) {
+            Ok(session) => session,
+            Err(err)    => {
+                logconsole(&format!("cannot load session: {}", err));
+                Session { marks: Vec::new(), tabs: Vec::new(), current: 0 }
+            }
+        };
+        let Session { marks, tabs, current } = session;
+        e.marks = marks;
+
+        if let Some(dir) = args.dirs.first() {
+            e.explorer_open(Path::new(dir))?;
+        }
+
+        // Without arguments, continue where the last session stopped.
+        if args.files.is_empty() && args.dirs.is_empty() {
+            e.tabs_restore(tabs, current);
+            if e.view.buffer_id != scratch && !e.buffer_is_shown(scratch) {
+                e.buffers.close(scratch, true)?;
+            }
+        }
+
+        Ok(e)
+    }
+
+    // An editor filling 'window' and showing an empty scratch buffer, without anything from the
+    // command line, the terminal or the session.
+    fn mk_scratch_editor(window: Pos, keymaps: Keymaps) -> Editor {
+        let (mainscreen, footer) = window.rec().vsplit(window.y - 1);
+        let screen = Screen::mk_screen(mainscreen);
+        let mut buffers = Bufferlist::mk_bufferlist();
+        let id = buffers.open_scratch(screen.textarea_size().rec());
+        let mut view = View::mk_fileview(id, screen.textarea_size());
+        view.buffer_switch(buffers.get(id));
+        view.recenter(buffers.buffer(id));
+
+        Editor {
+            window,
+            mainscreen,
+            footer,
+            buffers,
+            view,
+            screen,
+            pane_id:        0,
+            panes:          Vec::new(),
+            layout:         Layout::Pane(0),
+            next_pane_id:   1,
+            tabname:        "main".to_string(),
+            tab:            0,
+            tabs:           Vec::new(),
+            marks:          Vec::new(),
+            picker:     None,
+            explorer:   None,
+            prompt:     None,
+            cmdline:    None,
+            cmdhistory: Vec::new(),
+            message:    None,
+            macros:     Vec::new(),
+            recording:  None,
+            replaying:  0,
+            failed:     false,
+            change:     None,
+            insert_ops: Vec::new(),
+            yank:       None,
+            dragging:   false,
+            search:     None,
+            keymaps,
+            keys:       Vec::new(),
+            count:      0,
+            finder:     None,
+            wakeup:     None,
+        }
+    }
+
+    fn run(args: Args, mut config_errors: Vec<String>) -> Re<()> {
+        let (keymaps, keymap_errors) = Keymaps::load();
+        config_errors.extend(keymap_errors);
+        let mut e = Editor::mk_editor(&args, keymaps)?;
+        for err in &config_errors {
+            logconsole(err);
+        }
+        if let Some(err) = config_errors.first() {
+            let more = config_errors.len() - 1;
+            e.message = Some(if more > 0 { format!("{} (and {} more)", err, more) } else { err.clone() });
+        }
+        let mut f = Framebuffer::mk_framebuffer(e.window);
+        let mut m = Mode::default_command_state;
+        if args.files.is_empty() && e.explorer.is_some() {
+            m = Mode::Explorer;
+        }
+
+        e.refresh_screen(&mut f, &m)?;
+
+        let (send, recv) = std::sync::mpsc::sync_channel(32);
+        e.wakeup = Some(send.clone());
+        let mut input = Inputreader::mk_inputreader(recv);
+
+        std::thread::spawn(move || {
+            push_char(&send);
+        });
+
+
+        while m != Mode::Exit {
+            let i = input.pull()?;
+            logconsole(&format!("input: {}", i));
+
+            let _frame_time = Scopeclock::measure("last frame");     // caveat: displayed on next frame only
+
+            let recording = e.recording.is_some();
+            m = Mode::process_input(m, i.clone(), &mut e)?;
+            if let (true, Some(r)) = (recording, e.recording.as_mut()) {
+                r.inputs.push(i);
+            }
+            match m {
+                Mode::Insert(_) | Mode::PendingInsert(_) => (),
+                _ => e.undo_step_close(),
+            }
+            e.finder_poll();
+
+            e.refresh_screen(&mut f, &m)?;
+        }
+
+        e.session_save()
+    }
+
+    // Add a key to the pending key sequence and look the sequence up in the keymap of 'mode'.
+    fn key_input(&mut self, mode: Keymode, i: Input) -> Keylookup {
+        if self.keys.is_empty() {
+            if let Some(ref mut r) = self.recording {
+                r.sequence_start = r.inputs.len();
+            }
+        }
+        self.keys.push(i);
+        let keymap = match mode {
+            Keymode::Insert => &self.keymaps.insert,
+            _               => &self.keymaps.command,
+        };
+        let lookup = keymap.lookup(&self.keys);
+        if let Keylookup::Pending = lookup {
+            return lookup
+        }
+        self.keys.clear();
+        lookup
+    }
+
+    // Close the undo step of every buffer. Inputs of a same insert session, or of a same macro
+    // replay, all belong to a single undo step.
+    fn undo_step_close(&mut self) {
+        for entry in self.buffers.entries.iter_mut() {
+            entry.buffer.snapshot_finish();
+        }
+    }
+
+    // Do the last change again 'count' times at the cursor.
+    fn change_repeat(&mut self, count: usize) {
+        let change = match self.change.clone() {
+            Some(change)    => change,
+            None            => {
+                self.failed = true;
+                return
+            }
+        };
+        for _ in 0..count {
+            match change {
+                Change::Edit(offset, optype) => {
+                    let command = buffercommand(self.view.cursor + offset, optype);
+                    do_buffer_command(command, self);
+                }
+                Change::Insert(mode, ref ops) => {
+                    self.insert_replay(mode, ops);
+                }
+                Change::Operator(operator, motion, n, ref typed) => {
+                    if let Some(range) = self.motion_range(motion, n) {
+                        self.operator_apply(operator, range);
+                    }
+                    if let Some(ref ops) = *typed {
+                        self.insert_replay(InsertMode::Insert, ops);
+                    }
+                }
+            }
+        }
+    }
+
+    // Move the cursor to extend the selection, or apply an operator to it.
+    fn visual_input(&mut self, linewise: bool, i: Input) -> Mode {
+        if let Input::Key(c @ 'i') | Input::Key(c @ 'a') = i {
+            if self.keys.is_empty() {
+                return Mode::Object(None, 1, c == 'i')
+            }
+        }
+        let op = match self.key_input(Keymode::Command, i) {
+            Keylookup::Bound(command)   => (command.op)(self),
+            Keylookup::Pending          => return Mode::Visual(linewise),
+            Keylookup::Unbound(_)       => CommandOp::Noop,
+        };
+        match op {
+            CommandOp::BufferMove(m) => {
+                do_buffer_move(m, self);
+                Mode::Visual(linewise)
+            }
+            CommandOp::SwitchVisual(l) if l != linewise =>
+                Mode::Visual(l),
+            CommandOp::Operator(operator) => {
+                let range = self.selection_range(linewise);
+                self.operator_apply(operator, range)
+            }
+            _ => Mode::Command,
+        }
+    }
+
+    // Select a text object around the cursor.
+    fn visual_object(&mut self, object: Textobject, inner: bool, count: usize) -> Mode {
+        let buffer = self.buffers.buffer(self.view.buffer_id);
+        let (start, stop, linewise) = match buffer.object_range(self.view.cursor, object, inner, count) {
+            Some(range) if range.0 != range.1 || range.2 => range,
+            _ => return Mode::Visual(false),
+        };
+        let last = if linewise { stop } else { buffer.pos_prev(stop).unwrap_or(stop) };
+        self.view.anchor = start;
+        self.view.cursor_set(buffer, last);
+        Mode::Visual(linewise)
+    }
+
+    fn selection(&self, linewise: bool) -> (Pos, Pos) {
+        let (a, b) = (self.view.anchor, self.view.cursor);
+        let (start, stop) = if (a.y, a.x) < (b.y, b.x) { (a, b) } else { (b, a) };
+        if linewise {
+            return (pos(0, start.y), pos(0, stop.y))
+        }
+        (start, stop)
+    }
+
+    // The selected text, with the character under the cursor. Selecting the end of a line selects
+    // its line break.
+    fn selection_range(&self, linewise: bool) -> Textrange {
+        let (start, stop) = self.selection(linewise);
+        if linewise {
+            return Textrange { start, stop, linewise }
+        }
+        let buffer = self.buffers.buffer(self.view.buffer_id);
+        let stop = match buffer.pos_next(stop) {
+            Some(next)  => next,
+            None        => stop,
+        };
+        Textrange { start, stop, linewise }
+    }
+
+    // Insert pasted text at the cursor as a single edit, without the processing of typed keys.
+    fn paste(&mut self, text: &str) {
+        let bytes : Vec<u8> = text.chars().map(|c| c as u8).collect();
+        let cursor = self.view.cursor;
+        let buffer = self.buffers.buffer_mut(self.view.buffer_id);
+        buffer.snapshot_take(cursor);
+        let result = buffer.text_insert(cursor, &bytes);
+        update_buffer(result, self);
+    }
+
+    fn insert_replay(&mut self, mode: InsertMode, ops: &[InsertOp]) {
+        for op in ops {
+            match *op {
+                InsertOp::Buffer(optype) => {
+                    let command = BufferCommand { cursor: self.view.cursor, optype, mode: Some(mode) };
+                    do_buffer_command(command, self);
+                }
+                InsertOp::Paste(ref text) => {
+                    self.paste(text);
+                }
+            }
+        }
+    }
+
+    // Parse the motion following an operator: a count, the operator again for whole lines, a
+    // movement, a text object, a mark, or a search.
+    fn operator_input(&mut self, operator: Operator, count: usize, i: Input) -> Mode {
+        if let Input::Key(c @ '0' ..= '9') = i {
+            if self.keys.is_empty() && (c != '0' || self.count > 0) {
+                let digit = c as usize - '0' as usize;
+                self.count = min(COUNT_MAX, self.count * 10 + digit);
+                return Mode::Operator(operator, count)
+            }
+        }
+        if let Input::Key(c @ 'i') | Input::Key(c @ 'a') = i {
+            if self.keys.is_empty() {
+                let count = count * max(1, std::mem::replace(&mut self.count, 0));
+                return Mode::Object(Some(operator), count, c == 'i')
+            }
+        }
+        let op = match self.key_input(Keymode::Command, i) {
+            Keylookup::Bound(command)   => (command.op)(self),
+            Keylookup::Pending          => return Mode::Operator(operator, count),
+            Keylookup::Unbound(_)       => CommandOp::Noop,
+        };
+        let count = count * max(1, std::mem::replace(&mut self.count, 0));
+        let motion = match op {
+            CommandOp::Operator(o) if o == operator => Motion::Lines,
+            CommandOp::BufferMove(m) => Motion::Move(m),
+            CommandOp::SwitchMarkGoto =>
+                return Mode::OperatorMark(operator, count),
+            CommandOp::SwitchSearch => {
+                // Cancelling the search cancels the operator too.
+                let action = Promptaction::Search(Some((operator, count)));
+                self.prompt = Some(Prompt::mk_prompt("/".to_string(), String::new(), action, Mode::Command));
+                return Mode::Prompt
+            }
+            _ => return Mode::Command,  // cancelled
+        };
+        self.operator_motion(operator, motion, count)
+    }
+
+    fn operator_motion(&mut self, operator: Operator, motion: Motion, count: usize) -> Mode {
+        // Like in vim, changing words keeps the spaces after the last word.
+        let motion = match (operator, motion) {
+            (Operator::Change, Motion::Move(MoveOp::WordNext)) => {
+                let cursor = self.view.cursor;
+                let buffer = self.buffers.buffer(self.view.buffer_id);
+                match (buffer.class_at(cursor), buffer.class_at(cursor + pos(1,0))) {
+                    (0, _)                      => motion,
+                    (a, b) if a != b && count == 1 => Motion::Move(MoveOp::Movement(Move::Right)),
+                    _                           => Motion::Move(MoveOp::WordEnd),
+                }
+            }
+            _ => motion,
+        };
+        let range = match self.motion_range(motion, count) {
+            Some(range) => range,
+            None        => {
+                self.failed = true;
+                return Mode::Command
+            }
+        };
+        if operator != Operator::Yank {
+            self.change = Some(Change::Operator(operator, motion, count, None));
+        }
+        self.operator_apply(operator, range)
+    }
+
+    // Where movement 'm' goes from p, or None if it cannot move or is not a motion.
+    fn motion_target(&self, m: MoveOp, p: Pos) -> Option<(Pos, Motionkind)> {
+        use MoveOp::*;
+        use Motionkind::*;
+        let buffer = self.buffers.buffer(self.view.buffer_id);
+        let last = buffer.nlines() - 1;
+        let len = i32(buffer.line_len(usize(p.y)));
+        let target = match m {
+            Movement(Move::Left) if p.x > 0             => (p - pos(1,0), Exclusive),
+            Movement(Move::Right) if p.x < len          => (p + pos(1,0), Exclusive),
+            Movement(Move::Up) if p.y > 0               => (p - pos(0,1), Lines),
+            Movement(Move::Down) if p.y < last          => (p + pos(0,1), Lines),
+            PageUp                                      => (pos(p.x, max(0, p.y - 50)), Lines),
+            PageDown                                    => (pos(p.x, min(last, p.y + 50)), Lines),
+            FileStart                                   => (pos(0, 0), Lines),
+            FileEnd                                     => (pos(0, last), Lines),
+            WordNext                                    => (buffer.word_next(p), Exclusive),
+            WordPrev                                    => (buffer.word_prev(p), Exclusive),
+            WordEnd                                     => (buffer.word_end(p), Inclusive),
+            ParagraphNext                               => (buffer.paragraph_next(p), Exclusive),
+            ParagraphPrev                               => (buffer.paragraph_prev(p), Exclusive),
+            BracketMatch                                => (buffer.bracket_match(p)?, Inclusive),
+            LineStart                                   => (pos(0, p.y), Exclusive),
+            LineEnd                                     => (pos(max(0, len - 1), p.y), Inclusive),
+            SearchNext | SearchPrev => {
+                let pattern = self.search.as_ref()?.as_bytes();
+                let forward = matches!(m, SearchNext);
+                (buffer.search(pattern, p, forward)?, Exclusive)
+            }
+            _ => return None,
+        };
+        if target.0 == p && target.1 != Inclusive {
+            return None
+        }
+        Some(target)
+    }
+
+    // The text covered by a motion done 'count' times from the cursor.
+    fn motion_range(&self, motion: Motion, count: usize) -> Option<Textrange> {
+        let cursor = self.view.cursor;
+        let buffer = self.buffers.buffer(self.view.buffer_id);
+        let (target, kind) = match motion {
+            Motion::Lines => {
+                let y = min(buffer.nlines() - 1, cursor.y + i32(count) - 1);
+                (pos(0, y), Motionkind::Lines)
+            }
+            Motion::Mark(c) =>
+                (self.mark_position(c)?, Motionkind::Lines),
+            Motion::Object(object, inner) => {
+                let (start, stop, linewise) = buffer.object_range(cursor, object, inner, count)?;
+                if start == stop && !linewise {
+                    return None
+                }
+                return Some(Textrange { start, stop, linewise })
+            }
+            Motion::Move(m) => {
+                let mut target = self.motion_target(m, cursor)?;
+                for _ in 1..count {
+                    match self.motion_target(m, target.0) {
+                        Some(t) => target = t,
+                        None    => break,
+                    }
+                }
+                // Like in vim, a word motion does not cross the end of the line.
+                if let (MoveOp::WordNext, true) = (m, target.0.y > cursor.y) {
+                    target.0 = pos(i32(buffer.line_len(usize(cursor.y))), cursor.y);
+                }
+                target
+            }
+        };
+
+        let (start, stop) = if (target.y, target.x) < (cursor.y, cursor.x) { (target, cursor) } else { (cursor, target) };
+        let range = match kind {
+            Motionkind::Lines       => Textrange { start: pos(0, start.y), stop: pos(0, stop.y), linewise: true },
+            Motionkind::Exclusive   => Textrange { start, stop, linewise: false },
+            Motionkind::Inclusive   => Textrange { start, stop: stop + pos(1,0), linewise: false },
+        };
+        Some(range)
+    }
+
+    // Position of mark 'name' if it is in the current buffer.
+    fn mark_position(&self, name: char) -> Option<Pos> {
+        match name {
+            'a' ..= 'z' => self.buffers.buffer(self.view.buffer_id).mark_get(name),
+            'A' ..= 'Z' => {
+                let filepath = ioutil::canonical_path(self.filepath());
+                self.marks.iter().find(|m| m.name == name && m.filepath == filepath).map(|m| m.cursor)
+            }
+            _ => None,
+        }
+    }
+
+    fn operator_apply(&mut self, operator: Operator, range: Textrange) -> Mode {
+        use Operator::*;
+        let Textrange { start, stop, linewise } = range;
+        let (first, last) = (usize(start.y), usize(stop.y));
+        let cursor = self.view.cursor;
+        let indent = CONF.tab_expansion as usize;
+
+        let buffer = self.buffers.buffer_mut(self.view.buffer_id);
+        let text = if linewise { buffer.lines_text(first, last) } else { buffer.range_text(start, stop) };
+        buffer.snapshot_take(cursor);
+        let result = match operator {
+            Yank => {
+                self.yank = Some(::Yank { text, linewise });
+                if linewise { Opresult::Cursor(pos(cursor.x, start.y)) } else { Opresult::Cursor(start) }
+            }
+            Delete | Change => {
+                self.yank = Some(::Yank { text, linewise });
+                match (operator, linewise) {
+                    (Delete, true)  => buffer.lines_delete(first, last),
+                    (_, true)       => buffer.range_delete(pos(0, start.y), pos(i32(buffer.line_len(last)), stop.y)),
+                    (_, false)      => buffer.range_delete(start, stop),
+                }
+            }
+            Indent => buffer.lines_map(first, last, &|line| {
+                if line.is_empty() {
+                    return Vec::new()
+                }
+                let mut indented = vec![b' '; indent];
+                indented.extend_from_slice(line);
+                indented
+            }),
+            Dedent => buffer.lines_map(first, last, &|line| {
+                let n = line.iter().take(indent).take_while(|&&c| c == b' ').count();
+                line[n..].to_vec()
+            }),
+            Lowercase | Uppercase | Togglecase => {
+                let f : fn(u8) -> u8 = match operator {
+                    Lowercase   => |c| c.to_ascii_lowercase(),
+                    Uppercase   => |c| c.to_ascii_uppercase(),
+                    _           => |c| if c.is_ascii_lowercase() { c.to_ascii_uppercase() } else { c.to_ascii_lowercase() },
+                };
+                if linewise {
+                    buffer.range_map(pos(0, start.y), pos(i32(buffer.line_len(last)), stop.y), f)
+                } else {
+                    buffer.range_map(start, stop, f)
+                }
+            }
+        };
+        update_buffer(result, self);
+
+        match operator {
+            Change  => Mode::PendingInsert(InsertMode::Insert),
+            _       => Mode::Command,
+        }
+    }
+
+    // Insert the last yanked or deleted text around the cursor, on the lines around the cursor line
+    // for whole lines.
+    fn yank_put(&mut self, before: bool) {
+        let (text, linewise) = match self.yank {
+            Some(ref yank)  => (yank.text.clone(), yank.linewise),
+            None            => {
+                self.failed = true;
+                return
+            }
+        };
+        let cursor = self.view.cursor;
+        let buffer = self.buffers.buffer_mut(self.view.buffer_id);
+        let last = buffer.nlines() - 1;
+        buffer.snapshot_take(cursor);
+        let result = match (linewise, before) {
+            (true, true)                    => buffer.text_insert(pos(0, cursor.y), &text),
+            (true, false) if cursor.y < last => buffer.text_insert(pos(0, cursor.y + 1), &text),
+            (true, false) => {
+                // After the last line: start with a line break instead of ending with one.
+                let mut text = text;
+                text.pop();
+                text.insert(0, b'\n');
+                buffer.text_insert(pos(i32(buffer.line_len(usize(last))), last), &text)
+            }
+            (false, true)                   => buffer.text_insert(cursor, &text),
+            (false, false) => {
+                let x = min(cursor.x + 1, i32(buffer.line_len(usize(cursor.y))));
+                buffer.text_insert(pos(x, cursor.y), &text)
+            }
+        };
+        let y = match (linewise, before) {
+            (true, true)    => cursor.y,
+            (true, false)   => cursor.y + 1,
+            _               => -1,
+        };
+        update_buffer(result, self);
+        if y >= 0 {
+            self.view.cursor_set(self.buffers.buffer(self.view.buffer_id), pos(0, y));
+        }
+    }
+
+    fn macro_record(&mut self, register: char) {
+        if !is_printable(register) {
+            return
+        }
+        self.recording = Some(Recording { register, inputs: Vec::new(), sequence_start: 0 });
+    }
+
+    fn macro_stop(&mut self) {
+        if let Some(mut r) = self.recording.take() {
+            // Forget the keys which stopped the recording.
+            r.inputs.truncate(r.sequence_start);
+            self.macros.retain(|&(c, _)| c != r.register);
+            self.macros.push((r.register, r.inputs));
+        }
+    }
+
+    // Feed the inputs recorded in 'register' to the editor 'count' times, starting in Command mode.
+    // Replaying stops early when a movement fails.
+    fn macro_replay(&mut self, register: char, count: usize) -> Re<Mode> {
+        let inputs = match self.macros.iter().find(|&&(c, _)| c == register) {
+            Some((_, inputs))       => inputs.clone(),
+            None                    => {
+                self.message = Some(format!("register {} is empty", register));
+                return Ok(Mode::Command)
+            }
+        };
+        if self.replaying >= MACRO_DEPTH_MAX {
+            self.failed = true;
+            return Ok(Mode::Command)
+        }
+
+        self.replaying += 1;
+        let mut m = Mode::Command;
+        'replay: for _ in 0..count {
+            for i in &inputs {
+                self.failed = false;
+                m = Mode::process_input(m, i.clone(), self)?;
+                if m == Mode::Exit || self.failed {
+                    break 'replay
+                }
+            }
+        }
+        self.replaying -= 1;
+        self.keys.clear();
+
+        Ok(m)
+    }
+
+    fn session_save(&self) -> Re<()> {
+        let mut tabs : Vec<Tabinfo> = self.tabs.iter().map(|t| {
+            self.tabinfo(&t.name, t.pane_id, &t.view, &t.panes, &t.layout)
+        }).collect();
+        let shown = self.tabinfo(&self.tabname, self.pane_id, &self.view, &self.panes, &self.layout);
+        tabs.insert(self.tab, shown);
+        let session = Session {
+            marks:      self.marks.clone(),
+            tabs,
+            current:    self.tab,
+        };
+        session.save(&ioutil::expand_home(&CONF.sessionfile))
+    }
+
+    fn refresh_screen(&mut self, framebuffer: &mut Framebuffer, mode: &Mode) -> Re<()> {
+        // main screen
+        {
+            let _draw_time = Scopeclock::measure("draw");
+
+            let entry = self.buffers.get(self.view.buffer_id);
+            let header = entry.header(&self.view);
+            let selection = match *mode {
+                Mode::Visual(linewise) => {
+                    let (start, stop) = self.selection(linewise);
+                    Some((start, stop, linewise))
+                }
+                _ => None,
+            };
+            let drawinfo = Drawinfo {
+                header:             &header,
+                buffer:             &entry.buffer,
+                buffer_offset:      self.view.filearea.min,
+                cursor:             self.view.cursor,
+                draw:               Draw::All,
+                relative_lineno:    self.view.relative_lineno,
+                is_active:          self.view.is_active,
+                selection,
+            };
+            self.screen.put_text(framebuffer, &drawinfo);
+
+            for pane in self.panes.iter_mut() {
+                let entry = self.buffers.get(pane.view.buffer_id);
+                // Another view may have changed the buffer under this view's cursor.
+                pane.view.update(&entry.buffer);
+                let header = entry.header(&pane.view);
+                let drawinfo = Drawinfo {
+                    header:             &header,
+                    buffer:             &entry.buffer,
+                    buffer_offset:      pane.view.filearea.min,
+                    cursor:             pane.view.cursor,
+                    draw:               Draw::All,
+                    relative_lineno:    pane.view.relative_lineno,
+                    is_active:          false,
+                    selection:          None,
+                };
+                pane.screen.put_text(framebuffer, &drawinfo);
+            }
+        }
+
+        // tab bar
+        if !self.tabs.is_empty() {
+            let bar = self.mainscreen.row(self.mainscreen.y0());
+            framebuffer.put_color(bar, CONF.color_header_inactive);
+            let mut x = bar.x0();
+            for (i, name) in self.tabnames().iter().enumerate() {
+                if x >= bar.x1() {
+                    break
+                }
+                let label = format!(" {} ", name);
+                let w = min(i32(label.len()), bar.x1() - x);
+                framebuffer.put_line(pos(x, bar.y0()), label.as_bytes());
+                if i == self.tab {
+                    framebuffer.put_color(rec(x, bar.y0(), x + w, bar.y1()), CONF.color_header_active);
+                }
+                x += w;
+            }
+        }
+
+        // side panel
+        if let Some(ref explorer) = self.explorer {
+            explorer.put(framebuffer, self.explorer_area(), *mode == Mode::Explorer);
+        }
+
+        // popups
+        if let Some(ref picker) = self.picker {
+            picker.put(framebuffer, self.mainscreen);
+        }
+
+        // footer
+        {
+            framebuffer.put_line(self.footer.min + pos(1,0), mode.name().as_bytes());
+            if let Some(ref prompt) = self.prompt {
+                prompt.put(framebuffer, self.footer + pos(10, 0));
+            } else if let Some(ref cmdline) = self.cmdline {
+                cmdline.put(framebuffer, self.footer + pos(10, 0));
+            } else if self.count > 0 || !self.keys.is_empty() {
+                let count = if self.count > 0 { format!("{} ", self.count) } else { String::new() };
+                let pending = count + &keys_name(&self.keys);
+                framebuffer.put_line(self.footer.min + pos(10, 0), pending.as_bytes());
+            } else if let Some(ref message) = self.message {
+                framebuffer.put_line(self.footer.min + pos(10, 0), message.as_bytes());
+            }
+            if let Some(ref r) = self.recording {
+                let recording = format!("recording @{}", r.register);
+                framebuffer.put_line(pos(self.footer.max.x - 1 - i32(recording.len()), self.footer.min.y),
+                                     recording.as_bytes());
+            }
+            framebuffer.put_color(self.footer, mode.footer_color());
+        }
+
+        // renter frame to terminal
+        {
+            let _push_frame_time = Scopeclock::measure("render");
+
+            framebuffer.render()?;
+            if !CONF.retain_frame {
+                framebuffer.clear();
+            }
+        }
+
+        Ok(())
+    }
+
+    fn mv_cursor(&mut self, m : Move) {
+        use Move::*;
+        match m {
+            // Horizontal movements change the desired cursor column.
+            Left  => self.view.cursor_set(self.buffers.buffer(self.view.buffer_id), self.view.cursor - pos(1,0)),
+            Right => self.view.cursor_set(self.buffers.buffer(self.view.buffer_id), self.view.cursor + pos(1,0)),
+            // Vertical movements try to go back to the desired cursor column.
+            Up    => self.view.cursor_goto_line(self.view.cursor.y - 1),
+            Down  => self.view.cursor_goto_line(self.view.cursor.y + 1),
+            _     => (),
+        }
+    }
+
+    // Whether any view of any tab page shows buffer 'id'.
+    fn buffer_is_shown(&self, id: usize) -> bool {
+        self.view.buffer_id == id
+            || self.panes.iter().any(|p| p.view.buffer_id == id)
+            || self.tabs.iter().any(|t| t.view.buffer_id == id || t.panes.iter().any(|p| p.view.buffer_id == id))
+    }
+
+    fn view_update(&mut self) {
+        let buffer = self.buffers.buffer(self.view.buffer_id);
+        self.view.update(buffer);
+    }
+
+    fn filepath(&self) -> &str {
+        &self.buffers.get(self.view.buffer_id).filepath
+    }
+
+    // Load a file if needed and show it in the view.
+    fn open_file(&mut self, filepath: &str) -> Re<()> {
+        let filearea = self.view.filearea.size().rec();
+        let id = self.buffers.open(filepath, filearea)?;
+        self.view_switch(id);
+        Ok(())
+    }
+
+    // Show another buffer in the view, saving the cursor and scroll position of the current buffer
+    // and restoring the ones of the other buffer.
+    fn view_switch(&mut self, id: usize) {
+        if id == self.view.buffer_id {
+            return
+        }
+        {
+            let entry = self.buffers.get_mut(self.view.buffer_id);
+            entry.cursor = self.view.cursor;
+            entry.filearea = self.view.filearea;
+        }
+        self.view.buffer_switch(self.buffers.get(id));
+    }
+
+    // Close buffer 'id', after switching every view showing it to another buffer.
+    fn buffer_close(&mut self, id: usize, force: bool) {
+        if self.buffers.len() == 1 {
+            logconsole("cannot close the last buffer");
+            return
+        }
+        let entry = self.buffers.get(id);
+        if entry.buffer.dirty && !force {
+            logconsole(&format!("{} has unsaved changes", entry.filepath));
+            return
+        }
+        let next = self.buffers.cycle(id, 1);
+        if self.view.buffer_id == id {
+            self.view_switch(next);
+        }
+        for view in views_other(&mut self.panes, &mut self.tabs) {
+            if view.buffer_id == id {
+                view.buffer_switch(self.buffers.get(next));
+            }
+        }
+        if let Err(err) = self.buffers.close(id, force) {
+            logconsole(&format!("{}", err));
+        }
+    }
+
+    fn jump_here(&self) -> Jump {
+        Jump {
+            filepath:   self.filepath().to_string(),
+            cursor:     self.view.cursor,
+        }
+    }
+
+    // Record the current position before doing a large cursor movement.
+    fn jump_push(&mut self) {
+        let jump = self.jump_here();
+        self.view.jumps.push(jump);
+    }
+
+    fn jump_to(&mut self, jump: Jump) {
+        if let Err(err) = self.open_file(&jump.filepath) {
+            logconsole(&format!("jump failed: {}", err));
+            return
+        }
+        self.view.cursor_set(self.buffers.buffer(self.view.buffer_id), jump.cursor);
+    }
+
+    // Move positions stored outside of the current buffer after the buffer lines changed.
+    fn positions_adjust(&mut self, shifts: &[Lineshift]) {
+        if shifts.is_empty() {
+            return
+        }
+        let buffer_id = self.view.buffer_id;
+        let filepath = &self.buffers.get(buffer_id).filepath;
+        self.view.jumps.adjust(filepath, shifts);
+        for j in self.view.tagstack.iter_mut() {
+            j.adjust(filepath, shifts);
+        }
+        for view in views_other(&mut self.panes, &mut self.tabs) {
+            view.jumps.adjust(filepath, shifts);
+            for j in view.tagstack.iter_mut() {
+                j.adjust(filepath, shifts);
+            }
+            if view.buffer_id == buffer_id {
+                view.cursor = Lineshift::adjust_all(shifts, view.cursor).unwrap_or(view.cursor);
+                view.cursor_memory = view.cursor;
+            }
+        }
+
+        let filepath = ioutil::canonical_path(filepath);
+        let marks = self.marks.drain(..).filter_map(|mut m| {
+            if m.filepath == filepath {
+                m.cursor = Lineshift::adjust_all(shifts, m.cursor)?;
+            }
+            Some(m)
+        }).collect();
+        self.marks = marks;
+    }
+
+    // Lowercase marks are local to the buffer, uppercase marks are global across files.
+    fn mark_set(&mut self, name: char) {
+        match name {
+            'a' ..= 'z' => {
+                self.buffers.buffer_mut(self.view.buffer_id).mark_set(name, self.view.cursor);
+            }
+            'A' ..= 'Z' => {
+                self.marks.retain(|m| m.name != name);
+                self.marks.push(Mark {
+                    name,
+                    filepath:   ioutil::canonical_path(self.filepath()),
+                    cursor:     self.view.cursor,
+                });
+                if let Err(err) = self.session_save() {
+                    logconsole(&format!("cannot save session: {}", err));
+                }
+            }
+            _ => logconsole(&format!("invalid mark {}", name)),
+        }
+    }
+
+    fn mark_goto(&mut self, name: char) {
+        let jump = match name {
+            'a' ..= 'z' => self.buffers.buffer(self.view.buffer_id).mark_get(name).map(|cursor| {
+                Jump { filepath: self.filepath().to_string(), cursor }
+            }),
+            'A' ..= 'Z' => self.marks.iter().find(|m| m.name == name).map(|m| {
+                Jump { filepath: m.filepath.clone(), cursor: m.cursor }
+            }),
+            _ => None,
+        };
+
+        match jump {
+            Some(jump) => {
+                self.jump_push();
+                self.jump_to(jump);
+            }
+            None => logconsole(&format!("mark {} not set", name)),
+        }
+    }
+
+    // Follow the tag of the identifier under the cursor, or open a Picker if there are many.
+    fn tag_goto(&mut self) -> Mode {
+        let name = match self.buffers.buffer(self.view.buffer_id).word_at(self.view.cursor) {
+            Some(name)  => name,
+            None        => {
+                logconsole("no identifier under cursor");
+                return Mode::Command
+            }
+        };
+        let tagfile = match tags::find_tagfile(self.filepath()) {
+            Some(tagfile)   => tagfile,
+            None            => {
+                logconsole("no tag file found");
+                return Mode::Command
+            }
+        };
+        let mut matches = match tags::lookup(&tagfile, &name) {
+            Ok(matches) => matches,
+            Err(err)    => {
+                logconsole(&format!("cannot read tags: {}", err));
+                return Mode::Command
+            }
+        };
+
+        match matches.len() {
+            0 => {
+                logconsole(&format!("tag {} not found", name));
+                Mode::Command
+            }
+            1 => {
+                self.tag_jump(matches.remove(0));
+                Mode::Command
+            }
+            n => {
+                let entries = matches.iter().map(|t| match t.address {
+                    tags::Tagaddress::Lineno(l)         => format!("{}:{}", t.filepath, l),
+                    tags::Tagaddress::Pattern(ref p)    => format!("{}: {}", t.filepath, p.trim()),
+                }).collect();
+                let title = format!("{} definitions of {}", n, name);
+                self.picker = Some(Picker::mk_picker(title, entries, Pickaction::Tag(matches)));
+                Mode::Pick
+            }
+        }
+    }
+
+    fn tag_jump(&mut self, tag: tags::Tag) {
+        let here = self.jump_here();
+        if let Err(err) = self.open_file(&tag.filepath) {
+            logconsole(&format!("cannot open {}: {}", tag.filepath, err));
+            return
+        }
+        self.view.tagstack.push(here.clone());
+        self.view.jumps.push(here);
+
+        let buffer = self.buffers.buffer(self.view.buffer_id);
+        let lineno = match tag.address {
+            tags::Tagaddress::Lineno(l)         => Some(l - 1),
+            tags::Tagaddress::Pattern(ref p)    => buffer.line_find(p),
+        };
+        match lineno {
+            Some(y) => self.view.cursor_set(buffer, pos(0, y)),
+            None    => logconsole(&format!("tag {} not found in {}", tag.name, tag.filepath)),
+        }
+    }
+
+    fn picker_input(&mut self, i: Input) -> Mode {
+        use Input::*;
+        match i {
+            Key(CTRL_N) | Key(CTRL_J) | Special(Specialkey::Down, 0) => {
+                if let Some(ref mut picker) = self.picker {
+                    picker.select_next();
+                }
+            }
+            Key(CTRL_P) | Key(CTRL_K) | Special(Specialkey::Up, 0) => {
+                if let Some(ref mut picker) = self.picker {
+                    picker.select_prev();
+                }
+            }
+            Key(DEL) | Key(BACKSPACE) => {
+                if let Some(ref mut picker) = self.picker {
+                    picker.filter_pop();
+                }
+                self.finder_query();
+            }
+            Key(CTRL_D) => {
+                self.picker_buffer_command(false);
+            }
+            Key(CTRL_S) => {
+                self.picker_buffer_command(true);
+            }
+            Key(c) if is_printable(c) => {
+                if let Some(ref mut picker) = self.picker {
+                    picker.filter_push(c);
+                }
+                self.finder_query();
+            }
+            Paste(ref text) => {
+                if let Some(ref mut picker) = self.picker {
+                    for c in text.chars().filter(|&c| is_printable(c)) {
+                        picker.filter_push(c);
+                    }
+                }
+                self.finder_query();
+            }
+            Key(ESC) => {
+                self.picker = None;
+                return Mode::Command
+            }
+            Key(ENTER) => {
+                if let Some(picker) = self.picker.take() {
+                    self.picker_choose(picker);
+                }
+                return Mode::Command
+            }
+            _ => (),
+        }
+        Mode::Pick
+    }
+
+    fn picker_choose(&mut self, picker: Picker) {
+        let chosen = match picker.chosen() {
+            Some(i) => i,
+            None    => return,
+        };
+        match picker.action {
+            Pickaction::Tag(mut tags) => {
+                self.tag_jump(tags.swap_remove(chosen));
+            }
+            Pickaction::Buffer(ids) => {
+                self.jump_push();
+                self.view_switch(ids[chosen]);
+            }
+            Pickaction::File(root) => {
+                let path = root.join(&picker.entries[chosen]).to_string_lossy().into_owned();
+                self.jump_push();
+                if let Err(err) = self.open_file(&path) {
+                    logconsole(&format!("cannot open {}: {}", path, err));
+                }
+            }
+        }
+    }
+
+    // Open a Picker over all files of the project, starting the index if needed.
+    fn finder_open(&mut self) -> Re<()> {
+        match self.finder {
+            Some(ref finder) => finder.refresh(),
+            None => {
+                let root = explore::project_root(&std::env::current_dir()?);
+                let wakeup = match self.wakeup {
+                    Some(ref wakeup)    => wakeup.clone(),
+                    None                => return er!("no input loop to wake up"),
+                };
+                self.finder = Some(finder::Finder::mk_finder(root, wakeup));
+            }
+        }
+        let root = self.finder.as_ref().map(|f| f.root.clone()).unwrap_or_default();
+        self.picker = Some(Picker::mk_picker(String::new(), Vec::new(), Pickaction::File(root)));
+        self.finder_query();
+        self.finder_poll();
+        Ok(())
+    }
+
+    fn finder_query(&mut self) {
+        if let (Some(ref mut finder), Some(ref picker)) = (self.finder.as_mut(), self.picker.as_ref()) {
+            if picker.is_prefiltered() {
+                finder.query(&picker.filter);
+            }
+        }
+    }
+
+    // Show the latest results of the Finder, if the file Picker is open.
+    fn finder_poll(&mut self) {
+        let (finder, picker) = match (self.finder.as_mut(), self.picker.as_mut()) {
+            (Some(finder), Some(picker))    => (finder, picker),
+            _                               => return,
+        };
+        if !picker.is_prefiltered() {
+            return
+        }
+        if let Some(results) = finder.results() {
+            picker.entries_set(results);
+        }
+        picker.title = format!("{} files{}", finder.len(), if finder.is_indexing() { ", indexing..." } else { "" });
+    }
+
+    // A Picker listing all loaded files.
+    fn buffer_explorer(&self) -> Picker {
+        let mut entries = Vec::new();
+        let mut ids = Vec::new();
+        for entry in &self.buffers.entries {
+            let cursor = if entry.id == self.view.buffer_id { self.view.cursor } else { entry.cursor };
+            entries.push(format!("{}{} {} lines {}:{}",
+                    entry.filepath,
+                    if entry.buffer.dirty { "+" } else { " " },
+                    entry.buffer.nlines(),
+                    cursor.y + 1,
+                    cursor.x + 1));
+            ids.push(entry.id);
+        }
+        let title = format!("{} buffers", ids.len());
+        Picker::mk_picker(title, entries, Pickaction::Buffer(ids))
+    }
+
+    // Save or close the buffer selected in the buffer explorer, then refresh the explorer.
+    fn picker_buffer_command(&mut self, save: bool) {
+        let (id, filter, selected) = match self.picker {
+            Some(ref picker) => match (&picker.action, picker.chosen()) {
+                (Pickaction::Buffer(ids), Some(i)) => (ids[i], picker.filter.clone(), picker.selected),
+                _                                       => return,
+            },
+            None => return,
+        };
+
+        if save {
+            // Same checks as ':w', which is the way to force writing a read-only buffer.
+            let entry = self.buffers.get_mut(id);
+            if entry.filepath.is_empty() {
+                logconsole("cannot save: no file name");
+            } else if entry.readonly {
+                logconsole(&format!("cannot save {}: read-only buffer", entry.filepath));
+            } else if let Err(err) = entry.buffer.to_file(&entry.filepath) {
+                logconsole(&format!("cannot save {}: {}", entry.filepath, err));
+            }
+        } else {
+            self.buffer_close(id, false);
+        }
+
+        let mut picker = self.buffer_explorer();
+        picker.selected = selected;
+        picker.filter_set(filter);
+        self.picker = Some(picker);
+    }
+
+    // The main screen without the tab bar, shown when there is more than one tab page.
+    fn screenarea(&self) -> Rec {
+        if self.tabs.is_empty() {
+            return self.mainscreen
+        }
+        self.mainscreen.vsplit(self.mainscreen.y0() + 1).1
+    }
+
+    fn explorer_area(&self) -> Rec {
+        let area = self.screenarea();
+        area.hsplit(area.x0() + EXPLORER_WIDTH).0
+    }
+
+    // Area of every pane, in screen order.
+    fn pane_areas(&self) -> Vec<(usize, Rec)> {
+        let area = self.screenarea();
+        let screenarea = match self.explorer {
+            Some(_) => area.hsplit(area.x0() + EXPLORER_WIDTH).1,
+            None    => area,
+        };
+        let mut areas = Vec::new();
+        self.layout.areas(screenarea, &mut areas);
+        areas
+    }
+
+    // Resize the screens of all panes around the side panel.
+    fn layout(&mut self) {
+        for (id, area) in self.pane_areas() {
+            if id == self.pane_id {
+                self.screen = Screen::mk_screen(area);
+                self.view.filearea = self.view.filearea.min.extrude(self.screen.textarea_size());
+                continue
+            }
+            if let Some(pane) = self.panes.iter_mut().find(|p| p.id == id) {
+                pane.screen = Screen::mk_screen(area);
+                pane.view.filearea = pane.view.filearea.min.extrude(pane.screen.textarea_size());
+            }
+        }
+        self.view_update();
+    }
+
+    // The pane under screen position p.
+    fn pane_at(&self, p: Pos) -> Option<usize> {
+        if self.screen.contains(p) {
+            return Some(self.pane_id)
+        }
+        self.panes.iter().find(|pane| pane.screen.contains(p)).map(|pane| pane.id)
+    }
+
+    // Clicks move the cursor in the pane under the pointer and focus it, or only focus it on the
+    // header. Drags select text from the click, and the wheel scrolls the pane under the pointer.
+    // In Insert mode, only the focused pane can be clicked.
+    fn mouse_input(&mut self, mode: Mode, mouse: Mouse) -> Mode {
+        let inserting = match mode {
+            Mode::Insert(_)                 => true,
+            Mode::Command | Mode::Visual(_) => false,
+            _                               => return mode,
+        };
+        let p = mouse.pos;
+        match (mouse.button, mouse.action) {
+            (Button::Left, Mouseaction::Press) => {
+                let id = match self.pane_at(p) {
+                    Some(id) if !inserting || id == self.pane_id => id,
+                    _ => return mode,
+                };
+                self.pane_focus(id);
+                if self.screen.header_contains(p) {
+                    return mode
+                }
+                let target = self.view.filearea.min + self.screen.text_offset(p);
+                self.view.cursor_set(self.buffers.buffer(self.view.buffer_id), target);
+                self.view.anchor = self.view.cursor;
+                self.view_update();
+                self.insert_ops.clear();
+                self.dragging = !inserting;
+                if let Mode::Visual(_) = mode {
+                    return Mode::Command
+                }
+            }
+            (Button::Left, Mouseaction::Motion) if self.dragging => {
+                let target = self.view.filearea.min + self.screen.text_offset(p);
+                self.view.cursor_set(self.buffers.buffer(self.view.buffer_id), target);
+                self.view_update();
+                if let Mode::Command = mode {
+                    return Mode::Visual(false)
+                }
+            }
+            (_, Mouseaction::Release) => {
+                self.dragging = false;
+            }
+            (Button::WheelUp, Mouseaction::Press) | (Button::WheelDown, Mouseaction::Press) => {
+                let dy = if mouse.button == Button::WheelUp { -SCROLL_LINES } else { SCROLL_LINES };
+                match self.pane_at(p) {
+                    Some(id) if id == self.pane_id => {
+                        self.view.scroll(self.buffers.buffer(self.view.buffer_id), dy);
+                    }
+                    Some(id) => {
+                        if let Some(pane) = self.panes.iter_mut().find(|pane| pane.id == id) {
+                            pane.view.scroll(self.buffers.buffer(pane.view.buffer_id), dy);
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+        mode
+    }
+
+    fn pane_input(&mut self, i: Input) -> Mode {
+        use Input::*;
+        match i {
+            Key('s')                => self.pane_split(true),
+            Key('v')                => self.pane_split(false),
+            Key('w') | Key(CTRL_W)  => self.pane_cycle(1),
+            Key('W')                => self.pane_cycle(-1),
+            Key('h')                => self.pane_towards(Move::Left),
+            Key('j')                => self.pane_towards(Move::Down),
+            Key('k')                => self.pane_towards(Move::Up),
+            Key('l')                => self.pane_towards(Move::Right),
+            Key('q')                => self.pane_close(),
+            Key('o')                => self.pane_only(),
+            Key('t')                => {
+                let prompt = Prompt::mk_prompt("new tab:".to_string(), String::new(), Promptaction::TabNew, Mode::Command);
+                self.prompt = Some(prompt);
+                return Mode::Prompt
+            }
+            Key('T')                => {
+                let prompt = Prompt::mk_prompt("rename tab:".to_string(), self.tabname.clone(), Promptaction::TabRename, Mode::Command);
+                self.prompt = Some(prompt);
+                return Mode::Prompt
+            }
+            Key('n')                => self.tab_cycle(1),
+            Key('p')                => self.tab_cycle(-1),
+            Key('X')                => self.tab_close(),
+            _                       => (),
+        }
+        Mode::Command
+    }
+
+    // Split the focused pane in two panes showing the same buffer, and focus the new pane.
+    fn pane_split(&mut self, stacked: bool) {
+        let area = self.screen.area();
+        // Both halves need at least a header and a text line, or a lineno column and a text column.
+        let too_small = if stacked { area.h() < 4 } else { area.w() < 12 };
+        if too_small {
+            logconsole("not enough room to split");
+            return
+        }
+
+        let view = self.view.duplicate();
+        let id = self.next_pane_id;
+        self.next_pane_id += 1;
+        self.layout.split(self.pane_id, id, stacked);
+        let screen = Screen::mk_screen(self.screen.area());  // resized by layout()
+        self.pane_push(view, screen, id);
+        self.layout();
+    }
+
+    // Make 'view' and 'screen' the focused pane 'id', and move the previously focused view and
+    // screen to the other panes.
+    fn pane_push(&mut self, mut view: View, screen: Screen, id: usize) {
+        view.is_active = true;
+        let mut previous = std::mem::replace(&mut self.view, view);
+        previous.is_active = false;
+        let previous_screen = std::mem::replace(&mut self.screen, screen);
+        self.panes.push(Pane {
+            id:         self.pane_id,
+            view:       previous,
+            screen:     previous_screen,
+        });
+        self.pane_id = id;
     }
 
-    fn update(&mut self, buffer: &Buffer) {
-        if buffer.nlines() == 0 {
-            return;
+    fn pane_focus(&mut self, id: usize) {
+        if id == self.pane_id {
+            return
         }
+        let idx = match self.panes.iter().position(|p| p.id == id) {
+            Some(idx)   => idx,
+            None        => return,
+        };
+        let pane = self.panes.remove(idx);
+        self.pane_push(pane.view, pane.screen, id);
+    }
+
+    // Focus the pane 'offset' panes away from the focused pane in screen order, wrapping around.
+    fn pane_cycle(&mut self, offset: i32) {
+        let areas = self.pane_areas();
+        let n = i32(areas.len());
+        let idx = i32(areas.iter().position(|&(id, _)| id == self.pane_id).unwrap_or(0));
+        let next = ((idx + offset) % n + n) % n;
+        self.pane_focus(areas[usize(next)].0);
+    }
+
+    // Focus the pane next to the focused pane in the given direction, level with the cursor.
+    fn pane_towards(&mut self, m: Move) {
+        let areas = self.pane_areas();
+        let area = self.screen.area();
+        let cursor = self.view.cursor - self.view.filearea.min + area.min;
+        let target = match m {
+            Move::Left  => pos(area.min.x - 1, cursor.y),
+            Move::Right => pos(area.max.x, cursor.y),
+            Move::Up    => pos(cursor.x, area.min.y - 1),
+            Move::Down  => pos(cursor.x, area.max.y),
+            _           => return,
+        };
+        let found = areas.iter().find(|&&(_, r)| {
+            r.min.x <= target.x && target.x < r.max.x && r.min.y <= target.y && target.y < r.max.y
+        });
+        if let Some(&(id, _)) = found {
+            self.pane_focus(id);
+        }
+    }
 
-        self.cursor = View::cursor_adjust(buffer, self.cursor);
-
-        // text range adjustment
-        {
-            let p = self.cursor;
+    // Close the focused pane and focus the next one. The last pane cannot be closed.
+    fn pane_close(&mut self) {
+        if self.panes.is_empty() {
+            logconsole("cannot close the last pane");
+            return
+        }
+        let closed = self.pane_id;
+        self.pane_cycle(1);
+        let layout = std::mem::replace(&mut self.layout, Layout::Pane(self.pane_id));
+        self.layout = layout.remove(closed).unwrap_or(Layout::Pane(self.pane_id));
+        if let Some(idx) = self.panes.iter().position(|p| p.id == closed) {
+            let pane = self.panes.remove(idx);
+            let entry = self.buffers.get_mut(pane.view.buffer_id);
+            entry.cursor = pane.view.cursor;
+            entry.filearea = pane.view.filearea;
+        }
+        self.layout();
+    }
 
-            let mut dx = 0;
-            let mut dy = 0;
+    // Close all panes but the focused one.
+    fn pane_only(&mut self) {
+        self.panes.clear();
+        self.layout = Layout::Pane(self.pane_id);
+        self.layout();
+    }
 
-            if p.y < self.filearea.min.y {
-                dy = p.y - self.filearea.min.y;
-            }
-            if self.filearea.max.y <= p.y {
-                dy = p.y + 1 - self.filearea.max.y;
-            }
-            if p.x < self.filearea.min.x {
-                dx = p.x - self.filearea.min.x;
-            }
-            if self.filearea.max.x <= p.x {
-                dx = p.x + 1 - self.filearea.max.x;
-            }
+    fn tabnames(&self) -> Vec<&str> {
+        let mut names : Vec<&str> = self.tabs.iter().map(|t| t.name.as_str()).collect();
+        names.insert(self.tab, &self.tabname);
+        names
+    }
 
-            self.filearea = self.filearea + pos(dx, dy);
+    // Show tab page 'tab' and return the tab page shown until now.
+    fn tab_swap(&mut self, tab: Tabpage) -> Tabpage {
+        use std::mem::replace;
+        Tabpage {
+            name:       replace(&mut self.tabname, tab.name),
+            pane_id:    replace(&mut self.pane_id, tab.pane_id),
+            view:       replace(&mut self.view, tab.view),
+            screen:     replace(&mut self.screen, tab.screen),
+            panes:      replace(&mut self.panes, tab.panes),
+            layout:     replace(&mut self.layout, tab.layout),
         }
     }
 
-    fn recenter(&mut self, _buffer: &Buffer) {
-        let size = self.filearea.size();
-        let y = max(0, self.cursor.y - size.y / 2);
-        // TODO: consider horizontal recenter too
-        self.filearea = pos(0, y).extrude(size);
+    // Add a tab page after the one shown, with one pane at the position of the focused pane.
+    fn tab_new(&mut self, name: String) {
+        let id = self.next_pane_id;
+        self.next_pane_id += 1;
+        let tab = Tabpage {
+            name,
+            pane_id:    id,
+            view:       self.view.duplicate(),
+            screen:     Screen::mk_screen(self.screen.area()),  // resized by layout()
+            panes:      Vec::new(),
+            layout:     Layout::Pane(id),
+        };
+        let previous = self.tab_swap(tab);
+        self.tabs.insert(self.tab, previous);
+        self.tab += 1;
+        self.layout();
     }
 
-    fn go_page_down(&mut self, buffer: &Buffer) {
-        let y = min(buffer.nlines() - 1, self.cursor.y + 50);
-        self.cursor = pos(self.cursor.x, y);
+    // Show the tab page at position 'idx' in the tab bar.
+    fn tab_goto(&mut self, idx: usize) {
+        if idx == self.tab || idx > self.tabs.len() {
+            return
+        }
+        let k = if idx < self.tab { idx } else { idx - 1 };
+        let tab = self.tabs.remove(k);
+        let previous = self.tab_swap(tab);
+        self.tabs.insert(if self.tab < idx { self.tab } else { self.tab - 1 }, previous);
+        self.tab = idx;
+        self.layout();
     }
 
-    fn go_page_up(&mut self, _buffer: &Buffer) {
-        let y = max(0, self.cursor.y - 50);
-        self.cursor = pos(self.cursor.x, y);
+    fn tab_cycle(&mut self, offset: i32) {
+        let n = i32(self.tabs.len()) + 1;
+        let next = ((i32(self.tab) + offset) % n + n) % n;
+        self.tab_goto(usize(next));
     }
 
-    fn go_file_start(&mut self, _buffer: &Buffer) {
-        self.cursor = pos(self.cursor.x, 0);
+    // Close the tab page shown and show the next one. The last tab page cannot be closed.
+    fn tab_close(&mut self) {
+        if self.tabs.is_empty() {
+            logconsole("cannot close the last tab");
+            return
+        }
+        let tab = if self.tab < self.tabs.len() {
+            self.tabs.remove(self.tab)
+        } else {
+            self.tab -= 1;
+            self.tabs.remove(self.tab)
+        };
+        self.tab_swap(tab);
+        self.layout();
     }
 
-    fn go_file_end(&mut self, buffer: &Buffer) {
-        self.cursor = pos(self.cursor.x, buffer.nlines() - 1);
+    fn tabinfo(&self, name: &str, pane_id: usize, view: &View, panes: &[Pane], layout: &Layout) -> Tabinfo {
+        let mut ids = Vec::new();
+        layout.ids(&mut ids);
+        let paneinfos = ids.iter().map(|&id| {
+            let v = if id == pane_id {
+                view
+            } else {
+                panes.iter().find(|p| p.id == id).map(|p| &p.view).unwrap_or(view)
+            };
+            Paneinfo {
+                filepath:   ioutil::canonical_path(&self.buffers.get(v.buffer_id).filepath),
+                cursor:     v.cursor,
+            }
+        }).collect();
+        Tabinfo {
+            name:       name.to_string(),
+            layout:     layout.encode(&ids),
+            panes:      paneinfos,
+            focus:      ids.iter().position(|&id| id == pane_id).unwrap_or(0),
+        }
     }
-}
 
+    // Recreate the tab pages saved in the session. Panes whose file cannot be loaded show the
+    // current buffer instead.
+    fn tabs_restore(&mut self, tabinfos: Vec<Tabinfo>, current: usize) {
+        let mut tabs = Vec::new();
+        for info in tabinfos {
+            if info.panes.is_empty() {
+                continue
+            }
+            let mut views = Vec::new();
+            let mut ids = Vec::new();
+            for p in &info.panes {
+                let filearea = self.view.filearea.size().rec();
+                let buffer_id = match self.buffers.open(&p.filepath, filearea) {
+                    Ok(id)  => id,
+                    Err(err) => {
+                        logconsole(&format!("cannot restore {}: {}", p.filepath, err));
+                        self.view.buffer_id
+                    }
+                };
+                let mut view = View::mk_fileview(buffer_id, self.view.filearea.size());
+                view.cursor_set(self.buffers.buffer(buffer_id), p.cursor);
+                view.is_active = false;
+                views.push(view);
+                ids.push(self.next_pane_id);
+                self.next_pane_id += 1;
+            }
 
-// TODO: find better place
-fn update_buffer(r: text::Opresult, e: &mut Editor) {
-    use text::Opresult::*;
-    match r {
-        Cursor(p) => {
-            e.view.cursor = p;
+            let focus = min(info.focus, views.len() - 1);
+            let mut layout = Layout::decode(&mut info.layout.split_whitespace(), &ids);
+            // Every pane must appear exactly once.
+            let mut found = Vec::new();
+            if let Some(ref l) = layout {
+                l.ids(&mut found);
+            }
+            found.sort();
+            if found != ids {
+                layout = None;
+            }
+            let layout = layout.unwrap_or(Layout::Pane(ids[focus]));
+            found.clear();
+            layout.ids(&mut found);
+
+            let mut view = views.remove(focus);
+            view.is_active = true;
+            let pane_id = ids.remove(focus);
+            let panes = ids.into_iter().zip(views)
+                .filter(|&(id, _)| found.contains(&id))
+                .map(|(id, view)| Pane {
+                    id,
+                    view,
+                    screen: Screen::mk_screen(self.screen.area()),  // resized by layout()
+                })
+                .collect();
+            tabs.push(Tabpage {
+                name:       info.name,
+                pane_id,
+                view,
+                screen:     Screen::mk_screen(self.screen.area()),
+                panes,
+                layout,
+            });
         }
-        Change(p) => {
-            e.view.cursor = p;
-            e.buffer.dirty = true;
-            e.buffer.ops_do();
-            // push snapshot
+
+        if tabs.is_empty() {
+            return
         }
-        _ => (),
+        let current = min(current, tabs.len() - 1);
+        let tab = tabs.remove(current);
+        self.tab_swap(tab);
+        self.tabs = tabs;
+        self.tab = current;
+        self.layout();
+    }
+
+    fn explorer_open(&mut self, dir: &Path) -> Re<()> {
+        let tree = explore::Tree::mk_tree(dir)?;
+        self.explorer = Some(Explorer {
+            tree,
+            selected: 0,
+        });
+        self.layout();
+        Ok(())
     }
-}
 
-/* COMMAND AND BUFFER MANIPULATION */
-    fn do_command(op: CommandOp, e: &mut Editor) -> Re<Mode> {
-        use CommandOp::*;
-        use Mode::*;
-        match op {
-            BufferMove(m) =>
-                do_buffer_move(m, e),
+    fn explorer_close(&mut self) {
+        self.explorer = None;
+        self.layout();
+    }
 
-            BufferOp(command) =>
-                do_buffer_command(command, e),
+    fn explorer_input(&mut self, i: Input) -> Mode {
+        use Input::*;
+        let explorer = match self.explorer {
+            Some(ref mut explorer)  => explorer,
+            None                    => return Mode::Command,
+        };
+        let selected = explorer.selected;
+        let node = explorer.selected_node().cloned();
 
-            Save(path) =>
-                e.buffer.to_file(&path)?,
+        match i {
+            Key('j') | Special(Specialkey::Down, 0) =>
+                explorer.select_next(),
 
-            ClearConsole => Debugconsole::clear(),
+            Key('k') | Special(Specialkey::Up, 0) =>
+                explorer.select_prev(),
 
-            SwitchInsert => {
-                let mode = InsertMode::Insert;
-                return Ok(PendingInsert(mode))
-            }
+            Key('l') | Key(ENTER) | Special(Specialkey::Right, 0) => match node {
+                Some(ref node) if node.entry.is_dir => {
+                    if let Err(err) = explorer.tree.toggle(selected) {
+                        logconsole(&format!("cannot list {}: {}", node.entry.path.display(), err));
+                    }
+                }
+                Some(node) => {
+                    let path = node.entry.path.to_string_lossy().into_owned();
+                    self.jump_push();
+                    if let Err(err) = self.open_file(&path) {
+                        logconsole(&format!("cannot open {}: {}", path, err));
+                        return Mode::Explorer
+                    }
+                    return Mode::Command
+                }
+                None => (),
+            },
 
-            SwitchReplace => {
-                let mode = InsertMode::Replace;
-                return Ok(PendingInsert(mode))
+            Key('h') | Special(Specialkey::Left, 0) => match node {
+                Some(ref node) if node.expanded =>
+                    explorer.tree.collapse(selected),
+                Some(_) => {
+                    if let Some(parent) = explorer.tree.parent(selected) {
+                        explorer.selected = parent;
+                    }
+                }
+                None => (),
+            },
+
+            Key('a') => {
+                let dir = explorer.target_dir();
+                let message = format!("new file in {} (end with / for a directory): ", dir.display());
+                let prompt = Prompt::mk_prompt(message, String::new(), Promptaction::Create(dir), Mode::Explorer);
+                self.prompt = Some(prompt);
+                return Mode::Prompt
             }
 
-            Noop => (),
-        }
+            Key('r') => if let Some(node) = node {
+                let message = format!("rename {} to: ", node.entry.path.display());
+                let prompt = Prompt::mk_prompt(message, node.entry.name, Promptaction::Rename(node.entry.path), Mode::Explorer);
+                self.prompt = Some(prompt);
+                return Mode::Prompt
+            },
 
-        Ok(Command)
-    }
+            Key('d') => if let Some(node) = node {
+                let op = Fileop::Delete(node.entry.path, node.entry.is_dir);
+                self.prompt = Some(Prompt::mk_confirm(op, Mode::Explorer));
+                return Mode::Prompt
+            },
 
-    fn do_buffer_move(op: MoveOp, e: &mut Editor) {
-        use MoveOp::*;
-        match op {
-            Movement(mvt) =>
-                e.mv_cursor(mvt),
+            Key('q') | Key('E') => {
+                self.explorer_close();
+                return Mode::Command
+            }
 
-            Recenter =>
-                e.view.recenter(&e.buffer),
+            Key(ESC) =>
+                return Mode::Command,
 
-            PageUp =>
-                e.view.go_page_up(&e.buffer),
+            _ => (),
+        }
 
-            PageDown =>
-                e.view.go_page_down(&e.buffer),
+        Mode::Explorer
+    }
 
-            FileStart =>
-                e.view.go_file_start(&e.buffer),
+    fn prompt_input(&mut self, i: Input) -> Mode {
+        use Input::*;
+        let mut prompt = match self.prompt.take() {
+            Some(prompt)    => prompt,
+            None            => return Mode::Command,
+        };
 
-            FileEnd =>
-                e.view.go_file_end(&e.buffer),
+        // Confirmations only need one key.
+        if let Promptaction::Confirm(ref op) = prompt.action {
+            if i == Key('y') {
+                self.fileop_apply(op);
+            }
+            return prompt.mode
         }
-    }
 
-    // TODO: move into buffer
-    // TODO: remote Editor from there and pass all the views instead
-    fn do_buffer_command(command: BufferCommand, e: &mut Editor) {
-        let cursor = command.cursor;
+        match i {
+            Key(ESC) =>
+                return prompt.mode,
+            Key(DEL) | Key(BACKSPACE) => {
+                prompt.input.pop();
+            }
+            Key(ENTER) =>
+                return self.prompt_submit(prompt),
+            Key(c) if is_printable(c) =>
+                prompt.input.push(c),
+            Paste(ref text) =>
+                prompt.input.extend(text.chars().filter(|&c| is_printable(c))),
+            _ => (),
+        }
 
-        // FIXME: use Mode
+        self.prompt = Some(prompt);
+        Mode::Prompt
+    }
 
-        // Or reuse a stashed snapshot when doing an Insert
-        // So what this should do is:
-        //  if no pending snapshot, take one: this indicates first step of Insert mode
-        let snapshot = e.buffer.snapshot_take(cursor);
-        use BufferOpType::*;
-        let opresult = match command.optype {
-            // Undo and Redo bypass the normal flow.
-            Undo                => {
-                e.buffer.undo();
-                return;
+    fn prompt_submit(&mut self, prompt: Prompt) -> Mode {
+        let Prompt { input, action, mode, .. } = prompt;
+        if input.is_empty() {
+            return mode
+        }
+        let op = match action {
+            Promptaction::TabNew => {
+                self.tab_new(input);
+                return mode
             }
-            Redo                => {
-                e.buffer.redo();
-                return;
+            Promptaction::TabRename => {
+                self.tabname = input;
+                return mode
             }
-
-            LineDel             => e.buffer.line_del(cursor),
-            LineNew             => e.buffer.line_new(cursor),
-            LineJoin            => e.buffer.line_join(cursor),
-            LineBreak           => e.buffer.line_break(cursor),
-            CharDelete          => e.buffer.del(cursor),
-            CharBackspace       => e.buffer.backspace(cursor),
-
-            InsertChar(c) if c == TAB => {
-                let n = CONF.tab_expansion - cursor.x % CONF.tab_expansion;
-                let mut p = cursor;
-                for _ in 0..n {
-                    e.buffer.char_insert(command.mode.unwrap(), p, ' ');
-                    p = p + pos(1,0);
-                }
-                Opresult::Change(p)
+            Promptaction::Create(dir) => {
+                let is_dir = input.ends_with("/");
+                Fileop::Create(dir.join(input.trim_end_matches('/')), is_dir)
             }
-            InsertChar(c) if !is_printable(c) => {
-                Opresult::Noop
+            Promptaction::Rename(path) => {
+                let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                Fileop::Rename(path, dir.join(input))
             }
-            InsertChar(c) => {
-                // TODO: check that raw text mutation: insert is always preceded by a snapshot
-                // and appropriate line copy
-                e.buffer.char_insert(command.mode.unwrap(), cursor, c)
+            Promptaction::Confirm(_) => return mode,
+            Promptaction::Search(pending) => {
+                self.search = Some(input);
+                if let Some((operator, count)) = pending {
+                    return self.operator_motion(operator, Motion::Move(MoveOp::SearchNext), count)
+                }
+                do_buffer_move(MoveOp::SearchNext, self);
+                return mode
             }
+        };
+        self.prompt = Some(Prompt::mk_confirm(op, mode));
+        Mode::Prompt
+    }
 
-            SwitchCommand       |
-                // in insert mode, save stashed Snapshot and exit Insert mode
-            Noop                => Opresult::Noop
+    fn cmdline_input(&mut self, i: Input) -> Mode {
+        use Input::*;
+        let mut cmdline = match self.cmdline.take() {
+            Some(cmdline)   => cmdline,
+            None            => return Mode::Command,
         };
 
-        // FIXME
-        // use snapshot below
+        if i != Key(TAB) {
+            cmdline.completions.clear();
+        }
 
-        match opresult {
-            Opresult::Cursor(new_cursor) => {
-                e.view.cursor = new_cursor;
+        match i {
+            Key(ESC) =>
+                return Mode::Command,
+            Key(ENTER) =>
+                return self.cmdline_submit(cmdline.input),
+            Key(DEL) | Key(BACKSPACE) if cmdline.input.is_empty() =>
+                return Mode::Command,
+            Key(DEL) | Key(BACKSPACE) =>
+                cmdline.backspace(),
+            Key(TAB)    => cmdline.complete(),
+            Key(CTRL_A) | Special(Specialkey::Home, 0)  => cmdline.cursor = 0,
+            Key(CTRL_E) | Special(Specialkey::End, 0)   => cmdline.cursor = cmdline.input.len(),
+            Key(CTRL_B) | Special(Specialkey::Left, 0)  => cmdline.left(),
+            Key(CTRL_F) | Special(Specialkey::Right, 0) => cmdline.right(),
+            Key(CTRL_W) => cmdline.word_delete(),
+            Key(CTRL_U) => {
+                cmdline.input.replace_range(..cmdline.cursor, "");
+                cmdline.cursor = 0;
             }
-            Opresult::Change(new_cursor) => {
-                e.view.cursor = new_cursor;
-                // FIXME: partially avoid this if in insert mode ?
-                e.buffer.dirty = true;
-                e.buffer.ops_do();
-                //e.buffer.snapshot_buffer.save() ??
+            Key(CTRL_P) | Special(Specialkey::Up, 0) if cmdline.history > 0 => {
+                cmdline.history -= 1;
+                cmdline.set(self.cmdhistory[cmdline.history].clone());
             }
-            Opresult::Noop => (),
-                // FIXME: undo the snapshot push ?
+            Key(CTRL_N) | Special(Specialkey::Down, 0) if cmdline.history < self.cmdhistory.len() => {
+                cmdline.history += 1;
+                let line = self.cmdhistory.get(cmdline.history).cloned().unwrap_or_default();
+                cmdline.set(line);
+            }
+            Key(c) if is_printable(c) =>
+                cmdline.insert(c),
+            Paste(ref text) => {
+                for c in text.chars().filter(|&c| is_printable(c)) {
+                    cmdline.insert(c);
+                }
+            }
+            _ => (),
         }
+
+        self.cmdline = Some(cmdline);
+        Mode::Cmdline
     }
 
-impl Editor {
+    // Run a command line. Errors are shown in the footer instead of stopping the editor.
+    fn cmdline_submit(&mut self, line: String) -> Mode {
+        if !line.trim().is_empty() {
+            self.cmdhistory.retain(|l| *l != line);
+            self.cmdhistory.push(line.clone());
+            if self.cmdhistory.len() > CMDLINE_HISTORY_MAX {
+                self.cmdhistory.remove(0);
+            }
+        }
+        let result = ex_parse(self, &line).and_then(|op| do_command(op, 1, self));
+        match result {
+            Ok(mode)    => mode,
+            Err(err)    => {
+                self.message = Some(err.cause().to_string());
+                Mode::Command
+            }
+        }
+    }
 
-    fn mk_editor() -> Re<Editor> {
-        let filename = file!().to_string();
-        let buffer = Buffer::from_file(&filename)?;
+    fn option_set(&mut self, name: &str, value: &str) -> Re<()> {
+        let flag = match value {
+            "true" | "on" | "1"     => true,
+            "false" | "off" | "0"   => false,
+            _                       => return er!(format!("invalid value for {}: {}", name, value)),
+        };
+        match name {
+            "relative_lineno"   => self.view.relative_lineno = flag,
+            "show_token"        => self.view.show_token = flag,
+            "show_neighbor"     => self.view.show_neighbor = flag,
+            "show_selection"    => self.view.show_selection = flag,
+            _                   => return er!(format!("unknown option: {}", name)),
+        }
+        Ok(())
+    }
 
-        let window = Term::size();
-        let (mainscreen, footer) = window.rec().vsplit(window.y - 1);
-        let screen = Screen::mk_screen(mainscreen);
-        let view;
-        {
-            // reuse code in mk_Screen !!!
-            let (_, filearea) = mainscreen.vsplit(1);
-            let (_, textarea) = filearea.hsplit(5);
-            view = View::mk_fileview(filename, textarea.size());
+    fn fileop_apply(&mut self, op: &Fileop) {
+        if let Err(err) = op.apply() {
+            logconsole(&format!("{}", err));
+        }
+        if let Some(ref mut explorer) = self.explorer {
+            for dir in op.dirs() {
+                explorer.refresh(&dir);
+            }
         }
+    }
 
-        Ok(Editor {
-            window,
-            mainscreen,
-            footer,
-            buffer,
-            view,
-            screen,
-        })
+    fn resize(&mut self) {
+        // TODO
     }
+}
 
-    fn run() -> Re<()> {
-        let mut e = Editor::mk_editor()?;
-        let mut f = Framebuffer::mk_framebuffer(e.window);
-        let mut m = Mode::default_command_state;
 
-        e.refresh_screen(&mut f, &m)?;
+#[cfg(test)]
+mod tests {
 
-        let (send, recv) = std::sync::mpsc::sync_channel(32);
+use super::*;
 
-        std::thread::spawn(move || {
-            push_char(&send);
-        });
+// An editor showing 'text' in a scratch buffer, with the cursor at 'cursor'.
+fn editor(text: &str, cursor: Pos) -> Editor {
+    let (keymaps, _) = Keymaps::load();
+    let mut e = Editor::mk_scratch_editor(pos(80, 24), keymaps);
+    e.buffers.get_mut(e.view.buffer_id).buffer = Buffer::from_text(text.as_bytes().to_vec());
+    e.view.cursor = cursor;
+    e
+}
 
+fn content(e: &Editor) -> String {
+    let buffer = e.buffers.buffer(e.view.buffer_id);
+    buffer.iter_all().map(|line| String::from_utf8_lossy(line).into_owned()).collect::<Vec<String>>().join("\n")
+}
 
-        while m != Mode::Exit {
-            let i = pull_input(&recv)?;
-            logconsole(&format!("input: {}", i));
+fn range(e: &Editor, motion: Motion, count: usize) -> Option<(Pos, Pos, bool)> {
+    e.motion_range(motion, count).map(|r| (r.start, r.stop, r.linewise))
+}
 
-            let _frame_time = Scopeclock::measure("last frame");     // caveat: displayed on next frame only
+#[test]
+fn motion_range_words() {
+    let e = editor("foo bar\nbaz qux", pos(4,0));
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordPrev), 1), Some((pos(0,0), pos(4,0), false)));
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordEnd), 1), Some((pos(4,0), pos(7,0), false)));
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordEnd), 2), Some((pos(4,0), pos(3,1), false)));
 
-            m = Mode::process_input(m, i, &mut e)?;
+    let e = editor("foo bar\nbaz qux", pos(0,0));
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordNext), 1), Some((pos(0,0), pos(4,0), false)));
+}
 
-            e.refresh_screen(&mut f, &m)?;
-        }
+#[test]
+fn motion_range_word_next_stops_at_line_end() {
+    let e = editor("foo bar\nbaz qux", pos(4,0));
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordNext), 1), Some((pos(4,0), pos(7,0), false)));
 
-        Ok(())
-    }
+    let e = editor("foo bar\nbaz qux", pos(0,0));
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordNext), 3), Some((pos(0,0), pos(7,0), false)));
+}
 
-    fn refresh_screen(&mut self, framebuffer: &mut Framebuffer, mode: &Mode) -> Re<()> {
-        // main screen
-        {
-            let _draw_time = Scopeclock::measure("draw");
+#[test]
+fn motion_range_lines() {
+    let e = editor("a\nb\nc\nd", pos(0,1));
+    assert_eq!(range(&e, Motion::Lines, 1), Some((pos(0,1), pos(0,1), true)));
+    assert_eq!(range(&e, Motion::Lines, 2), Some((pos(0,1), pos(0,2), true)));
+    assert_eq!(range(&e, Motion::Lines, 10), Some((pos(0,1), pos(0,3), true)));
+    assert_eq!(range(&e, Motion::Move(MoveOp::Movement(Move::Up)), 1), Some((pos(0,0), pos(0,1), true)));
+    assert_eq!(range(&e, Motion::Move(MoveOp::FileEnd), 1), Some((pos(0,1), pos(0,3), true)));
+}
 
-            let header = format!("{}{} {:?}",
-                    self.view.filepath,
-                    if self.buffer.dirty { "+" } else { " " },
-                    self.view.movement_mode);
-            let drawinfo = Drawinfo {
-                header:             &header,
-                buffer:             &self.buffer,
-                buffer_offset:      self.view.filearea.min,
-                cursor:             self.view.cursor,
-                draw:               Draw::All,
-                relative_lineno:    self.view.relative_lineno,
-                is_active:          self.view.is_active,
-            };
-            self.screen.put_text(framebuffer, &drawinfo);
-        }
+#[test]
+fn motion_range_objects() {
+    let e = editor("foo bar baz", pos(5,0));
+    assert_eq!(range(&e, Motion::Object(Textobject::Word, true), 1), Some((pos(4,0), pos(7,0), false)));
+    assert_eq!(range(&e, Motion::Object(Textobject::Word, false), 1), Some((pos(4,0), pos(8,0), false)));
+    assert_eq!(range(&e, Motion::Object(Textobject::Block(b'(', b')'), true), 1), None);
+}
 
-        // footer
-        {
-            framebuffer.put_line(self.footer.min + pos(1,0), mode.name().as_bytes());
-            framebuffer.put_line(self.footer.min + pos(10, 0), b"FOOTER FOOTER FOOTER FOOTER FOOTER FOOTER FOOTER FOOTER FOOTER FOOTER");
-            framebuffer.put_color(self.footer, mode.footer_color());
-        }
+#[test]
+fn motion_range_no_movement() {
+    let e = editor("foo", pos(0,0));
+    assert_eq!(range(&e, Motion::Move(MoveOp::Movement(Move::Left)), 1), None);
+    assert_eq!(range(&e, Motion::Move(MoveOp::WordPrev), 1), None);
+}
 
-        // renter frame to terminal
-        {
-            let _push_frame_time = Scopeclock::measure("render");
+#[test]
+fn change_word() {
+    // 'cw' changes up to the end of the word, like 'ce'.
+    let mut e = editor("foo bar", pos(0,0));
+    e.operator_motion(Operator::Change, Motion::Move(MoveOp::WordNext), 1);
+    assert_eq!(content(&e), " bar");
+
+    // On a one character word, only that character.
+    let mut e = editor("a bar", pos(0,0));
+    e.operator_motion(Operator::Change, Motion::Move(MoveOp::WordNext), 1);
+    assert_eq!(content(&e), " bar");
+
+    // On spaces, the spaces up to the next word.
+    let mut e = editor("foo  bar", pos(3,0));
+    e.operator_motion(Operator::Change, Motion::Move(MoveOp::WordNext), 1);
+    assert_eq!(content(&e), "foobar");
+
+    let mut e = editor("foo bar baz", pos(0,0));
+    e.operator_motion(Operator::Change, Motion::Move(MoveOp::WordNext), 2);
+    assert_eq!(content(&e), " baz");
+}
 
-            framebuffer.render()?;
-            if !CONF.retain_frame {
-                framebuffer.clear();
-            }
-        }
+#[test]
+fn delete_word() {
+    let mut e = editor("foo bar", pos(0,0));
+    e.operator_motion(Operator::Delete, Motion::Move(MoveOp::WordNext), 1);
+    assert_eq!(content(&e), "bar");
 
-        Ok(())
-    }
+    let mut e = editor("foo bar\nbaz", pos(4,0));
+    e.operator_motion(Operator::Delete, Motion::Move(MoveOp::WordNext), 1);
+    assert_eq!(content(&e), "foo \nbaz");
+    assert_eq!(e.view.cursor, pos(4,0));
+}
 
-    fn mv_cursor(&mut self, m : Move) {
-        use Move::*;
-        let delta = match m {
-            Left  => pos(-1,0),
-            Right => pos(1,0),
-            Up    => pos(0,-1),
-            Down  => pos(0,1),
-            _     => pos(0,0),
-        };
+#[test]
+fn delete_lines() {
+    let mut e = editor("a\nb\nc", pos(0,2));
+    e.operator_motion(Operator::Delete, Motion::Lines, 1);
+    assert_eq!(content(&e), "a\nb");
+    assert_eq!(e.view.cursor, pos(0,1));
+}
 
-        // TODO: update the 'desired cursor position' instead of the real cursor position
-        self.view.cursor = self.view.cursor + delta;
-    }
+#[test]
+fn operator_undo_redo() {
+    let mut e = editor("foo bar\nbaz", pos(0,0));
+    e.operator_motion(Operator::Delete, Motion::Move(MoveOp::WordEnd), 2);
+    e.undo_step_close();
+    assert_eq!(content(&e), "\nbaz");
 
-    fn resize(&mut self) {
-        // TODO
-    }
+    let buffer = e.buffers.buffer_mut(e.view.buffer_id);
+    assert_eq!(buffer.undo().map(|(p, _)| p), Some(pos(0,0)));
+    assert_eq!(content(&e), "foo bar\nbaz");
+
+    let buffer = e.buffers.buffer_mut(e.view.buffer_id);
+    assert!(buffer.redo().is_some());
+    assert_eq!(content(&e), "\nbaz");
 }
+
+} // mod tests
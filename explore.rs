@@ -1,50 +1,386 @@
 #![allow(dead_code)]
 #![allow(non_upper_case_globals)]
 
+// Directory listing and traversal. Standalone this prints the tree of the current directory,
+// inside czl this is the backend of the directory explorer and of the file finder.
+
 use std::{env, fs};
-use std::ffi::OsStr;
-//use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::vec::Vec;
-use std::fs::ReadDir;
 
 fn main() -> Result<(), std::io::Error> {
     let current_dir = env::current_dir()?;
-    let mut vec : std::vec::Vec<ReadDir> = Vec::new();
-    vec.push(fs::read_dir(current_dir)?);
-
-    while !vec.is_empty() {
-        for entry in vec.pop().unwrap() {
-            match entry {
-                Err(_) => (),
-                Ok(entry) => {
-                    let path = entry.path();
-                    let metadata = fs::metadata(&path)?;
-                    //if metadata.is_file() {
-                    //}
-//                    let x = path.file_name().map(&OsStr::to_str).flatten();
-//                    match x {
-//                        Some(n) => println!("{}", n),
-//                        _ => ()
-//                    }
-
-//                    let mut sep = " ";
-//                    for comp in path.components() {
-//                        //match comp {
-//                        //}
-//                        match comp.as_os_str().to_str() {
-//                            Some(n) => print!("{}{}", sep, n),
-//                            _ => ()
-//                        }
-//                        sep = " ";
-//                    }
-//                    println!();
-                    if metadata.is_dir() {
-                        vec.push(fs::read_dir(path)?);
-                    }
-                }
+    walk(&current_dir, &mut |entry, depth| {
+        let indent = "  ".repeat(depth);
+        if entry.is_dir {
+            println!("{}{}/", indent, entry.name);
+        } else {
+            println!("{}{} {}", indent, entry.name, size_human(entry.size));
+        }
+    })
+}
+
+
+// A file or directory inside a directory.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub path:       PathBuf,
+    pub name:       String,
+    pub is_dir:     bool,
+    pub is_link:    bool,   // symbolic link, to a directory if is_dir is set
+    pub size:       u64,
+}
+
+// List the content of a directory, directories first and then files, both sorted by name.
+// Symbolic links are described by their target. Entries which cannot be read are skipped.
+pub fn list_dir(dir: &Path) -> io::Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = match entry {
+            Ok(entry)   => entry,
+            Err(_)      => continue,
+        };
+        let path = entry.path();
+        let is_link = match fs::symlink_metadata(&path) {
+            Ok(metadata)    => metadata.file_type().is_symlink(),
+            Err(_)          => continue,
+        };
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata)    => metadata,
+            Err(_)          => continue,
+        };
+        entries.push(Entry {
+            name:   entry.file_name().to_string_lossy().into_owned(),
+            path,
+            is_dir: metadata.is_dir(),
+            is_link,
+            size:   metadata.len(),
+        });
+    }
+    entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+
+    Ok(entries)
+}
+
+// Depth first traversal of a directory. Directories are visited before their content. Links to
+// directories are visited but not traversed, as they can form cycles.
+pub fn walk<F>(root: &Path, visit: &mut F) -> io::Result<()> where F : FnMut(&Entry, usize) {
+    let mut stack : Vec<(Entry, usize)> = list_dir(root)?.into_iter().rev().map(|e| (e, 0)).collect();
+
+    while let Some((entry, depth)) = stack.pop() {
+        visit(&entry, depth);
+        if entry.is_dir && !entry.is_link {
+            if let Ok(children) = list_dir(&entry.path) {
+                stack.extend(children.into_iter().rev().map(|e| (e, depth + 1)));
             }
         }
     }
 
     Ok(())
 }
+
+pub fn size_human(size: u64) -> String {
+    const units : [&str; 4] = ["K", "M", "G", "T"];
+    if size < 1024 {
+        return format!("{}", size)
+    }
+    let mut s = size as f64 / 1024.0;
+    let mut u = 0;
+    while s >= 1024.0 && u < units.len() - 1 {
+        s /= 1024.0;
+        u += 1;
+    }
+    format!("{:.1}{}", s, units[u])
+}
+
+
+// A node of a Tree: one entry and its display state.
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub entry:      Entry,
+    pub depth:      usize,
+    pub expanded:   bool,
+}
+
+// A directory tree, flattened in display order: the children of an expanded directory node
+// directly follow that node. Directories are only listed when expanded.
+pub struct Tree {
+    pub root:       PathBuf,
+    pub nodes:      Vec<Node>,
+}
+
+impl Tree {
+    pub fn mk_tree(root: &Path) -> io::Result<Tree> {
+        let nodes = list_dir(root)?.into_iter().map(|entry| Node {
+            entry,
+            depth:      0,
+            expanded:   false,
+        }).collect();
+
+        Ok(Tree {
+            root: root.to_path_buf(),
+            nodes,
+        })
+    }
+
+    // Index of the node after the last descendant of node i.
+    fn subtree_end(&self, i: usize) -> usize {
+        let depth = self.nodes[i].depth;
+        let mut j = i + 1;
+        while j < self.nodes.len() && self.nodes[j].depth > depth {
+            j += 1;
+        }
+        j
+    }
+
+    pub fn expand(&mut self, i: usize) -> io::Result<()> {
+        if !self.nodes[i].entry.is_dir || self.nodes[i].expanded {
+            return Ok(())
+        }
+        let depth = self.nodes[i].depth + 1;
+        let children : Vec<Node> = list_dir(&self.nodes[i].entry.path)?.into_iter().map(|entry| Node {
+            entry,
+            depth,
+            expanded:   false,
+        }).collect();
+        self.nodes.splice(i + 1..i + 1, children);
+        self.nodes[i].expanded = true;
+
+        Ok(())
+    }
+
+    pub fn collapse(&mut self, i: usize) {
+        if !self.nodes[i].expanded {
+            return
+        }
+        let end = self.subtree_end(i);
+        self.nodes.drain(i + 1..end);
+        self.nodes[i].expanded = false;
+    }
+
+    pub fn toggle(&mut self, i: usize) -> io::Result<()> {
+        if self.nodes[i].expanded {
+            self.collapse(i);
+            Ok(())
+        } else {
+            self.expand(i)
+        }
+    }
+
+    // Index of the directory node containing node i, if i is not at the top of the tree.
+    pub fn parent(&self, i: usize) -> Option<usize> {
+        let depth = self.nodes[i].depth;
+        if depth == 0 {
+            return None
+        }
+        (0..i).rev().find(|&j| self.nodes[j].depth < depth)
+    }
+
+    // The directory containing node i.
+    pub fn parent_dir(&self, i: usize) -> PathBuf {
+        match self.parent(i) {
+            Some(j) => self.nodes[j].entry.path.clone(),
+            None    => self.root.clone(),
+        }
+    }
+
+    // List again the content of directory 'dir' after it changed on disk, if it is shown. The
+    // directories of that subtree which were expanded and still exist stay expanded.
+    pub fn refresh(&mut self, dir: &Path) -> io::Result<()> {
+        let expanded : Vec<PathBuf> = self.nodes.iter()
+            .filter(|n| n.expanded && n.entry.path.starts_with(dir))
+            .map(|n| n.entry.path.clone())
+            .collect();
+        if dir == self.root.as_path() {
+            let fresh = Tree::mk_tree(&self.root)?;
+            self.nodes = fresh.nodes;
+        } else {
+            match self.nodes.iter().position(|n| n.entry.path == dir) {
+                Some(i) if self.nodes[i].expanded => self.collapse(i),
+                _ => return Ok(()),
+            }
+        }
+        // Children follow their parent, so nested directories are expanded in turn.
+        let mut i = 0;
+        while i < self.nodes.len() {
+            if !self.nodes[i].expanded && expanded.contains(&self.nodes[i].entry.path) {
+                self.expand(i)?;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+}
+
+
+// One pattern line of a .gitignore file.
+#[derive(Debug, Clone)]
+pub struct Ignorerule {
+    base:       PathBuf,    // directory of the .gitignore file
+    pattern:    String,
+    negate:     bool,       // "!pattern": re-include what a previous rule excluded
+    dir_only:   bool,       // "pattern/": only match directories
+    anchored:   bool,       // pattern with a '/': match the path relative to base, not the file name
+}
+
+pub fn gitignore_load(dir: &Path) -> Vec<Ignorerule> {
+    let text = match fs::read_to_string(dir.join(".gitignore")) {
+        Ok(text)    => text,
+        Err(_)      => return Vec::new(),
+    };
+
+    let mut rules = Vec::new();
+    for line in text.lines() {
+        let mut pattern = line.trim_end();
+        if pattern.is_empty() || pattern.starts_with("#") {
+            continue
+        }
+        let negate = pattern.starts_with("!");
+        if negate {
+            pattern = &pattern[1..];
+        }
+        let dir_only = pattern.ends_with("/");
+        pattern = pattern.trim_end_matches('/');
+        let anchored = pattern.contains('/');
+        pattern = pattern.trim_start_matches('/');
+        if pattern.is_empty() {
+            continue
+        }
+        rules.push(Ignorerule {
+            base:       dir.to_path_buf(),
+            pattern:    pattern.to_string(),
+            negate,
+            dir_only,
+            anchored,
+        });
+    }
+    rules
+}
+
+// The last rule matching a path decides if that path is ignored.
+pub fn is_ignored(rules: &[Ignorerule], path: &Path, is_dir: bool) -> bool {
+    let mut ignored = false;
+    for rule in rules {
+        if rule.dir_only && !is_dir {
+            continue
+        }
+        let rel = match path.strip_prefix(&rule.base) {
+            Ok(rel) => rel,
+            Err(_)  => continue,
+        };
+        let text = if rule.anchored {
+            rel.to_string_lossy()
+        } else {
+            match path.file_name() {
+                Some(name)  => name.to_string_lossy(),
+                None        => continue,
+            }
+        };
+        if glob_match(rule.pattern.as_bytes(), text.as_bytes()) {
+            ignored = !rule.negate;
+        }
+    }
+    ignored
+}
+
+// Match text against a gitignore glob pattern: '*' and '?' do not match '/', "**" matches any
+// number of directories, '[...]' matches a character class.
+pub fn glob_match(p: &[u8], t: &[u8]) -> bool {
+    if p.is_empty() {
+        return t.is_empty()
+    }
+    match p[0] {
+        b'*' if p.starts_with(b"**/") => {
+            (0..t.len() + 1).any(|i| (i == 0 || t[i - 1] == b'/') && glob_match(&p[3..], &t[i..]))
+        }
+        b'*' if p.starts_with(b"**") => {
+            (0..t.len() + 1).any(|i| glob_match(&p[2..], &t[i..]))
+        }
+        b'*' => {
+            let stop = t.iter().position(|&c| c == b'/').unwrap_or(t.len());
+            (0..stop + 1).any(|i| glob_match(&p[1..], &t[i..]))
+        }
+        b'?' => {
+            !t.is_empty() && t[0] != b'/' && glob_match(&p[1..], &t[1..])
+        }
+        b'[' => {
+            let end = match p.iter().skip(2).position(|&c| c == b']') {
+                Some(n) => n + 2,
+                None    => return !t.is_empty() && t[0] == b'[' && glob_match(&p[1..], &t[1..]),
+            };
+            !t.is_empty() && class_match(&p[1..end], t[0]) && glob_match(&p[end + 1..], &t[1..])
+        }
+        b'\\' if p.len() > 1 => {
+            !t.is_empty() && t[0] == p[1] && glob_match(&p[2..], &t[1..])
+        }
+        c => {
+            !t.is_empty() && t[0] == c && glob_match(&p[1..], &t[1..])
+        }
+    }
+}
+
+fn class_match(class: &[u8], c: u8) -> bool {
+    let (negate, class) = match class.first() {
+        Some(&b'!') | Some(&b'^')   => (true, &class[1..]),
+        _                           => (false, class),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            found |= class[i] <= c && c <= class[i + 2];
+            i += 3;
+        } else {
+            found |= class[i] == c;
+            i += 1;
+        }
+    }
+    found != negate
+}
+
+// Depth first traversal of a project directory, skipping .git directories and entries ignored by
+// .gitignore files. Directories are listed with 'list', which allows reusing listings of
+// directories that did not change since a previous traversal.
+pub fn walk_project<L, F>(root: &Path, list: &mut L, visit: &mut F)
+        where L : FnMut(&Path) -> io::Result<Vec<Entry>>, F : FnMut(&Entry) {
+    let mut stack : Vec<(PathBuf, Rc<Vec<Ignorerule>>)> = vec![(root.to_path_buf(), Rc::new(Vec::new()))];
+
+    while let Some((dir, mut rules)) = stack.pop() {
+        let dir_rules = gitignore_load(&dir);
+        if !dir_rules.is_empty() {
+            let mut r = (*rules).clone();
+            r.extend(dir_rules);
+            rules = Rc::new(r);
+        }
+
+        let entries = match list(&dir) {
+            Ok(entries) => entries,
+            Err(_)      => continue,
+        };
+        let entries : Vec<&Entry> = entries.iter().filter(|e| {
+            e.name != ".git" && !is_ignored(&rules, &e.path, e.is_dir)
+        }).collect();
+        for entry in &entries {
+            visit(entry);
+        }
+        // Links to directories can form cycles: do not go through them.
+        for entry in entries.iter().rev().filter(|e| e.is_dir && !e.is_link) {
+            stack.push((entry.path.clone(), rules.clone()));
+        }
+    }
+}
+
+// The closest directory containing 'dir' with a .git directory, or 'dir' itself.
+pub fn project_root(dir: &Path) -> PathBuf {
+    let mut d = Some(dir);
+    while let Some(p) = d {
+        if p.join(".git").exists() {
+            return p.to_path_buf()
+        }
+        d = p.parent();
+    }
+    dir.to_path_buf()
+}